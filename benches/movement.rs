@@ -0,0 +1,49 @@
+//! Throughput of the movement transform, run with cargo bench --features testing.
+//! Feeds a stream of synthetic events through a mouse's movement processing, so it runs without a trackpad or uinput
+#[allow(dead_code)]
+#[path = "../src/mouse.rs"]
+mod mouse;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use mouse::{MouseMovement, SyntheticEvent};
+
+/// Events fed per iteration
+const EVENTS: u32 = 10_000;
+
+/// Motion tracing circles in small steps, the way a finger moves, with a bit of scrolling every tenth event
+fn event_stream() -> Vec<SyntheticEvent> {
+    let mut events = Vec::with_capacity(EVENTS as usize);
+    for i in 0..EVENTS {
+        let angle = i as f64 * 0.01;
+        events.push(SyntheticEvent::Motion{dx: angle.cos() * 2.5, dy: angle.sin() * 2.5});
+        if i % 10 == 0 {events.push(SyntheticEvent::Scroll{vertical: 0.05, horizontal: 0.0});}
+    }
+    events
+}
+
+/// Processes every event like its own frame, returning how many events were emitted
+fn feed(movement: &mut MouseMovement, events: &[SyntheticEvent]) -> usize {
+    events.iter().map(|event| {
+        movement.process_synthetic(event.clone());
+        movement.take_frames().iter().map(|frame| frame.len()).sum::<usize>()
+    }).sum()
+}
+
+fn movement(c: &mut Criterion) {
+    let events = event_stream();
+    let mut group = c.benchmark_group("movement");
+    group.throughput(Throughput::Elements(events.len() as u64));
+    group.bench_function("default", |b| {
+        let mut movement = MouseMovement::default();
+        b.iter(|| feed(&mut movement, std::hint::black_box(&events)))
+    });
+    group.bench_function("fixed point", |b| {
+        let mut movement = MouseMovement::default();
+        movement.settings.fixed_point = true;
+        b.iter(|| feed(&mut movement, std::hint::black_box(&events)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, movement);
+criterion_main!(benches);
@@ -1,17 +1,162 @@
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
 
-use dbus::nonblock;
-use dbus_tokio::connection;
+use dbus::{arg::{PropMap, RefArg, Variant}, channel::Channel, message::MatchRule, nonblock::{self, SyncConnection}};
 
+use crate::{communicator::EventIdKind, config, mouse::{MouseCreationOptions, QueuePolicy, ScrollKeyMap}};
+use dbus_tokio::connection::{self, IOResource};
+
+/// A mouse as listed by the server: name, input id, output id, and creation time as a unix timestamp
+type MouseEntry = (String, u32, u32, u64);
+/// A failed creation as listed by the server: name, error, and failure time as a unix timestamp
+type FailureEntry = (String, String, u64);
 
 /// Enum representing the different functions of the client side app
 pub enum ClientCommand{
-    New(String, String),
+    New(String, String, MouseCreationOptions),
+    /// Name prefix, glob of device paths, and the creation options used for every match
+    NewAll(String, String, MouseCreationOptions),
     List,
+    ListWithErrors,
+    ListOwners,
+    Get(String),
+    GetMirrors(String),
+    /// Input event id
+    Resolve(u32),
+    GetThroughput(String),
+    /// Path of the device
+    DeviceInfo(String),
+    /// Path of the device, and seconds to watch it for
+    ProbeInput(String, u32),
+    /// Name of the mouse, and the motion on each axis
+    Nudge(String, i32, i32),
+    /// Name of the mouse, and path of the new trackpad
+    Rebind(String, String),
+    Aborted,
     Stop(String),
+    /// Kind of event id, and the id
+    StopById(EventIdKind, u32),
+    CancelCreate(String),
     Shutdown,
     Reset,
-    PID
+    PauseAll,
+    ResumeAll,
+    PID,
+    ServerInfo,
+    SystemInfo,
+    ServerConfig,
+    /// Whether to export as TOML, and the file to write to instead of stdout
+    ExportConfig(bool, Option<String>),
+    /// Name of the profile, and its settings as JSON
+    SaveProfile(String, String),
+    DeleteProfile(String),
+    ListProfiles,
+    SetShiftToHScroll(String, bool),
+    SetScrollModifier(String, bool),
+    SetScrollAccel(String, bool),
+    SetIdleReset(String, u64),
+    SetFixedPoint(String, bool),
+    SetThreeFingerMiddleClick(String, bool),
+    SetHiResScroll(String, bool),
+    SetAxisScale(String, f64, f64),
+    SetLockToggleButton(String, u32),
+    SetSyncTimeout(String, u64),
+    SetScrollDeadzone(String, f64),
+    SetThreeFingerDrag(String, bool),
+    SetDoubleTap(String, bool, u64),
+    SetUnknownButtonLogging(String, bool),
+    SetMaxScrollPerFrame(String, u32),
+    SetTurboButton(String, u32, f64),
+    SetNormalizeByResolution(String, bool),
+    GetAccelProfile(String),
+    SetAccelProfile(String, String),
+    /// Mouse name and the rules as JSON
+    SetRules(String, String),
+    SetDiscreteScroll(String, bool),
+    SetScrollRateLimit(String, u64),
+    SetFlushMotionOnClick(String, bool),
+    SetIgnoredButtons(String, Vec<u32>),
+    /// Source and target mouse names
+    CloneSettings(String, String),
+    SetScrollAxes(String, bool, bool),
+    SetMaxSpeed(String, u32),
+    SetScrollAsMotion(String, bool),
+    SetClickStabilization(String, u64),
+    SetHiResQuantum(String, u32),
+    SetChannels(String, bool, bool, bool),
+    SetPassthroughButton(String, u32),
+    SetClampFirstMotion(String, bool, u64),
+    WaitFor(String, u32)
+}
+
+impl ClientCommand{
+    /// Name of the server method the command calls
+    pub fn method_name(&self) -> &'static str {
+        match self {
+            ClientCommand::New(..) => "CreateNewMouseWithOptions",
+            ClientCommand::NewAll(..) => "CreateNewMouseWithOptions",
+            ClientCommand::List => "ListMice",
+            ClientCommand::ListWithErrors => "ListMiceWithErrors",
+            ClientCommand::ListOwners => "ListOwners",
+            ClientCommand::Get(..) => "GetMouse",
+            ClientCommand::GetMirrors(..) => "GetMirrorOutputs",
+            ClientCommand::Resolve(..) => "ResolveMapping",
+            ClientCommand::GetThroughput(..) => "GetThroughput",
+            ClientCommand::Aborted => "ListAborted",
+            ClientCommand::Stop(..) => "StopMouse",
+            ClientCommand::StopById(..) => "StopMouseById",
+            ClientCommand::CancelCreate(..) => "CancelCreate",
+            ClientCommand::Shutdown => "Shutdown",
+            ClientCommand::Reset => "Reset",
+            ClientCommand::PauseAll => "PauseAll",
+            ClientCommand::ResumeAll => "ResumeAll",
+            ClientCommand::PID => "GetProcessID",
+            ClientCommand::ServerInfo => "GetServerInfo",
+            ClientCommand::SystemInfo => "GetSystemInfo",
+            ClientCommand::ServerConfig => "GetServerConfig",
+            ClientCommand::DeviceInfo(..) => "GetDeviceInfo",
+            ClientCommand::ProbeInput(..) => "ProbeInput",
+            ClientCommand::Nudge(..) => "Nudge",
+            ClientCommand::Rebind(..) => "Rebind",
+            ClientCommand::ExportConfig(..) => "ExportConfig",
+            ClientCommand::SaveProfile(..) => "SaveProfile",
+            ClientCommand::DeleteProfile(..) => "DeleteProfile",
+            ClientCommand::ListProfiles => "ListProfiles",
+            ClientCommand::SetShiftToHScroll(..) => "SetShiftToHScroll",
+            ClientCommand::SetScrollModifier(..) => "SetScrollModifier",
+            ClientCommand::SetScrollAccel(..) => "SetScrollAccel",
+            ClientCommand::SetIdleReset(..) => "SetIdleReset",
+            ClientCommand::SetFixedPoint(..) => "SetFixedPoint",
+            ClientCommand::SetThreeFingerMiddleClick(..) => "SetThreeFingerMiddleClick",
+            ClientCommand::SetHiResScroll(..) => "SetHiResScroll",
+            ClientCommand::SetAxisScale(..) => "SetAxisScale",
+            ClientCommand::SetLockToggleButton(..) => "SetLockToggleButton",
+            ClientCommand::SetSyncTimeout(..) => "SetSyncTimeout",
+            ClientCommand::SetScrollDeadzone(..) => "SetScrollDeadzone",
+            ClientCommand::SetThreeFingerDrag(..) => "SetThreeFingerDrag",
+            ClientCommand::SetDoubleTap(..) => "SetDoubleTap",
+            ClientCommand::SetUnknownButtonLogging(..) => "SetUnknownButtonLogging",
+            ClientCommand::SetMaxScrollPerFrame(..) => "SetMaxScrollPerFrame",
+            ClientCommand::SetTurboButton(..) => "SetTurboButton",
+            ClientCommand::SetNormalizeByResolution(..) => "SetNormalizeByResolution",
+            ClientCommand::GetAccelProfile(..) => "GetAccelProfile",
+            ClientCommand::SetAccelProfile(..) => "SetAccelProfile",
+            ClientCommand::SetRules(..) => "SetRules",
+            ClientCommand::SetDiscreteScroll(..) => "SetDiscreteScroll",
+            ClientCommand::SetScrollRateLimit(..) => "SetScrollRateLimit",
+            ClientCommand::SetFlushMotionOnClick(..) => "SetFlushMotionOnClick",
+            ClientCommand::SetIgnoredButtons(..) => "SetIgnoredButtons",
+            ClientCommand::CloneSettings(..) => "CloneSettings",
+            ClientCommand::SetScrollAxes(..) => "SetScrollAxes",
+            ClientCommand::SetMaxSpeed(..) => "SetMaxSpeed",
+            ClientCommand::SetScrollAsMotion(..) => "SetScrollAsMotion",
+            ClientCommand::SetClickStabilization(..) => "SetClickStabilization",
+            ClientCommand::SetHiResQuantum(..) => "SetHiResQuantum",
+            ClientCommand::SetChannels(..) => "SetChannels",
+            ClientCommand::SetPassthroughButton(..) => "SetPassthroughButton",
+            ClientCommand::SetClampFirstMotion(..) => "SetClampFirstMotion",
+            ClientCommand::WaitFor(..) => "WaitForMouse"
+        }
+    }
 }
 
 /// Error representing ways the client can fail
@@ -21,7 +166,10 @@ pub enum ClientError{
     ServerNotFound(dbus::Error),
     MethodCallFailed(dbus::Error),
     XInputCallError(std::io::Error),
-    XInputParseError
+    XInputParseError,
+    WriteFailed(std::io::Error),
+    NoMatchingDevices(String),
+    UnsupportedCommand(&'static str)
 }
 impl Display for ClientError{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -30,7 +178,10 @@ impl Display for ClientError{
             ClientError::ServerNotFound(err) => format!("Failed to find the server. DBus error: {}", err),
             ClientError::MethodCallFailed(err) => format!("Failed to call the method. DBus error: {}", err),
             ClientError::XInputCallError(err) => format!("Failed to call the xinput tool. IO Error: {}", err),
-            ClientError::XInputParseError => format!("Failed to parse xinput data")
+            ClientError::XInputParseError => format!("Failed to parse xinput data"),
+            ClientError::WriteFailed(err) => format!("Failed to write the file. IO Error: {}", err),
+            ClientError::NoMatchingDevices(pattern) => format!("No devices match {}", pattern),
+            ClientError::UnsupportedCommand(method) => format!("The server does not support {}, it is probably older than the client", method)
         };
         f.write_str(string.as_str())?;
         Ok(())
@@ -38,67 +189,619 @@ impl Display for ClientError{
 }
 impl Error for ClientError{}
 
-/// Client code
-pub async fn client(function: ClientCommand) -> Result<(), Box<dyn std::error::Error>> {
+/// Connects to the bus at a DBus address (e.g. tcp:host=example,port=4000), instead of the local system bus
+fn connect_to_address(address: &str) -> Result<(IOResource<SyncConnection>, Arc<SyncConnection>), dbus::Error> {
+    let mut channel = Channel::open_private(address)?;
+    channel.register()?;
+    connection::from_channel(channel)
+}
+
+/// Client code. Connects to the bus at address if one is given, and the system bus otherwise.
+/// Talks to the named server instance if one is given, and the default server otherwise
+pub async fn client(function: ClientCommand, address: Option<&str>, instance: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
     // Setup DBus connection
-    let (resource, conn) = connection::new_system_sync()
-        .map_err(|err| ClientError::DBusConnectionFailed(err))?;
-    let dbus_handle = tokio::spawn(async {
+    let (resource, conn) = match address {
+        Some(address) => connect_to_address(address),
+        None => connection::new_system_sync()
+    }.map_err(|err| ClientError::DBusConnectionFailed(err))?;
+    let mut dbus_handle = tokio::spawn(async {
         resource.await
     });
     // Setup proxy
-    let proxy = nonblock::Proxy::new("com.cowsociety.virtual_mouse", "/", std::time::Duration::from_secs(2), conn.clone());
+    let proxy = nonblock::Proxy::new(config::bus_name(instance), "/", std::time::Duration::from_secs(2), conn.clone());
+    // Run the command, failing fast if the connection to the dbus daemon is lost in the meantime
+    let result = tokio::select! {
+        result = run_command(&proxy, function) => result,
+        _ = &mut dbus_handle => Err(ClientError::ServerNotFound(dbus::Error::new_custom(
+            "org.freedesktop.DBus.Error.Disconnected", 
+            "Lost the connection to the dbus daemon"
+        )).into())
+    };
+    dbus_handle.abort();
+    result
+}
+
+/// Sends the command to the server
+async fn run_command(proxy: &nonblock::Proxy<'_, Arc<SyncConnection>>, function: ClientCommand) -> Result<(), Box<dyn std::error::Error>> {
     // make sure server is running
     proxy.method_call::<(u32,), (), &str, &str>("com.cowsociety.virtual_mouse", "GetProcessID", ()).await
         .map_err(|err| ClientError::ServerNotFound(err))?;
+    // make sure the server supports the command. Servers without GetCapabilities predate it, so they are assumed to support everything
+    if let Ok((capabilities,)) = proxy.method_call::<(Vec<String>,), _, _, _>("com.cowsociety.virtual_mouse", "GetCapabilities", ()).await {
+        if !capabilities.iter().any(|method| method == function.method_name()) {
+            return Err(ClientError::UnsupportedCommand(function.method_name()).into());
+        }
+    }
     // Do the command
     match function {
-        ClientCommand::New(name, path) => {
-            let (name, input_id, output_id): (String, u32, u32) = proxy.method_call(
-                "com.cowsociety.virtual_mouse", 
-                "CreateNewMouse", 
-                (name.as_str(), path.as_str())
-            ).await.map_err(|err| ClientError::MethodCallFailed(err))?;
+        ClientCommand::New(name, path, options) => {
+            let (name, input_id, output_id) = create_mouse(proxy, &name, &path, &options).await?;
             println!("Success: (name input_id output_id)");
             println!("{} {} {}", name, input_id, output_id);
         }
+        ClientCommand::NewAll(prefix, pattern, options) => {
+            let mut created = vec![];
+            for (index, path) in expand_device_glob(&pattern)?.into_iter().enumerate() {
+                created.push(create_mouse(proxy, &format!("{}-{}", prefix, index), &path, &options).await?);
+            }
+            println!("Success: (name input_id output_id)");
+            for (name, input_id, output_id) in created {
+                println!("{} {} {}", name, input_id, output_id);
+            }
+        }
         ClientCommand::List => {
-            let (list,): (Vec<(String, u32, u32)>,) = proxy.method_call(
+            let (mut list,): (Vec<MouseEntry>,) = proxy.method_call(
                 "com.cowsociety.virtual_mouse", 
                 "ListMice", 
-                ()).await.map_err(|err| ClientError::MethodCallFailed(err))?;
-            println!("Mice: (name input_id output_id)");
-            for (name, input_id, output_id) in list.into_iter() {
-                println!("{} {} {}", name, input_id, output_id);
+                ()).await.map_err(ClientError::MethodCallFailed)?;
+            // Servers from before the list was sorted return it in any order
+            list.sort();
+            println!("Mice: (name input_id output_id uptime)");
+            for (name, input_id, output_id, created_at) in list.into_iter() {
+                println!("{} {} {} up for {}", name, input_id, output_id, format_age(created_at));
+            }
+        }
+        ClientCommand::ListWithErrors => {
+            let (mut list, failures): (Vec<MouseEntry>, Vec<FailureEntry>) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "ListMiceWithErrors", 
+                ()).await.map_err(ClientError::MethodCallFailed)?;
+            // Servers from before the list was sorted return it in any order
+            list.sort();
+            println!("Mice: (name input_id output_id uptime)");
+            for (name, input_id, output_id, created_at) in list.into_iter() {
+                println!("{} {} {} up for {}", name, input_id, output_id, format_age(created_at));
+            }
+            println!("Failed Creations: (name failed_ago error)");
+            for (name, error, failed_at) in failures.into_iter() {
+                println!("{} {} ago {}", name, format_age(failed_at), error);
+            }
+        }
+        ClientCommand::ListOwners => {
+            let (owners,): (Vec<(String, String, u32)>,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "ListOwners", 
+                ()).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Owners: (name client uid)");
+            for (name, client, uid) in owners.into_iter() {
+                println!("{} {} {}", name, client, uid);
+            }
+        }
+        ClientCommand::ExportConfig(toml, path) => {
+            let (config,): (String,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "ExportConfig", 
+                (toml,)).await.map_err(ClientError::MethodCallFailed)?;
+            match path {
+                Some(path) => {std::fs::write(path, config).map_err(|err| ClientError::WriteFailed(err))?;}
+                None => {println!("{}", config);}
+            }
+        }
+        ClientCommand::Aborted => {
+            let (list,): (Vec<(String, String, u64)>,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "ListAborted", 
+                ()).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Aborted Mice: (name aborted_ago error)");
+            for (name, error, aborted_at) in list.into_iter() {
+                println!("{} {} ago {}", name, format_age(aborted_at), error);
+            }
+        }
+        ClientCommand::Get(name) => {
+            let (name, input_id, output_id, created_at): (String, u32, u32, u64) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "GetMouse", 
+                (name,)).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Mouse: (name input_id output_id uptime)");
+            println!("{} {} {} up for {}", name, input_id, output_id, format_age(created_at));
+        }
+        ClientCommand::Resolve(input_id) => {
+            let (name, output_id): (String, u32) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "ResolveMapping", 
+                (input_id,)).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Mouse: (name output_id)");
+            println!("{} {}", name, output_id);
+        }
+        ClientCommand::GetMirrors(name) => {
+            let (mirror_ids,): (Vec<u32>,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "GetMirrorOutputs", 
+                (name,)).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Mirror Outputs: (output_event_id)");
+            for mirror_id in mirror_ids.into_iter() {
+                println!("{}", mirror_id);
             }
         }
+        ClientCommand::GetThroughput(name) => {
+            let (emits, events): (f64, f64) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "GetThroughput", 
+                (name,)).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Emits per second: {:.1}", emits);
+            println!("Events per second: {:.1}", events);
+        }
         ClientCommand::Stop(name) => {
-            proxy.method_call(
+            let () = proxy.method_call(
                 "com.cowsociety.virtual_mouse", 
                 "StopMouse", 
-                (name, )).await.map_err(|err| ClientError::MethodCallFailed(err))?;
+                (name, )).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::StopById(kind, id) => {
+            let (name,): (String,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "StopMouseById", 
+                (kind.as_str(), id)).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Stopped {}", name);
+        }
+        ClientCommand::CancelCreate(name) => {
+            let (cancelled,): (bool,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "CancelCreate", 
+                (name, )).await.map_err(ClientError::MethodCallFailed)?;
+            println!("{}", if cancelled {"Cancelled"} else {"Nothing to cancel"});
         }
         ClientCommand::Shutdown => {
-            proxy.method_call(
+            let () = proxy.method_call(
                 "com.cowsociety.virtual_mouse", 
                 "Shutdown", 
-                ()).await.map_err(|err| ClientError::MethodCallFailed(err))?;
+                ()).await.map_err(ClientError::MethodCallFailed)?;
         }
         ClientCommand::Reset => {
-            proxy.method_call(
+            let () = proxy.method_call(
                 "com.cowsociety.virtual_mouse", 
                 "Reset", 
-                ()).await.map_err(|err| ClientError::MethodCallFailed(err))?;
+                ()).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SaveProfile(name, settings) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SaveProfile", 
+                (name, settings)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::DeleteProfile(name) => {
+            let (deleted,): (bool,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "DeleteProfile", 
+                (name,)).await.map_err(ClientError::MethodCallFailed)?;
+            println!("{}", if deleted {"Deleted"} else {"No such profile"});
+        }
+        ClientCommand::ListProfiles => {
+            let (names,): (Vec<String>,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "ListProfiles", 
+                ()).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Profiles:");
+            for name in names.into_iter() {
+                println!("{}", name);
+            }
+        }
+        ClientCommand::PauseAll => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "PauseAll", 
+                ()).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::ResumeAll => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "ResumeAll", 
+                ()).await.map_err(ClientError::MethodCallFailed)?;
         }
         ClientCommand::PID => {
             let (pid,): (u32,) = proxy.method_call(
                 "com.cowsociety.virtual_mouse", 
                 "GetProcessID", 
-                ()).await.map_err(|err| ClientError::MethodCallFailed(err))?;
+                ()).await.map_err(ClientError::MethodCallFailed)?;
             println!("Server Process ID:");
             println!("{}", pid);
         }
+        ClientCommand::ServerInfo => {
+            let (pid, systemd, started_at, mice): (u32, bool, u64, u32) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "GetServerInfo", 
+                ()).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Server: (pid systemd uptime mice)");
+            println!("{} {} up for {} {}", pid, if systemd {"yes"} else {"no"}, format_age(started_at), mice);
+        }
+        ClientCommand::ServerConfig => {
+            let (config,): (PropMap,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "GetServerConfig", 
+                ()).await.map_err(ClientError::MethodCallFailed)?;
+            let mut config: Vec<(String, String)> = config.into_iter().map(|(key, value)| (key, format_value(&value.0))).collect();
+            config.sort();
+            println!("Server Configuration:");
+            for (key, value) in config {
+                println!("{}: {}", key, value);
+            }
+        }
+        ClientCommand::SystemInfo => {
+            let (info,): (PropMap,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "GetSystemInfo", 
+                ()).await.map_err(ClientError::MethodCallFailed)?;
+            let mut info: Vec<(String, String)> = info.into_iter().map(|(key, value)| (key, format_value(&value.0))).collect();
+            info.sort();
+            println!("System Info:");
+            for (key, value) in info {
+                println!("{}: {}", key, value);
+            }
+        }
+        ClientCommand::DeviceInfo(path) => {
+            let (info,): (PropMap,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "GetDeviceInfo", 
+                (path,)).await.map_err(ClientError::MethodCallFailed)?;
+            let mut info: Vec<(String, String)> = info.into_iter().map(|(key, value)| (key, format_value(&value.0))).collect();
+            info.sort();
+            println!("Device Info:");
+            for (key, value) in info {
+                println!("{}: {}", key, value);
+            }
+        }
+        ClientCommand::ProbeInput(path, duration) => {
+            // the server only replies once it watched the device for the whole duration
+            let proxy = nonblock::Proxy::new(
+                proxy.destination.clone(), 
+                "/", 
+                std::time::Duration::from_secs(duration as u64 + 2), 
+                proxy.connection.clone()
+            );
+            println!("Watching {} for {} seconds...", path, duration);
+            let (frames, key, relative, absolute, other): (u32, u32, u32, u32, u32) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "ProbeInput", 
+                (path, duration)).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Frames: {}", frames);
+            println!("Key (buttons and touches): {}", key);
+            println!("Relative (mouse motion and scroll): {}", relative);
+            println!("Absolute (finger motion and scroll): {}", absolute);
+            println!("Other: {}", other);
+            if frames == 0 {println!("The device sent nothing, touch it while probing");}
+        }
+        ClientCommand::Nudge(name, dx, dy) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "Nudge", 
+                (name, dx, dy)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::Rebind(name, path) => {
+            let (input_id,): (u32,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "Rebind", 
+                (name.clone(), path)).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Mouse Rebound: (name input_id)");
+            println!("{} {}", name, input_id);
+        }
+        ClientCommand::WaitFor(name, timeout) => {
+            // the server only replies once the mouse exists, so the call must not time out before the server does
+            let proxy = nonblock::Proxy::new(
+                proxy.destination.clone(), 
+                "/", 
+                std::time::Duration::from_secs(timeout as u64 + 2), 
+                proxy.connection.clone()
+            );
+            let (name, input_id, output_id): (String, u32, u32) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "WaitForMouse", 
+                (name, timeout)).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Mouse Ready: (name input_id output_id)");
+            println!("{} {} {}", name, input_id, output_id);
+        }
+        ClientCommand::SetShiftToHScroll(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetShiftToHScroll", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetScrollModifier(name, held) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetScrollModifier", 
+                (name, held)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetScrollAccel(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetScrollAccel", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetIdleReset(name, ms) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetIdleReset", 
+                (name, ms)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetFixedPoint(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetFixedPoint", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetThreeFingerMiddleClick(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetThreeFingerMiddleClick", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetHiResScroll(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetHiResScroll", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetAxisScale(name, x, y) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetAxisScale", 
+                (name, x, y)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetLockToggleButton(name, button) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetLockToggleButton", 
+                (name, button)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetSyncTimeout(name, ms) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetSyncTimeout", 
+                (name, ms)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetScrollDeadzone(name, deadzone) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetScrollDeadzone", 
+                (name, deadzone)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetThreeFingerDrag(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetThreeFingerDrag", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetDoubleTap(name, enabled, interval_ms) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetDoubleTap", 
+                (name, enabled, interval_ms)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetUnknownButtonLogging(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetUnknownButtonLogging", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetMaxScrollPerFrame(name, notches) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetMaxScrollPerFrame", 
+                (name, notches)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetTurboButton(name, button, factor) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetTurboButton", 
+                (name, button, factor)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetNormalizeByResolution(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetNormalizeByResolution", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::GetAccelProfile(name) => {
+            let (profile,): (String,) = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "GetAccelProfile", 
+                (name,)).await.map_err(ClientError::MethodCallFailed)?;
+            println!("Accel Profile:");
+            println!("{}", profile);
+        }
+        ClientCommand::SetAccelProfile(name, profile) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetAccelProfile", 
+                (name, profile)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetRules(name, rules) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetRules", 
+                (name, rules)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetDiscreteScroll(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetDiscreteScroll", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetScrollRateLimit(name, milliseconds) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetScrollRateLimit", 
+                (name, milliseconds)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetFlushMotionOnClick(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetFlushMotionOnClick", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetIgnoredButtons(name, codes) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetIgnoredButtons", 
+                (name, codes)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::CloneSettings(from, to) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "CloneSettings", 
+                (from, to)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetScrollAxes(name, vertical, horizontal) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetScrollAxes", 
+                (name, vertical, horizontal)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetMaxSpeed(name, pixels) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetMaxSpeed", 
+                (name, pixels)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetScrollAsMotion(name, enabled) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetScrollAsMotion", 
+                (name, enabled)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetClickStabilization(name, milliseconds) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetClickStabilization", 
+                (name, milliseconds)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetHiResQuantum(name, quantum) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetHiResQuantum", 
+                (name, quantum)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetChannels(name, motion, buttons, scroll) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetChannels", 
+                (name, motion, buttons, scroll)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetPassthroughButton(name, button) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetPassthroughButton", 
+                (name, button)).await.map_err(ClientError::MethodCallFailed)?;
+        }
+        ClientCommand::SetClampFirstMotion(name, enabled, idle_ms) => {
+            let () = proxy.method_call(
+                "com.cowsociety.virtual_mouse", 
+                "SetClampFirstMotion", 
+                (name, enabled, idle_ms)).await.map_err(ClientError::MethodCallFailed)?;
+        }
     }
-    dbus_handle.abort();
     Ok(())
 }
+
+/// Re-enables every disabled touchpad with xinput, without needing a server. Recovers trackpads left disabled when the servers
+/// went away before the session server could enable them again. Touchpads are told apart by libinput's tapping property
+pub fn unlock_all() -> Result<(), ClientError> {
+    let output = std::process::Command::new("xinput").args(["list", "--id-only"]).output()
+        .map_err(|err| ClientError::XInputCallError(err))?;
+    let output = String::from_utf8(output.stdout).map_err(|_| ClientError::XInputParseError)?;
+    // Disabled devices are floating, which xinput marks with a leading ∼
+    let ids = output.lines().map(|id| id.trim_start_matches("∼ ").trim()).filter(|id| id.parse::<u32>().is_ok());
+    let mut enabled = 0;
+    for id in ids {
+        let props = std::process::Command::new("xinput").args(["list-props", id]).output()
+            .map_err(|err| ClientError::XInputCallError(err))?;
+        let props = String::from_utf8(props.stdout).map_err(|_| ClientError::XInputParseError)?;
+        let disabled = props.lines().any(|line| line.trim_start().starts_with("Device Enabled") && line.trim_end().ends_with(":\t0"));
+        if !disabled || !props.contains("libinput Tapping Enabled") {continue;}
+        let name = props.lines().next().and_then(|line| line.strip_prefix("Device '")).and_then(|line| line.strip_suffix("':")).unwrap_or(id);
+        let status = std::process::Command::new("xinput").args(["--enable", id]).status()
+            .map_err(|err| ClientError::XInputCallError(err))?;
+        if status.success() {
+            println!("Enabled {} (xinput id {})", name, id);
+            enabled += 1;
+        } else {
+            println!("Could not enable {} (xinput id {})", name, id);
+        }
+    }
+    if enabled == 0 {println!("No disabled touchpads found");}
+    Ok(())
+}
+
+/// Expands a glob of device paths, sorted so the numbering of the mice is stable. Fails if nothing matches
+pub fn expand_device_glob(pattern: &str) -> Result<Vec<String>, ClientError> {
+    let mut paths: Vec<String> = glob::glob(pattern).map_err(|_| ClientError::NoMatchingDevices(pattern.to_string()))?
+        .filter_map(|path| path.ok())
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    if paths.is_empty() {return Err(ClientError::NoMatchingDevices(pattern.to_string()));}
+    paths.sort();
+    Ok(paths)
+}
+
+/// Creates a mouse, printing the creation stages while waiting for it. Returns its name and event ids
+async fn create_mouse(proxy: &nonblock::Proxy<'_, Arc<SyncConnection>>, name: &str, path: &str, options: &MouseCreationOptions) -> Result<(String, u32, u32), ClientError> {
+    let mut option_map = PropMap::new();
+    option_map.insert("with-keyboard".to_string(), Variant(Box::new(options.with_keyboard)));
+    // Only sent when it differs from the default, so servers that predate the option still accept the call
+    if !options.hi_res_scroll {option_map.insert("hi-res-scroll".to_string(), Variant(Box::new(false)));}
+    if let Some(bus_type) = options.bus_type {option_map.insert("bus-type".to_string(), Variant(Box::new(bus_type)));}
+    if options.mirrors != 0 {option_map.insert("mirrors".to_string(), Variant(Box::new(options.mirrors)));}
+    if let Some(path) = &options.event_tap {option_map.insert("event-tap".to_string(), Variant(Box::new(path.clone())));}
+    if options.queue_depth != 0 {option_map.insert("queue-depth".to_string(), Variant(Box::new(options.queue_depth)));}
+    if options.queue_policy != QueuePolicy::Coalesce {option_map.insert("queue-policy".to_string(), Variant(Box::new(options.queue_policy.as_str().to_string())));}
+    if options.lock_delay_ms != 0 {option_map.insert("lock-delay-ms".to_string(), Variant(Box::new(options.lock_delay_ms)));}
+    if options.soft_start_ms != 0 {option_map.insert("soft-start-ms".to_string(), Variant(Box::new(options.soft_start_ms)));}
+    if let Some(profile) = &options.profile {option_map.insert("profile".to_string(), Variant(Box::new(profile.clone())));}
+    if options.scroll_key_map != ScrollKeyMap::Off {option_map.insert("scroll-key-map".to_string(), Variant(Box::new(options.scroll_key_map.as_str().to_string())));}
+    // Show the creation stages while waiting, creation can take a while on slow devices
+    let watched_name = name.to_string();
+    // Only the progress of the server the mouse is created on, other instances may be creating mice of the same name
+    let progress = proxy.connection.add_match(MatchRule::new_signal("com.cowsociety.virtual_mouse", "MouseCreationProgress").with_sender(proxy.destination.clone().into_static())).await
+        .map_err(ClientError::MethodCallFailed)?
+        .cb(move |_, (name, stage): (String, String)| {
+            if name == watched_name {println!("{}...", stage);}
+            true
+        });
+    let result: Result<(String, u32, u32), dbus::Error> = proxy.method_call(
+        "com.cowsociety.virtual_mouse", 
+        "CreateNewMouseWithOptions", 
+        (name, path, option_map)
+    ).await;
+    let _ = proxy.connection.remove_match(progress.token()).await;
+    result.map_err(ClientError::MethodCallFailed)
+}
+
+/// Formats a dbus value for printing, arrays as comma separated lists
+fn format_value(value: &dyn RefArg) -> String {
+    if let Some(string) = value.as_str() {return string.to_string();}
+    if let Some(number) = value.as_u64() {return number.to_string();}
+    if let Some(items) = value.as_iter() {return items.map(|item| format_value(item)).collect::<Vec<String>>().join(", ");}
+    format!("{:?}", value)
+}
+
+/// Formats how long ago a unix timestamp was, e.g. "3h 12m"
+fn format_age(timestamp: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let seconds = now.saturating_sub(timestamp);
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds/60),
+        3600..=86399 => format!("{}h {}m", seconds/3600, seconds%3600/60),
+        _ => format!("{}d {}h", seconds/86400, seconds%86400/3600)
+    }
+}
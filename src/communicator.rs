@@ -1,27 +1,342 @@
-use std::{collections::{HashMap, HashSet}, future::Future, sync::{Arc, Mutex}, task::{Poll, Waker}};
+use std::{collections::{HashMap, HashSet, VecDeque}, path::PathBuf, time::{Duration, SystemTime, UNIX_EPOCH}, future::Future, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, task::{Poll, Waker}};
 
-use crate::mouse::{MouseCreationError, MouseInfo};
+use crate::{config::{self, MouseConfig}, mouse::{MouseCreationError, MouseCreationOptions, MouseInfo, MouseOwner, MouseSettings, RebindRequest}};
 
+/// How many aborted mice the communicator remembers
+const ABORTED_HISTORY: usize = 16;
+/// How many failed creations the communicator remembers
+const FAILURE_HISTORY: usize = 16;
+/// How long creation errors are kept, both the ones nobody collected and the failure history
+const ERROR_RETENTION: Duration = Duration::from_secs(600);
+/// Most uncollected creation errors kept at once, the oldest is evicted beyond it, so a burst of failures can't grow the map
+const MAX_UNCOLLECTED_ERRORS: usize = 64;
+/// How long after its creation CancelCreate still stops a mouse, a bit longer than DBus's default 25 second method call timeout,
+/// so a client that gave up waiting can still take its mouse back
+const CANCEL_GRACE: Duration = Duration::from_secs(30);
+
+/// What cancelling a mouse creation did
+pub enum CancelOutcome{
+    /// The mouse was still queued and won't be created
+    Dequeued,
+    /// The mouse was just created and is being stopped, contains its input id
+    Stopped(u32),
+    /// There is no queued or recently created mouse with the name
+    NotFound
+}
+
+/// Which of a mouse's event ids a lookup uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventIdKind{
+    /// The trackpad's event id
+    Input,
+    /// The virtual device's event id, or one of its mirrors'
+    Output
+}
+impl EventIdKind{
+    /// Name of the kind, as used over DBus
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventIdKind::Input => "input",
+            EventIdKind::Output => "output"
+        }
+    }
+    /// Looks up a kind by name, none for unknown names
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "input" => Some(EventIdKind::Input),
+            "output" => Some(EventIdKind::Output),
+            _ => None
+        }
+    }
+}
 
 /// A struct used to facilitate communication between the non send mouse driver, and the DBus listener threads
 #[derive(Debug, Default)]
 pub struct Communicator{
-    /// Hashmap of queued mice, name -> evdev event path
-    pub queued_mice: HashMap<String, String>,
+    /// Hashmap of queued mice, name -> (evdev event path, creation options)
+    pub queued_mice: HashMap<String, (String, MouseCreationOptions)>,
     /// Hashmap of currently simulated mice, name -> mouse info
     pub current_mice: HashMap<String, MouseInfo>,
-    /// Hashmap of errors from the mouse creation process, name -> error message
-    pub errors: HashMap<String, MouseCreationError>,
+    /// Hashmap of errors from the mouse creation process, name -> (error, time it happened).
+    /// Removed when a waiting future collects them, or after ERROR_RETENTION or beyond MAX_UNCOLLECTED_ERRORS if nobody does
+    pub errors: HashMap<String, (MouseCreationError, SystemTime)>,
     /// Handle to a waker that should be called any time a new queued mice is added.
     pub work_waker: Option<Waker>,
     /// Handle to wakers that should be called when a queued mice has finished being processed
-    pub result_wakers: HashMap<String, Waker>,
+    /// Every waiting future has its own waker, so several requests for the same name all get woken
+    pub result_wakers: HashMap<String, Vec<Waker>>,
     /// whether or not to shutdown the system, and a waker to call when you set the bool to true
     pub shutdown: (bool, Option<Waker>),
     /// Set of mice names to stop
     pub dequeued_mice: HashSet<String>,
     /// Waker that should be called when mice are added to dequeued_mice
-    pub dequeue_waker: Option<Waker>
+    pub dequeue_waker: Option<Waker>,
+    /// Wakers that should be called when mice are added to current_mice
+    pub presence_wakers: Vec<Waker>,
+    /// The most recently aborted mice, oldest first, as (name, error, time of the abort). Holds at most ABORTED_HISTORY entries
+    pub aborted_mice: VecDeque<(String, String, SystemTime)>,
+    /// Whether all mice are paused. Shared with every mouse driver, which ignores its trackpad while it is set
+    pub paused: Arc<AtomicBool>,
+    /// The most recently failed creations, oldest first, as (name, error, time of the failure).
+    /// Holds at most FAILURE_HISTORY entries, none older than ERROR_RETENTION
+    pub failed_creations: VecDeque<(String, String, SystemTime)>,
+    /// Named settings mice can be created with, name -> settings
+    pub profiles: HashMap<String, MouseSettings>,
+    /// File the profiles are saved to whenever they change, none keeps them in memory only
+    pub profiles_file: Option<PathBuf>,
+    /// When the server started, none until the server sets it
+    pub started_at: Option<SystemTime>
+}
+
+impl Communicator{
+    /// Queues a mouse for creation, waking the mouse manager
+    pub fn queue_mouse(&mut self, name: String, path: String, options: MouseCreationOptions) {
+        // An error left by an earlier attempt nobody waited for would otherwise be returned for this one
+        self.errors.remove(&name);
+        // Wakers are only needed for queued mice, the manager removes them under this lock once it creates one,
+        // so any left for other names belong to futures that were dropped
+        self.result_wakers.retain(|name, _| self.queued_mice.contains_key(name));
+        self.queued_mice.insert(name, (path, options));
+        if let Some(waker) = self.work_waker.take() {waker.wake();}
+    }
+    /// Queues a mouse to be stopped, waking the mouse manager. Returns the mouse's input id if it exists
+    pub fn stop_mouse(&mut self, name: String) -> Option<u32> {
+        let input_id = self.current_mice.get(&name).map(|info| info.input_id);
+        self.dequeued_mice.insert(name);
+        if let Some(waker) = self.dequeue_waker.take() {waker.wake();}
+        input_id
+    }
+    /// Whether the mouse is still running, false once it is queued to be stopped or a new mouse took over its name
+    pub fn is_running(&self, info: &MouseInfo) -> bool {
+        !self.dequeued_mice.contains(&info.name) && self.current_mice.get(&info.name).is_some_and(|current| current.created_at == info.created_at)
+    }
+    /// Name of the mouse with the event id, none if no mouse has it
+    pub fn name_by_id(&self, kind: EventIdKind, id: u32) -> Option<String> {
+        self.current_mice.values().find(|info| match kind {
+            EventIdKind::Input => info.input_id == id,
+            EventIdKind::Output => info.output_id == id || info.mirror_output_ids.contains(&id)
+        }).map(|info| info.name.clone())
+    }
+    /// When the server started in seconds since the unix epoch, 0 if it wasn't set
+    pub fn started_at_unix(&self) -> u64 {
+        self.started_at.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|duration| duration.as_secs()).unwrap_or(0)
+    }
+    /// Name and output id of the mouse reading the trackpad with the input id, none if no mouse reads it
+    pub fn resolve_mapping(&self, input_id: u32) -> Option<(String, u32)> {
+        self.current_mice.values().find(|info| info.input_id == input_id).map(|info| (info.name.clone(), info.output_id))
+    }
+    /// Cancels the creation of a mouse. A queued mouse is removed from the queue, and its waiting futures resolve with Cancelled.
+    /// A mouse created within CANCEL_GRACE is stopped, older ones are left alone since their creation has long been answered
+    pub fn cancel_create(&mut self, name: String) -> CancelOutcome {
+        if self.queued_mice.remove(&name).is_some() {
+            self.record_error(name.clone(), MouseCreationError::Cancelled);
+            self.result_wakers.remove(&name).into_iter().flatten().for_each(|waker| waker.wake());
+            return CancelOutcome::Dequeued;
+        }
+        let recent = self.current_mice.get(&name).is_some_and(|info| info.created_at.elapsed().is_ok_and(|age| age < CANCEL_GRACE));
+        if !recent {return CancelOutcome::NotFound;}
+        match self.stop_mouse(name) {
+            Some(input_id) => CancelOutcome::Stopped(input_id),
+            None => CancelOutcome::NotFound
+        }
+    }
+    /// Queues every mouse to be stopped, waking the mouse manager. Returns the input ids of the mice
+    pub fn stop_all_mice(&mut self) -> Vec<u32> {
+        let names: Vec<String> = self.current_mice.keys().cloned().collect();
+        self.dequeued_mice.extend(names);
+        if let Some(waker) = self.dequeue_waker.take() {waker.wake();}
+        self.current_mice.values().map(|info| info.input_id).collect()
+    }
+    /// Requests a shutdown, waking the mouse manager. Returns the input ids of the mice that will be stopped
+    pub fn request_shutdown(&mut self) -> Vec<u32> {
+        self.shutdown.0 = true;
+        if let Some(waker) = self.shutdown.1.take() {waker.wake();}
+        self.current_mice.values().map(|info| info.input_id).collect()
+    }
+    /// Pauses or resumes all mice. Returns the input ids of the mice with locked trackpads, which are enabled while paused.
+    /// Unlocked trackpads are enabled either way
+    pub fn set_paused(&mut self, paused: bool) -> Vec<u32> {
+        self.paused.store(paused, Ordering::Relaxed);
+        self.current_mice.values().filter(|info| info.is_locked()).map(|info| info.input_id).collect()
+    }
+    /// Whether all mice are paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+    /// Lists the current mice as (name, input id, output id, creation time as a unix timestamp), sorted by name
+    pub fn list_mice(&self) -> Vec<(String, u32, u32, u64)> {
+        let mut mice: Vec<(String, u32, u32, u64)> = self.current_mice.values().map(|info| (info.name.clone(), info.input_id, info.output_id, info.created_at_unix())).collect();
+        mice.sort();
+        mice
+    }
+    /// The current mice as they would be configured in a config file, sorted by name
+    pub fn mouse_configs(&self) -> Vec<MouseConfig> {
+        let mut mice: Vec<MouseConfig> = self.current_mice.values().map(|info| MouseConfig{
+            name: info.name.clone(),
+            path: info.input_path.clone(),
+            options: info.options.clone(),
+            settings: Some(info.settings.lock().unwrap().clone())
+        }).collect();
+        mice.sort_by(|a, b| a.name.cmp(&b.name));
+        mice
+    }
+    /// Stores the error of a failed creation for the waiting futures and the failure history, forgetting errors past their retention
+    pub fn record_error(&mut self, name: String, error: MouseCreationError) {
+        let now = SystemTime::now();
+        let expired = |time: &SystemTime| now.duration_since(*time).is_ok_and(|age| age > ERROR_RETENTION);
+        self.errors.retain(|_, (_, time)| !expired(time));
+        while self.errors.len() >= MAX_UNCOLLECTED_ERRORS {
+            let oldest = self.errors.iter().min_by_key(|(_, (_, time))| *time).map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {self.errors.remove(&oldest);}
+        }
+        self.failed_creations.retain(|(_, _, time)| !expired(time));
+        if self.failed_creations.len() >= FAILURE_HISTORY {self.failed_creations.pop_front();}
+        self.failed_creations.push_back((name.clone(), error.to_string(), now));
+        self.errors.insert(name, (error, now));
+    }
+    /// Lists the recently failed creations, oldest first, as (name, error, failure time as a unix timestamp)
+    pub fn list_failures(&self) -> Vec<(String, String, u64)> {
+        let now = SystemTime::now();
+        self.failed_creations.iter()
+            .filter(|(_, _, time)| !now.duration_since(*time).is_ok_and(|age| age > ERROR_RETENTION))
+            .map(|(name, error, time)| {
+                (name.clone(), error.clone(), time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0))
+            }).collect()
+    }
+    /// Human readable summary of the communicator's state, for debugging stuck states
+    pub fn state_dump(&self) -> String {
+        fn sorted<'a>(names: impl Iterator<Item = &'a String>) -> Vec<&'a String> {
+            let mut names: Vec<&String> = names.collect();
+            names.sort();
+            names
+        }
+        let mut dump = format!("Paused: {}\n", self.is_paused());
+        dump += &format!("Active mice ({}):\n", self.current_mice.len());
+        for name in sorted(self.current_mice.keys()) {
+            let info = &self.current_mice[name];
+            dump += &format!("  {}: input {} output {} mirrors {:?}, path {}, created at {}\n", name, info.input_id, info.output_id, info.mirror_output_ids, info.input_path, info.created_at_unix());
+            dump += &format!("    options: {:?}\n", info.options);
+            if let Some(owner) = &info.owner {dump += &format!("    owner: {} (uid {})\n", owner.client, owner.uid);}
+            dump += &format!("    settings: {:?}\n", *info.settings.lock().unwrap());
+        }
+        dump += &format!("Queued mice ({}):\n", self.queued_mice.len());
+        for name in sorted(self.queued_mice.keys()) {
+            dump += &format!("  {}: path {}\n", name, self.queued_mice[name].0);
+        }
+        dump += &format!("Mice to stop ({}): {:?}\n", self.dequeued_mice.len(), sorted(self.dequeued_mice.iter()));
+        dump += &format!("Uncollected creation errors: {}\n", self.errors.len());
+        dump += &format!("Creations waited on: {}\n", self.result_wakers.len());
+        dump += &format!("Profiles: {:?}\n", self.list_profiles());
+        dump += &format!("Recently aborted mice: {}, recently failed creations: {}\n", self.aborted_mice.len(), self.failed_creations.len());
+        dump += &format!("Shutdown requested: {}", self.shutdown.0);
+        dump
+    }
+    /// Remembers that a mouse was aborted, forgetting the oldest abort once the history is full
+    pub fn record_abort(&mut self, name: String, error: String) {
+        if self.aborted_mice.len() >= ABORTED_HISTORY {self.aborted_mice.pop_front();}
+        self.aborted_mice.push_back((name, error, SystemTime::now()));
+    }
+    /// Lists the recently aborted mice, oldest first, as (name, error, abort time as a unix timestamp)
+    pub fn list_aborted(&self) -> Vec<(String, String, u64)> {
+        self.aborted_mice.iter().map(|(name, error, time)| {
+            (name.clone(), error.clone(), time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0))
+        }).collect()
+    }
+    /// Runs a closure on the settings of the named mouse, none if the mouse does not exist
+    pub fn with_settings<R>(&self, name: &str, f: impl FnOnce(&mut MouseSettings) -> R) -> Option<R> {
+        let info = self.current_mice.get(name)?;
+        let mut settings = info.settings.lock().unwrap();
+        Some(f(&mut settings))
+    }
+    /// Changes the settings of the named mouse like with_settings, but only keeps the change if the settings stay valid, returning why otherwise.
+    /// Returns none if the mouse does not exist
+    pub fn with_checked_settings(&self, name: &str, f: impl FnOnce(&mut MouseSettings)) -> Option<Result<(), String>> {
+        let info = self.current_mice.get(name)?;
+        let mut settings = info.settings.lock().unwrap();
+        let mut changed = settings.clone();
+        f(&mut changed);
+        Some(config::validate_settings(&changed, &format!("mouse {}", name)).map(|_| *settings = changed).map_err(|err| err.to_string()))
+    }
+    /// Turns the high resolution scroll events of the named mouse on or off. They can't be turned on for a mouse created without the
+    /// high resolution axes, the virtual device couldn't emit them. Returns none if the mouse does not exist
+    pub fn set_hi_res_scroll(&self, name: &str, enabled: bool) -> Option<Result<(), String>> {
+        let info = self.current_mice.get(name)?;
+        if enabled && !info.options.hi_res_scroll {
+            return Some(Err(format!("Mouse {} was created without high resolution scroll axes", name)));
+        }
+        info.settings.lock().unwrap().hi_res_scroll = enabled;
+        Some(Ok(()))
+    }
+    /// Stores a profile, replacing the one with the same name. Fails if the profiles file can't be written, the profile is stored anyway
+    pub fn save_profile(&mut self, name: String, settings: MouseSettings) -> Result<(), String> {
+        self.profiles.insert(name, settings);
+        self.persist_profiles()
+    }
+    /// Removes a profile. Returns whether it existed, and fails if the profiles file can't be written
+    pub fn delete_profile(&mut self, name: &str) -> Result<bool, String> {
+        if self.profiles.remove(name).is_none() {return Ok(false);}
+        self.persist_profiles().map(|_| true)
+    }
+    /// Names of the profiles, sorted
+    pub fn list_profiles(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.profiles.keys().cloned().collect();
+        names.sort();
+        names
+    }
+    /// Writes the profiles to the profiles file, if there is one
+    fn persist_profiles(&self) -> Result<(), String> {
+        match &self.profiles_file {
+            Some(path) => config::save_profiles(path, &self.profiles).map_err(|err| err.to_string()),
+            None => Ok(())
+        }
+    }
+    /// Copies all settings of the mouse named from onto the mouse named to. Fails with the name of the mouse that does not exist
+    pub fn clone_settings(&self, from: &str, to: &str) -> Result<(), String> {
+        let settings = self.with_settings(from, |settings| settings.clone()).ok_or_else(|| from.to_string())?;
+        self.with_settings(to, |target| *target = settings).ok_or_else(|| to.to_string())
+    }
+    /// Stores the info a mouse's driver reported after switching trackpads. Returns the mouse's previous input id, none if it is no longer running
+    pub fn rebound(&mut self, mut info: MouseInfo) -> Option<u32> {
+        if !self.is_running(&info) {return None;}
+        // The driver doesn't know who created the mouse, so the owner is kept from the stored info
+        info.owner = self.current_mice.get(&info.name).and_then(|current| current.owner.clone());
+        self.current_mice.insert(info.name.clone(), info).map(|old| old.input_id)
+    }
+    /// Records the client that created a mouse, unless the mouse was stopped or replaced since. Returns the info with the owner
+    pub fn set_owner(&mut self, mut info: MouseInfo, owner: MouseOwner) -> MouseInfo {
+        if self.is_running(&info) {
+            if let Some(current) = self.current_mice.get_mut(&info.name) {current.owner = Some(owner.clone());}
+        }
+        info.owner = Some(owner);
+        info
+    }
+    /// Queues the mice tied to the client for stopping, after it disconnected. Returns their input ids
+    pub fn stop_tied_mice(&mut self, client: &str) -> Vec<u32> {
+        let names: Vec<String> = self.current_mice.values()
+            .filter(|info| info.options.tie_to_caller && info.owner.as_ref().is_some_and(|owner| owner.client == client))
+            .map(|info| info.name.clone())
+            .collect();
+        names.into_iter().filter_map(|name| self.stop_mouse(name)).collect()
+    }
+    /// Lists the mice created by a client as (name, client, uid), sorted by name. Mice from the config file have no owner and aren't listed
+    pub fn list_owners(&self) -> Vec<(String, String, u32)> {
+        let mut owners: Vec<(String, String, u32)> = self.current_mice.values()
+            .filter_map(|info| info.owner.as_ref().map(|owner| (info.name.clone(), owner.client.clone(), owner.uid)))
+            .collect();
+        owners.sort();
+        owners
+    }
+}
+
+/// Has the mouse named name read the trackpad at input_path instead of its current one, keeping its virtual devices.
+/// Returns the mouse's previous input id and its updated info
+pub async fn rebind_mouse(com: &Arc<Mutex<Communicator>>, name: &str, input_path: String) -> Result<(u32, MouseInfo), String> {
+    let rebinder = com.lock().unwrap().current_mice.get(name).ok_or_else(|| format!("No mouse named {}", name))?.rebinder.clone();
+    let (reply, result) = tokio::sync::oneshot::channel();
+    rebinder.send(RebindRequest{input_path, reply}).map_err(|_| format!("Mouse {} is no longer running", name))?;
+    let info = result.await.map_err(|_| format!("Mouse {} is no longer running", name))?.map_err(|err| err.to_string())?;
+    let old_input_id = com.lock().unwrap().rebound(info.clone()).ok_or_else(|| format!("Mouse {} is no longer running", name))?;
+    Ok((old_input_id, info))
 }
 
 /// Future which waits for the communicator to request a shutdown. places a waker into the communicator which should be used by anything that sets shutdown to true
@@ -82,15 +397,213 @@ impl Future for CommunicatorResultFuture{
     fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let mut communicator  = self.handle.lock().unwrap();
         if communicator.queued_mice.contains_key(&self.name) {
-            communicator.result_wakers.insert(self.name.clone(), cx.waker().clone());
+            // A future polled again only replaces its own waker, so the list can't grow while waiting
+            let wakers = communicator.result_wakers.entry(self.name.clone()).or_default();
+            wakers.retain(|waker| !waker.will_wake(cx.waker()));
+            wakers.push(cx.waker().clone());
             return Poll::Pending;
         }
         if let Some(info) = communicator.current_mice.get(&self.name) {
             return Poll::Ready(Ok(info.clone()));
         }
-        if let Some(err) = communicator.errors.remove(&self.name){
+        if let Some((err, _)) = communicator.errors.remove(&self.name){
             return Poll::Ready(Err(err));
         }
         Poll::Ready(Err(MouseCreationError::AsyncProgramError))
     }
 }
+
+
+/// Future which waits until a mouse with a specific name is in current_mice. Places a waker in the communicator, needs to be woken by anything that adds current mice
+pub struct CommunicatorPresenceFuture{
+    /// Name of the mouse this future is waiting on
+    pub name: String,
+    pub com: Arc<Mutex<Communicator>>
+}
+impl Future for CommunicatorPresenceFuture{
+    type Output = MouseInfo;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        let mut communicator = self.com.lock().unwrap();
+        if let Some(info) = communicator.current_mice.get(&self.name) {return Poll::Ready(info.clone());}
+        communicator.presence_wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn communicator_with(mice: &[MouseInfo]) -> Communicator {
+        let mut com = Communicator::default();
+        for info in mice {
+            com.current_mice.insert(info.name.clone(), info.clone());
+        }
+        com
+    }
+
+    #[test]
+    fn checked_settings_reject_invalid_axis_scales() {
+        let com = communicator_with(&[MouseInfo::detached("trackpad", 3)]);
+        for scale in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let result = com.with_checked_settings("trackpad", |settings| {settings.x_scale = 2.0; settings.y_scale = scale;});
+            assert!(matches!(result, Some(Err(_))), "scale {} was accepted", scale);
+        }
+        // A rejected change leaves the settings as they were
+        assert_eq!(com.with_settings("trackpad", |settings| settings.x_scale), Some(1.0));
+        assert_eq!(com.with_checked_settings("trackpad", |settings| {settings.x_scale = 2.0; settings.y_scale = 0.5;}), Some(Ok(())));
+        assert_eq!(com.with_settings("trackpad", |settings| (settings.x_scale, settings.y_scale)), Some((2.0, 0.5)));
+    }
+
+    #[test]
+    fn checked_settings_reject_invalid_scroll_deadzones() {
+        let com = communicator_with(&[MouseInfo::detached("trackpad", 3)]);
+        for deadzone in [-0.1, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(matches!(com.with_checked_settings("trackpad", |settings| settings.scroll_deadzone = deadzone), Some(Err(_))), "deadzone {} was accepted", deadzone);
+        }
+        assert_eq!(com.with_checked_settings("trackpad", |settings| settings.scroll_deadzone = 0.0), Some(Ok(())));
+        assert_eq!(com.with_checked_settings("trackpad", |settings| settings.scroll_deadzone = 0.3), Some(Ok(())));
+        assert_eq!(com.with_settings("trackpad", |settings| settings.scroll_deadzone), Some(0.3));
+    }
+
+    #[test]
+    fn hi_res_scroll_needs_the_axes() {
+        let mut without_axes = MouseInfo::detached("plain", 4);
+        without_axes.options.hi_res_scroll = false;
+        without_axes.settings.lock().unwrap().hi_res_scroll = false;
+        let com = communicator_with(&[MouseInfo::detached("trackpad", 3), without_axes]);
+        assert_eq!(com.set_hi_res_scroll("trackpad", false), Some(Ok(())));
+        assert_eq!(com.set_hi_res_scroll("trackpad", true), Some(Ok(())));
+        assert!(matches!(com.set_hi_res_scroll("plain", true), Some(Err(_))));
+        assert_eq!(com.with_settings("plain", |settings| settings.hi_res_scroll), Some(false));
+        // Turning them off is always fine
+        assert_eq!(com.set_hi_res_scroll("plain", false), Some(Ok(())));
+        assert_eq!(com.set_hi_res_scroll("missing", false), None);
+    }
+
+    #[test]
+    fn checked_settings_need_the_mouse() {
+        let com = communicator_with(&[]);
+        assert_eq!(com.with_checked_settings("trackpad", |settings| settings.x_scale = 2.0), None);
+    }
+
+    #[test]
+    fn stopping_a_mouse_queues_it_for_removal() {
+        let trackpad = MouseInfo::detached("trackpad", 3);
+        let mut com = communicator_with(&[trackpad.clone(), MouseInfo::detached("other", 4)]);
+        assert!(com.is_running(&trackpad));
+        // The input id is what the MouseDeleted signal carries, so the session server enables the trackpad again
+        assert_eq!(com.stop_mouse("trackpad".to_string()), Some(3));
+        assert!(com.dequeued_mice.contains("trackpad"));
+        assert!(!com.is_running(&trackpad));
+        // Unknown mice are still queued, there is just no input id to signal
+        assert_eq!(com.stop_mouse("missing".to_string()), None);
+        assert!(com.dequeued_mice.contains("missing"));
+    }
+
+    #[test]
+    fn cancelling_a_new_mouse_stops_it() {
+        let mut com = communicator_with(&[MouseInfo::detached("trackpad", 3)]);
+        assert!(matches!(com.cancel_create("trackpad".to_string()), CancelOutcome::Stopped(3)));
+        assert!(com.dequeued_mice.contains("trackpad"));
+    }
+
+    #[test]
+    fn stopping_all_mice_queues_every_one() {
+        let mut com = communicator_with(&[MouseInfo::detached("trackpad", 3), MouseInfo::detached("other", 4)]);
+        let mut input_ids = com.stop_all_mice();
+        input_ids.sort();
+        assert_eq!(input_ids, vec![3, 4]);
+        assert_eq!(com.dequeued_mice, HashSet::from(["trackpad".to_string(), "other".to_string()]));
+    }
+
+    #[test]
+    fn pausing_only_signals_locked_trackpads() {
+        let unlocked = MouseInfo::detached("unlocked", 4);
+        unlocked.locked.store(false, Ordering::Relaxed);
+        let mut com = communicator_with(&[MouseInfo::detached("trackpad", 3), unlocked]);
+        // The unlocked trackpad is enabled the whole time, resuming must not disable it
+        assert_eq!(com.set_paused(true), vec![3]);
+        assert!(com.is_paused());
+        assert_eq!(com.set_paused(false), vec![3]);
+        assert!(!com.is_paused());
+    }
+
+    #[test]
+    fn uncollected_errors_are_bounded() {
+        let mut com = Communicator::default();
+        for index in 0..MAX_UNCOLLECTED_ERRORS + 10 {
+            com.record_error(format!("mouse{}", index), MouseCreationError::NameInUse);
+        }
+        assert_eq!(com.errors.len(), MAX_UNCOLLECTED_ERRORS);
+        assert!(com.errors.contains_key(&format!("mouse{}", MAX_UNCOLLECTED_ERRORS + 9)));
+        assert_eq!(com.failed_creations.len(), FAILURE_HISTORY);
+    }
+
+    #[test]
+    fn expired_errors_are_forgotten() {
+        let mut com = Communicator::default();
+        let long_ago = SystemTime::now() - ERROR_RETENTION - Duration::from_secs(1);
+        com.errors.insert("stale".to_string(), (MouseCreationError::NameInUse, long_ago));
+        com.failed_creations.push_back(("stale".to_string(), "error".to_string(), long_ago));
+        com.record_error("fresh".to_string(), MouseCreationError::NameInUse);
+        assert!(!com.errors.contains_key("stale"));
+        assert!(com.errors.contains_key("fresh"));
+        assert_eq!(com.list_failures().len(), 1);
+    }
+
+    /// Waker that records whether it was woken
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+    impl futures::task::ArcWake for Flag {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Polls a future once with the waker of the flag
+    fn poll_with<F: Future + Unpin>(future: &mut F, flag: &Arc<Flag>) -> Poll<F::Output> {
+        let waker = futures::task::waker(flag.clone());
+        std::pin::Pin::new(future).poll(&mut std::task::Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn every_waiting_future_keeps_one_waker() {
+        let com = Arc::new(Mutex::new(Communicator::default()));
+        com.lock().unwrap().queue_mouse("trackpad".to_string(), "/dev/input/event3".to_string(), MouseCreationOptions::default());
+        let (first_flag, second_flag) = (Arc::new(Flag::default()), Arc::new(Flag::default()));
+        let mut first = CommunicatorResultFuture{name: "trackpad".to_string(), handle: com.clone()};
+        let mut second = CommunicatorResultFuture{name: "trackpad".to_string(), handle: com.clone()};
+        assert!(poll_with(&mut first, &first_flag).is_pending());
+        assert!(poll_with(&mut second, &second_flag).is_pending());
+        // Polling again replaces the future's own waker instead of adding one
+        assert!(poll_with(&mut first, &first_flag).is_pending());
+        assert_eq!(com.lock().unwrap().result_wakers["trackpad"].len(), 2);
+        // Both are woken once the creation is decided, and no waker is left behind
+        com.lock().unwrap().cancel_create("trackpad".to_string());
+        assert!(first_flag.0.load(Ordering::SeqCst) && second_flag.0.load(Ordering::SeqCst));
+        assert!(com.lock().unwrap().result_wakers.is_empty());
+        assert!(matches!(poll_with(&mut first, &first_flag), Poll::Ready(Err(MouseCreationError::Cancelled))));
+    }
+
+    #[test]
+    fn wakers_of_dropped_futures_are_cleared() {
+        let com = Arc::new(Mutex::new(Communicator::default()));
+        com.lock().unwrap().queue_mouse("trackpad".to_string(), "/dev/input/event3".to_string(), MouseCreationOptions::default());
+        let mut future = CommunicatorResultFuture{name: "trackpad".to_string(), handle: com.clone()};
+        assert!(poll_with(&mut future, &Arc::new(Flag::default())).is_pending());
+        drop(future);
+        // The mouse is no longer queued, e.g. it was created while nobody waited, so queueing another one clears the waker
+        com.lock().unwrap().queued_mice.remove("trackpad");
+        com.lock().unwrap().queue_mouse("other".to_string(), "/dev/input/event4".to_string(), MouseCreationOptions::default());
+        assert!(!com.lock().unwrap().result_wakers.contains_key("trackpad"));
+    }
+
+    #[test]
+    fn queueing_clears_a_stale_error() {
+        let mut com = Communicator::default();
+        com.record_error("trackpad".to_string(), MouseCreationError::NameInUse);
+        com.queue_mouse("trackpad".to_string(), "/dev/input/event3".to_string(), MouseCreationOptions::default());
+        assert!(!com.errors.contains_key("trackpad"));
+    }
+}
@@ -0,0 +1,284 @@
+/* Configuration
+    Server wide configuration and the mice to create at startup, set from the command line or loaded from a TOML or JSON file.
+    Everything has a default, so a file only needs the values it changes, e.g.
+        acquire_retries = 3
+        [[mice]]
+        name = "trackpad"
+        path = "/dev/input/event5"
+        settings = { scroll_accel = true, x_scale = 1.2 }
+*/
+
+use std::{collections::{BTreeMap, HashMap}, error::Error, fmt::Display, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+use crate::mouse::{MouseCreationOptions, MouseSettings};
+
+/// Error representing ways loading the configuration can fail
+#[derive(Debug)]
+pub enum ConfigError{
+    ReadFailed(std::io::Error),
+    ParseFailed(String),
+    Invalid(String),
+    SerializeFailed(String),
+    WriteFailed(std::io::Error)
+}
+impl Display for ConfigError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            ConfigError::ReadFailed(err) => format!("Could not read the config file. IO Error: {}", err),
+            ConfigError::ParseFailed(err) => format!("Could not parse the config file: {}", err),
+            ConfigError::Invalid(err) => format!("The config is invalid: {}", err),
+            ConfigError::SerializeFailed(err) => format!("Could not serialize the config: {}", err),
+            ConfigError::WriteFailed(err) => format!("Could not write the file. IO Error: {}", err)
+        };
+        f.write_str(string.as_str())?;
+        Ok(())
+    }
+}
+impl Error for ConfigError{}
+
+/// Server wide configuration, set from the command line or a config file when the server is started
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig{
+    /// Unix user ids that are allowed to create and stop mice
+    pub allowed_uids: Vec<u32>,
+    /// How many times acquiring the dbus name is retried, with exponential backoff, before giving up
+    pub acquire_retries: u32,
+    /// Path of a unix socket to listen for commands on instead of using DBus
+    pub socket: Option<PathBuf>,
+    /// Seconds without any mice after which the server exits, to be started again on demand by DBus or socket activation. 0 never exits
+    pub idle_timeout_secs: u64,
+    /// Name of the server instance, instances own their own bus name so several servers can run side by side
+    pub instance: Option<String>,
+    /// JSON file the settings profiles are loaded from at startup and saved to whenever they change, none keeps them in memory only
+    pub profiles_file: Option<PathBuf>,
+    /// Mice created when the server starts
+    pub mice: Vec<MouseConfig>
+}
+impl Default for ServerConfig{
+    /// By default only the user running the server may create and stop mice
+    fn default() -> Self {
+        ServerConfig { allowed_uids: vec![unsafe {libc::getuid()}], acquire_retries: 0, socket: None, idle_timeout_secs: 0, instance: None, profiles_file: None, mice: vec![] }
+    }
+}
+impl ServerConfig{
+    /// Checks the values that can't be expressed by their types
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(instance) = &self.instance {
+            if !valid_instance_name(instance) {
+                return Err(ConfigError::Invalid(format!("instance name {} has to be letters, digits, and underscores, not starting with a digit", instance)));
+            }
+        }
+        for (index, mouse) in self.mice.iter().enumerate() {
+            mouse.validate()?;
+            if self.mice[..index].iter().any(|other| other.name == mouse.name) {
+                return Err(ConfigError::Invalid(format!("mouse name {} is used twice", mouse.name)));
+            }
+        }
+        Ok(())
+    }
+    /// The bus name the server owns
+    pub fn bus_name(&self) -> String {
+        bus_name(self.instance.as_deref())
+    }
+}
+
+/// Bus name of the default server
+pub const DEFAULT_BUS_NAME: &str = "com.cowsociety.virtual_mouse";
+/// Directory the config files of named instances are looked up in
+pub const INSTANCE_CONFIG_DIR: &str = "/etc/trackpad-evdev-converter";
+
+/// The bus name of a server instance, the default one for none. Instances append their name as the last element
+pub fn bus_name(instance: Option<&str>) -> String {
+    match instance {
+        Some(instance) => format!("{}.{}", DEFAULT_BUS_NAME, instance),
+        None => DEFAULT_BUS_NAME.to_string()
+    }
+}
+
+/// Whether the name can be the last element of a bus name
+pub fn valid_instance_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The config file of a named instance, loaded when the instance is started without --config
+pub fn instance_config_path(instance: &str) -> PathBuf {
+    Path::new(INSTANCE_CONFIG_DIR).join(format!("{}.toml", instance))
+}
+
+/// A mouse created when the server starts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MouseConfig{
+    /// Name of the mouse
+    pub name: String,
+    /// Path of the trackpad's evdev event file
+    pub path: String,
+    /// Options the virtual device is created with
+    #[serde(default)]
+    pub options: MouseCreationOptions,
+    /// Settings the mouse starts with. Without them it keeps the settings of its profile, or the default ones
+    #[serde(default)]
+    pub settings: Option<MouseSettings>
+}
+impl MouseConfig{
+    /// Checks the values that can't be expressed by their types
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.name.is_empty() {
+            return Err(ConfigError::Invalid("mouse names can't be empty".to_string()));
+        }
+        match &self.settings {
+            Some(settings) => validate_settings(settings, &format!("mouse {}", self.name)),
+            None => Ok(())
+        }
+    }
+}
+
+/// Checks the settings values that can't be expressed by their types, owner names whose settings they are in the error
+pub fn validate_settings(settings: &MouseSettings, owner: &str) -> Result<(), ConfigError> {
+    for (axis, scale) in [("x_scale", settings.x_scale), ("y_scale", settings.y_scale), ("turbo_factor", settings.turbo_factor)] {
+        if !scale.is_finite() || scale <= 0.0 {
+            return Err(ConfigError::Invalid(format!("{} of {} has to be a positive number", axis, owner)));
+        }
+    }
+    if !settings.scroll_deadzone.is_finite() || settings.scroll_deadzone < 0.0 {
+        return Err(ConfigError::Invalid(format!("scroll_deadzone of {} can't be negative", owner)));
+    }
+    if settings.hi_res_quantum == 0 {
+        return Err(ConfigError::Invalid(format!("hi_res_quantum of {} has to be at least 1", owner)));
+    }
+    Ok(())
+}
+
+/// Loads and validates a profiles file, a JSON object from profile name to settings
+pub fn load_profiles(path: &Path) -> Result<HashMap<String, MouseSettings>, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(ConfigError::ReadFailed)?;
+    let profiles: HashMap<String, MouseSettings> = serde_json::from_str(&contents).map_err(|err| ConfigError::ParseFailed(err.to_string()))?;
+    for (name, settings) in profiles.iter() {
+        validate_settings(settings, &format!("profile {}", name))?;
+    }
+    Ok(profiles)
+}
+
+/// Writes the profiles to a profiles file, sorted by name so the file diffs well
+pub fn save_profiles(path: &Path, profiles: &HashMap<String, MouseSettings>) -> Result<(), ConfigError> {
+    let sorted: BTreeMap<&String, &MouseSettings> = profiles.iter().collect();
+    let contents = serde_json::to_string_pretty(&sorted).map_err(|err| ConfigError::SerializeFailed(err.to_string()))?;
+    std::fs::write(path, contents).map_err(ConfigError::WriteFailed)
+}
+
+/// Loads and validates a server config file. Files ending in .toml are read as TOML, anything else as JSON
+pub fn load_from_path(path: &Path) -> Result<ServerConfig, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(ConfigError::ReadFailed)?;
+    let config: ServerConfig = if path.extension().is_some_and(|extension| extension == "toml") {
+        toml::from_str(&contents).map_err(|err| ConfigError::ParseFailed(err.to_string()))?
+    } else {
+        serde_json::from_str(&contents).map_err(|err| ConfigError::ParseFailed(err.to_string()))?
+    };
+    config.validate()?;
+    Ok(config)
+}
+
+/// A config file holding only mice, so loading it on another machine keeps that machine's server options
+#[derive(Serialize)]
+struct MiceConfig{
+    mice: Vec<MouseConfig>
+}
+
+/// Serializes mice as a loadable config file, as TOML or JSON
+pub fn export_mice(mice: Vec<MouseConfig>, toml: bool) -> Result<String, ConfigError> {
+    let config = MiceConfig{mice};
+    if toml {
+        toml::to_string(&config).map_err(|err| ConfigError::SerializeFailed(err.to_string()))
+    } else {
+        serde_json::to_string_pretty(&config).map_err(|err| ConfigError::SerializeFailed(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes contents to a file in the temp directory, named so parallel tests don't collide
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("trackpad-evdev-converter-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn mouse(name: &str) -> MouseConfig {
+        MouseConfig{name: name.to_string(), path: "/dev/input/event5".to_string(), options: MouseCreationOptions::default(), settings: None}
+    }
+
+    #[test]
+    fn loads_toml_and_json() {
+        let toml = temp_file("config.toml", "acquire_retries = 3\n[[mice]]\nname = \"trackpad\"\npath = \"/dev/input/event5\"\nsettings = { scroll_accel = true, x_scale = 1.2 }\n");
+        let json = temp_file("config.json", r#"{"acquire_retries": 3, "mice": [{"name": "trackpad", "path": "/dev/input/event5", "settings": {"scroll_accel": true, "x_scale": 1.2}}]}"#);
+        for path in [toml, json] {
+            let config = load_from_path(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(config.acquire_retries, 3);
+            assert_eq!(config.mice.len(), 1);
+            let settings = config.mice[0].settings.as_ref().unwrap();
+            // Missing fields keep their defaults
+            assert!(settings.scroll_accel && settings.x_scale == 1.2 && settings.y_scale == 1.0);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let invalid_scale = MouseConfig{settings: Some(MouseSettings{x_scale: 0.0, ..Default::default()}), ..mouse("trackpad")};
+        let configs = [
+            ServerConfig{mice: vec![mouse("trackpad"), mouse("trackpad")], ..Default::default()},
+            ServerConfig{mice: vec![mouse("")], ..Default::default()},
+            ServerConfig{mice: vec![invalid_scale], ..Default::default()},
+            ServerConfig{instance: Some("2nd".to_string()), ..Default::default()}
+        ];
+        for config in configs {
+            assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))), "{:?} was accepted", config);
+        }
+        assert!(ServerConfig{mice: vec![mouse("trackpad"), mouse("other")], ..Default::default()}.validate().is_ok());
+    }
+
+    #[test]
+    fn validates_settings() {
+        let owner = "mouse trackpad";
+        assert!(validate_settings(&MouseSettings::default(), owner).is_ok());
+        assert!(validate_settings(&MouseSettings{turbo_factor: f64::NAN, ..Default::default()}, owner).is_err());
+        assert!(validate_settings(&MouseSettings{y_scale: -1.0, ..Default::default()}, owner).is_err());
+        assert!(validate_settings(&MouseSettings{scroll_deadzone: -0.1, ..Default::default()}, owner).is_err());
+        assert!(validate_settings(&MouseSettings{hi_res_quantum: 0, ..Default::default()}, owner).is_err());
+    }
+
+    #[test]
+    fn exported_mice_load_again() {
+        for toml in [true, false] {
+            let exported = export_mice(vec![mouse("trackpad")], toml).unwrap();
+            let path = temp_file(if toml {"export.toml"} else {"export.json"}, &exported);
+            let config = load_from_path(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(config.mice.len(), 1);
+            assert_eq!(config.mice[0].name, "trackpad");
+            assert_eq!(config.mice[0].path, "/dev/input/event5");
+        }
+    }
+
+    #[test]
+    fn saved_profiles_load_again() {
+        let path = temp_file("profiles.json", "");
+        let profiles = HashMap::from([("fast".to_string(), MouseSettings{x_scale: 2.0, y_scale: 2.0, ..Default::default()})]);
+        save_profiles(&path, &profiles).unwrap();
+        let loaded = load_profiles(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded["fast"].x_scale, 2.0);
+    }
+
+    #[test]
+    fn instance_names() {
+        assert!(valid_instance_name("office_2"));
+        assert!(!valid_instance_name(""));
+        assert!(!valid_instance_name("2nd"));
+        assert!(!valid_instance_name("a.b"));
+        assert_eq!(bus_name(Some("office")), "com.cowsociety.virtual_mouse.office");
+        assert_eq!(bus_name(None), DEFAULT_BUS_NAME);
+    }
+}
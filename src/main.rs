@@ -4,21 +4,106 @@ pub mod communicator;
 pub mod server;
 pub mod client;
 pub mod session;
+pub mod socket;
+pub mod config;
 
-use std::{env::args, error::Error, fmt::Display};
+use std::{env::args, error::Error, fmt::Display, path::{Path, PathBuf}};
 use client::ClientCommand;
+use mouse::{AccelProfile, EventRule, MouseCreationOptions, QueuePolicy, ScrollKeyMap, MAX_PROBE_DURATION};
+use config::ServerConfig;
+use communicator::EventIdKind;
 
 /// Prints the help message
 pub fn print_help() -> Result<(), Box<dyn std::error::Error>>{
     println!("Trackpad to Mouse evdev Conversion Utility: ");
     println!("Usage: trackpad-evdev-converter [function] [parameter]");
-    println!("\"\", \"--server\" : Starts a process to handle all mice conversions");
-    println!("\"-n\", \"--new\" : Tells the server to create a new mouse with parameters: name path_to_evdev_event");
-    println!("\"-l\", \"--list\" : Queries the server and prints all currently active mice, (name input_event_id output_event_id)");
+    println!("\"\", \"--server\" : Starts a process to handle all mice conversions, with options:");
+    println!("    \"--allow-uid\" uid : Allows the user to create and stop mice, can be repeated. Defaults to the user running the server");
+    println!("    \"--acquire-retries\" count : Retries acquiring the dbus name with exponential backoff, for restarts where the old server is still exiting. Defaults to 0");
+    println!("    \"--socket\" path : Listens for commands on a unix socket at path instead of using DBus");
+    println!("    \"--idle-timeout\" seconds : Exits after this long without any mice, for DBus or socket activation to start it again. Defaults to 0, never exiting");
+    println!("    \"--config\" path : Loads the server configuration and the mice to create at startup from a TOML (.toml) or JSON file, the other options override it");
+    println!("    \"--profiles-file\" path : Loads the settings profiles from a JSON file, and saves them there whenever they change. Without it profiles are lost on exit");
+    println!("    \"--instance\" name : Runs a separate server instance owning com.cowsociety.virtual_mouse.name, loading /etc/trackpad-evdev-converter/name.toml if it exists and --config isn't given");
+    println!("\"--session-server\" : Starts the session server, which disables the trackpads of the mice with xinput, with options:");
+    println!("    \"--no-x\" : Runs without X, only logging the mice. Used automatically with a warning when $DISPLAY isn't set");
+    println!("    \"--instance\" name : Follows the named server instance instead of the default server");
+    println!("\"--unlock-all\" : Enables every touchpad xinput has disabled, without needing a server, to recover from a crash");
+    println!("\"-n\", \"--new\" : Tells the server to create a new mouse with parameters: name path_to_evdev_event, and options:");
+    println!("    \"--keyboard\" : The virtual device also advertises keyboard keys");
+    println!("    \"--no-hi-res\" : The virtual device has no high resolution scroll axes, only notched scrolling");
+    println!("    \"--bus-type\" type : The bus type the virtual device reports, usb (default), bluetooth, virtual, i8042, i2c, or a number");
+    println!("    \"--mirrors\" count : Creates count extra virtual devices that every event is echoed to");
+    println!("    \"--lock-delay\" milliseconds : Waits this long before the session server disables the trackpad, a mouse stopped sooner never disables it. Defaults to 0");
+    println!("    \"--soft-start\" milliseconds : Ramps the pointer sensitivity up from a fifth to the configured one over this long after creation. Defaults to 0");
+    println!("    \"--queue-depth\" count : Processes at most count motion and scroll events per frame, defaults to 0 for no limit");
+    println!("    \"--queue-policy\" policy : What happens to the events beyond the queue depth: coalesce (default, all are summed), drop-oldest, or drop-newest");
+    println!("    \"--event-tap\" path : Copies every emitted frame to a named pipe at path, created if missing, for visualizers");
+    println!("    \"--profile\" name : Starts the mouse with the settings of a profile saved with --save-profile");
+    println!("    \"--scroll-key-map\" map : While the scroll modifier is held, ctrl holds Ctrl around the scroll (zoom) and page turns vertical notches into Page Up/Down. Defaults to off");
+    println!("\"--new-all\" : Tells the server to create a mouse for every device matching a glob, named prefix-0, prefix-1, ..., with parameters: name_prefix glob, and the same options as --new");
+    println!("\"-l\", \"--list\" : Queries the server and prints all currently active mice, (name input_event_id output_event_id uptime), with --errors also the creations that failed in the last 10 minutes, with --owners instead the client and user that created each mouse");
+    println!("\"--get\" : Queries the server and prints one mouse, (name input_event_id output_event_id uptime), with parameter: name");
+    println!("\"--resolve\" : Queries the server and prints the mouse reading an input device, (name output_event_id), with parameter: input_event_id");
+    println!("\"--get-mirrors\" : Queries the server and prints the output event ids of a mouse's mirror devices, with parameter: name");
+    println!("\"--throughput\" : Queries the server and prints how many frames and events a mouse emitted per second over the last 5 seconds, with parameter: name");
+    println!("\"--export-config\" : Prints the current mice and their settings as a config file for --server --config, with parameters: [--toml] [file], writing to file if given, as JSON unless --toml is given");
+    println!("\"--aborted\" : Queries the server and prints the recently aborted mice, (name aborted_ago error)");
     println!("\"-s\", \"--stop\" : Tells the server to stop a mouse with parameter: name");
+    println!("\"--stop-id\" : Tells the server to stop the mouse with an event id, with parameters: input or output, and the id");
+    println!("\"--cancel\" : Tells the server to cancel creating a mouse, stopping it if it was created in the last 30 seconds, with parameter: name");
     println!("\"--shutdown\" : Tells the server to stop all mice and exit");
     println!("\"--reset\" : Tells the server to stop all mice and not exit");
-    println!("\"--server-pid\" : print the server pid");
+    println!("\"--save-profile\" : Stores settings on the server as a profile mice can be created with, with parameters: name settings_json, e.g. '{{\"x_scale\": 1.5}}'");
+    println!("\"--delete-profile\" : Deletes a profile, with parameter: name");
+    println!("\"--list-profiles\" : Queries the server and prints the names of the profiles");
+    println!("\"--pause-all\" : Tells the server to pause all mice, so the trackpads work on their own again, until --resume-all");
+    println!("\"--resume-all\" : Tells the server to resume all mice paused by --pause-all");
+    println!("\"--server-pid\" : print the server pid, with --info also whether systemd runs it, its uptime, and the number of active mice");
+    println!("\"--system-info\" : Queries the server and prints the kernel release and the uinput and evdev versions, for bug reports");
+    println!("\"--device-info\" : Queries the server and prints the libinput and evdev details of a device, for bug reports, with parameter: path_to_evdev_event");
+    println!("\"--probe-input\" : Has the server count the events a device sends, to tell whether it produces input at all, with parameter: path_to_evdev_event, and option:");
+    println!("    \"--duration\" seconds : How long to watch the device, at most 60. Defaults to 5");
+    println!("\"--nudge\" : Moves a virtual mouse once, bypassing its trackpad, to check that it moves the cursor at all, with parameters: name dx dy");
+    println!("\"--rebind\" : Points a virtual mouse at a new trackpad, keeping its virtual device so whatever uses it survives a trackpad swap, with parameters: name path");
+    println!("\"--server-config\" : print the server wide configuration of the running server");
+    println!("\"--wait-for\" : Waits until a mouse exists, with parameters: name [--timeout seconds], the timeout defaults to 30 seconds");
+    println!("\"--shift-hscroll\" : Sets whether a mouse turns vertical scroll into horizontal scroll while the scroll modifier is held, with parameters: name on|off");
+    println!("\"--scroll-modifier\" : Sets whether the scroll modifier of a mouse is held, with parameters: name on|off");
+    println!("\"--scroll-accel\" : Sets whether fast scrolling of a mouse scrolls farther than slow scrolling, with parameters: name on|off");
+    println!("\"--idle-reset\" : Sets after how many idle milliseconds a mouse discards leftover sub pixel motion, 0 to disable, with parameters: name milliseconds");
+    println!("\"--fixed-point\" : Sets whether a mouse accumulates motion as fixed point integers instead of floats, with parameters: name on|off");
+    println!("\"--three-finger-middle-click\" : Sets whether a quick three finger tap clicks the middle button of a mouse, with parameters: name on|off");
+    println!("\"--hi-res-scroll\" : Sets whether a mouse emits high resolution scroll events alongside the notched ones, with parameters: name on|off");
+    println!("\"--axis-scale\" : Sets the factors horizontal and vertical motion of a mouse are multiplied by, with parameters: name x_scale y_scale");
+    println!("\"--lock-toggle-button\" : Sets the button code that toggles the trackpad lock of a mouse instead of clicking, 0 to disable, with parameters: name button_code");
+    println!("\"--sync-timeout\" : Sets after how many milliseconds without a SYN_REPORT a mouse processes its input anyway, 0 to wait forever, with parameters: name milliseconds");
+    println!("\"--scroll-deadzone\" : Sets below how many notches the scroll value of a single event is ignored, per axis, with parameters: name notches");
+    println!("\"--three-finger-drag\" : Sets whether a three finger swipe of a mouse drags, holding the left button while moving the pointer, with parameters: name on|off");
+    println!("\"--double-tap\" : Sets whether two quick single finger taps of a mouse emit a double click, with parameters: name on|off interval_ms");
+    println!("\"--log-unknown-buttons\" : Sets whether the server prints the button codes of a mouse's trackpad that aren't turned into clicks, with parameters: name on|off");
+    println!("\"--max-scroll\" : Sets the most notches a mouse scrolls in a single frame, discarding the rest, with parameters: name notches (0 doesn't clamp)");
+    println!("\"--turbo-button\" : Sets the button code that multiplies the pointer motion of a mouse by factor while held instead of clicking, 0 to disable, with parameters: name button_code factor");
+    println!("\"--normalize-resolution\" : Sets whether the pointer motion of a mouse is scaled by its trackpad's resolution, so tuning values feel the same across trackpads, with parameters: name on|off");
+    println!("\"--get-accel-profile\" : Queries the server and prints the accel profile of a mouse, with parameter: name");
+    println!("\"--accel-profile\" : Sets the accel profile of a mouse, with parameters: name flat|adaptive|linear");
+    println!("\"--rules\" : Replaces the event rules of a mouse, applied in order to everything it emits, with parameters: name rules_json, e.g. '[{{\"rule\": \"swap_buttons\"}}]'");
+    println!("\"--discrete-scroll\" : Sets whether a mouse scrolls at most one notch per frame, queueing the rest, with parameters: name on|off");
+    println!("\"--scroll-rate-limit\" : Sets the shortest time between frames that scroll, with parameters: name milliseconds, 0 to not limit it");
+    println!("\"--flush-motion-on-click\" : Sets whether leftover sub pixel motion is emitted before a click, with parameters: name on/off");
+    println!("\"--ignore-buttons\" : Sets the button codes whose presses are dropped, with parameters: name codes, comma separated, or none");
+    println!("\"--clone-settings\" : Copies all settings of one mouse onto another, with parameters: from_name to_name");
+    println!("\"--scroll-axes\" : Sets which scroll axes are enabled, with parameters: name vertical_on/off horizontal_on/off");
+    println!("\"--max-speed\" : Caps the pointer motion per frame, carrying the rest over, with parameters: name pixels, 0 to not cap it");
+    println!("\"--scroll-as-motion\" : Sets whether two finger scrolling moves the pointer instead of scrolling, with parameters: name on/off");
+    println!("\"--click-stabilization\" : Drops pointer motion for a while after each button press, so clicking doesn't move the pointer, with parameters: name milliseconds, 0 to turn it off");
+    println!("\"--hi-res-quantum\" : Sets how many high resolution scroll units make one notch, for compositors that don't use 120, with parameters: name units");
+    println!("\"--channels\" : Sets whether a virtual mouse moves, clicks, and scrolls, e.g. a kiosk mouse that clicks but doesn't move, with parameters: name on/off on/off on/off");
+    println!("\"--passthrough-button\" : Sets the button code that unlocks the trackpad of a mouse while held instead of clicking, 0 to disable, with parameters: name button_code");
+    println!("\"--clamp-first-motion\" : Sets whether the first motion after the trackpad was idle is clamped, so the cursor doesn't jump when returning to it, with parameters: name on/off idle_ms");
+    println!("\"--socket\" : Sends the command to a server listening on a unix socket, with parameters: path command [parameters]");
+    println!("\"--dbus-address\" : Sends the command over the bus at a DBus address instead of the system bus, e.g. tcp:host=example,port=4000, with parameters: address command [parameters]");
+    println!("\"--instance\" : Sends the command to a named server instance instead of the default server, with parameters: name command [parameters], can be followed by --dbus-address");
     println!("The program may require sudo privaliges in order to work.");
     return Ok(());
 }
@@ -29,6 +114,383 @@ pub fn malformed() -> Result<(), Box<dyn std::error::Error>>{
     return Err(Box::new(AppError::MalformedCommand))
 }
 
+/// Parses a boolean command line parameter
+pub fn parse_bool(arg: &str) -> Option<bool>{
+    match arg {
+        "on" | "true" | "1" => Some(true),
+        "off" | "false" | "0" => Some(false),
+        _ => None
+    }
+}
+
+/// Parses a bus type, either by name or as a number (decimal or 0x hexadecimal)
+pub fn parse_bus_type(arg: &str) -> Option<u16>{
+    match arg {
+        "usb" => Some(0x03),
+        "bluetooth" => Some(0x05),
+        "virtual" => Some(0x06),
+        "i8042" => Some(0x11),
+        "i2c" => Some(0x18),
+        _ => match arg.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None => arg.parse::<u16>().ok()
+        }
+    }
+}
+
+/// Parses the options given after --server, on top of the config (from the config file, or the default one)
+pub fn parse_server_args<'a>(mut config: ServerConfig, mut arguments: impl Iterator<Item = &'a String>) -> Option<ServerConfig>{
+    let mut allowed_uids = vec![];
+    while let Some(option) = arguments.next() {
+        match option.as_str() {
+            "--config" => {arguments.next()?;}
+            "--allow-uid" => {allowed_uids.push(arguments.next()?.parse::<u32>().ok()?);}
+            "--acquire-retries" => {config.acquire_retries = arguments.next()?.parse::<u32>().ok()?;}
+            "--socket" => {config.socket = Some(PathBuf::from(arguments.next()?));}
+            "--idle-timeout" => {config.idle_timeout_secs = arguments.next()?.parse::<u64>().ok()?;}
+            "--profiles-file" => {config.profiles_file = Some(PathBuf::from(arguments.next()?));}
+            "--instance" => {config.instance = Some(arguments.next().filter(|instance| config::valid_instance_name(instance))?.clone());}
+            _ => {return None;}
+        }
+    }
+    if !allowed_uids.is_empty() {config.allowed_uids = allowed_uids;}
+    Some(config)
+}
+
+/// Parses the creation options given after --new name path
+pub fn parse_creation_args<'a>(mut arguments: impl Iterator<Item = &'a String>) -> Option<MouseCreationOptions>{
+    let mut options = MouseCreationOptions::default();
+    while let Some(option) = arguments.next() {
+        match option.as_str() {
+            "--keyboard" => {options.with_keyboard = true;}
+            "--no-hi-res" => {options.hi_res_scroll = false;}
+            "--bus-type" => {options.bus_type = Some(parse_bus_type(arguments.next()?)?);}
+            "--mirrors" => {options.mirrors = arguments.next()?.parse::<u32>().ok()?;}
+            "--queue-depth" => {options.queue_depth = arguments.next()?.parse::<u32>().ok()?;}
+            "--queue-policy" => {options.queue_policy = QueuePolicy::from_name(arguments.next()?)?;}
+            "--event-tap" => {options.event_tap = Some(arguments.next()?.clone());}
+            "--profile" => {options.profile = Some(arguments.next()?.clone());}
+            "--scroll-key-map" => {options.scroll_key_map = ScrollKeyMap::from_name(arguments.next()?)?;}
+            "--lock-delay" => {options.lock_delay_ms = arguments.next()?.parse::<u64>().ok()?;}
+            "--soft-start" => {options.soft_start_ms = arguments.next()?.parse::<u64>().ok()?;}
+            _ => {return None;}
+        }
+    }
+    Some(options)
+}
+
+/// Parses the arguments of a client command, none if they are malformed
+pub fn parse_client_command(arguments: &[String]) -> Option<ClientCommand>{
+    if arguments.is_empty() {return None;}
+    let function: ClientCommand = match arguments[0].as_str() {
+        "-n" | "--new" => {
+            if arguments.len() < 3 {return None;}
+            let options = parse_creation_args(arguments.iter().skip(3))?;
+            ClientCommand::New(arguments[1].clone(), arguments[2].clone(), options)
+        }
+        "--new-all" => {
+            if arguments.len() < 3 {return None;}
+            let options = parse_creation_args(arguments.iter().skip(3))?;
+            ClientCommand::NewAll(arguments[1].clone(), arguments[2].clone(), options)
+        }
+        "-l" | "--list" => {
+            match arguments.get(1).map(|arg| arg.as_str()) {
+                None => ClientCommand::List,
+                Some("--errors") if arguments.len() == 2 => ClientCommand::ListWithErrors,
+                Some("--owners") if arguments.len() == 2 => ClientCommand::ListOwners,
+                _ => {return None;}
+            }
+        }
+        "--export-config" => {
+            let toml = arguments.get(1).is_some_and(|arg| arg == "--toml");
+            let rest = &arguments[if toml {2} else {1}..];
+            if rest.len() > 1 {return None;}
+            ClientCommand::ExportConfig(toml, rest.first().cloned())
+        }
+        "--aborted" => {
+            if arguments.len() != 1 {return None;}
+            ClientCommand::Aborted
+        }
+        "--get" => {
+            if arguments.len() != 2 {return None;}
+            ClientCommand::Get(arguments[1].clone())
+        }
+        "--resolve" => {
+            if arguments.len() != 2 {return None;}
+            ClientCommand::Resolve(arguments[1].parse().ok()?)
+        }
+        "--get-mirrors" => {
+            if arguments.len() != 2 {return None;}
+            ClientCommand::GetMirrors(arguments[1].clone())
+        }
+        "--throughput" => {
+            if arguments.len() != 2 {return None;}
+            ClientCommand::GetThroughput(arguments[1].clone())
+        }
+        "-s" | "--stop" => {
+            if arguments.len() != 2 {return None;}
+            ClientCommand::Stop(arguments[1].clone())
+        }
+        "--stop-id" => {
+            if arguments.len() != 3 {return None;}
+            ClientCommand::StopById(EventIdKind::from_name(&arguments[1])?, arguments[2].parse().ok()?)
+        }
+        "--cancel" => {
+            if arguments.len() != 2 {return None;}
+            ClientCommand::CancelCreate(arguments[1].clone())
+        }
+        "--shutdown" => {
+            if arguments.len() != 1 {return None;}
+            ClientCommand::Shutdown
+        }
+        "--reset" => {
+            if arguments.len() != 1 {return None;}
+            ClientCommand::Reset
+        }
+        "--save-profile" => {
+            if arguments.len() != 3 {return None;}
+            ClientCommand::SaveProfile(arguments[1].clone(), arguments[2].clone())
+        }
+        "--delete-profile" => {
+            if arguments.len() != 2 {return None;}
+            ClientCommand::DeleteProfile(arguments[1].clone())
+        }
+        "--list-profiles" => {
+            if arguments.len() != 1 {return None;}
+            ClientCommand::ListProfiles
+        }
+        "--pause-all" => {
+            if arguments.len() != 1 {return None;}
+            ClientCommand::PauseAll
+        }
+        "--resume-all" => {
+            if arguments.len() != 1 {return None;}
+            ClientCommand::ResumeAll
+        }
+        "--server-pid" => {
+            match arguments.get(1).map(|arg| arg.as_str()) {
+                None => ClientCommand::PID,
+                Some("--info") if arguments.len() == 2 => ClientCommand::ServerInfo,
+                _ => {return None;}
+            }
+        }
+        "--device-info" => {
+            if arguments.len() != 2 {return None;}
+            ClientCommand::DeviceInfo(arguments[1].clone())
+        }
+        "--probe-input" => {
+            match arguments.len() {
+                2 => ClientCommand::ProbeInput(arguments[1].clone(), 5),
+                4 if arguments[2] == "--duration" => {
+                    let duration = arguments[3].parse::<u32>().ok().filter(|duration| *duration as u64 <= MAX_PROBE_DURATION.as_secs())?;
+                    ClientCommand::ProbeInput(arguments[1].clone(), duration)
+                }
+                _ => {return None;}
+            }
+        }
+        "--nudge" => {
+            if arguments.len() != 4 {return None;}
+            ClientCommand::Nudge(arguments[1].clone(), arguments[2].parse().ok()?, arguments[3].parse().ok()?)
+        }
+        "--rebind" => {
+            if arguments.len() != 3 {return None;}
+            ClientCommand::Rebind(arguments[1].clone(), arguments[2].clone())
+        }
+        "--server-config" => {
+            if arguments.len() != 1 {return None;}
+            ClientCommand::ServerConfig
+        }
+        "--system-info" => {
+            if arguments.len() != 1 {return None;}
+            ClientCommand::SystemInfo
+        }
+        "--wait-for" => {
+            match arguments.len() {
+                2 => ClientCommand::WaitFor(arguments[1].clone(), 30),
+                4 if arguments[2] == "--timeout" => {
+                    let timeout = arguments[3].parse::<u32>().ok()?;
+                    ClientCommand::WaitFor(arguments[1].clone(), timeout)
+                }
+                _ => {return None;}
+            }
+        }
+        "--shift-hscroll" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetShiftToHScroll(arguments[1].clone(), enabled)
+        }
+        "--scroll-modifier" => {
+            if arguments.len() != 3 {return None;}
+            let held = parse_bool(&arguments[2])?;
+            ClientCommand::SetScrollModifier(arguments[1].clone(), held)
+        }
+        "--scroll-accel" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetScrollAccel(arguments[1].clone(), enabled)
+        }
+        "--idle-reset" => {
+            if arguments.len() != 3 {return None;}
+            let ms = arguments[2].parse::<u64>().ok()?;
+            ClientCommand::SetIdleReset(arguments[1].clone(), ms)
+        }
+        "--fixed-point" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetFixedPoint(arguments[1].clone(), enabled)
+        }
+        "--three-finger-middle-click" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetThreeFingerMiddleClick(arguments[1].clone(), enabled)
+        }
+        "--hi-res-scroll" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetHiResScroll(arguments[1].clone(), enabled)
+        }
+        "--axis-scale" => {
+            if arguments.len() != 4 {return None;}
+            let x = arguments[2].parse::<f64>().ok()?;
+            let y = arguments[3].parse::<f64>().ok()?;
+            ClientCommand::SetAxisScale(arguments[1].clone(), x, y)
+        }
+        "--lock-toggle-button" => {
+            if arguments.len() != 3 {return None;}
+            let button = arguments[2].parse::<u32>().ok()?;
+            ClientCommand::SetLockToggleButton(arguments[1].clone(), button)
+        }
+        "--sync-timeout" => {
+            if arguments.len() != 3 {return None;}
+            let ms = arguments[2].parse::<u64>().ok()?;
+            ClientCommand::SetSyncTimeout(arguments[1].clone(), ms)
+        }
+        "--scroll-deadzone" => {
+            if arguments.len() != 3 {return None;}
+            let deadzone = arguments[2].parse::<f64>().ok()?;
+            ClientCommand::SetScrollDeadzone(arguments[1].clone(), deadzone)
+        }
+        "--three-finger-drag" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetThreeFingerDrag(arguments[1].clone(), enabled)
+        }
+        "--double-tap" => {
+            if arguments.len() != 4 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            let interval_ms = arguments[3].parse::<u64>().ok()?;
+            ClientCommand::SetDoubleTap(arguments[1].clone(), enabled, interval_ms)
+        }
+        "--log-unknown-buttons" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetUnknownButtonLogging(arguments[1].clone(), enabled)
+        }
+        "--max-scroll" => {
+            if arguments.len() != 3 {return None;}
+            let notches = arguments[2].parse::<u32>().ok()?;
+            ClientCommand::SetMaxScrollPerFrame(arguments[1].clone(), notches)
+        }
+        "--turbo-button" => {
+            if arguments.len() != 4 {return None;}
+            let button = arguments[2].parse::<u32>().ok()?;
+            let factor = arguments[3].parse::<f64>().ok()?;
+            ClientCommand::SetTurboButton(arguments[1].clone(), button, factor)
+        }
+        "--normalize-resolution" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetNormalizeByResolution(arguments[1].clone(), enabled)
+        }
+        "--get-accel-profile" => {
+            if arguments.len() != 2 {return None;}
+            ClientCommand::GetAccelProfile(arguments[1].clone())
+        }
+        "--accel-profile" => {
+            if arguments.len() != 3 {return None;}
+            // Unknown names are rejected by the server too, checking here gives the usage message instead of a DBus error
+            AccelProfile::from_name(&arguments[2])?;
+            ClientCommand::SetAccelProfile(arguments[1].clone(), arguments[2].clone())
+        }
+        "--rules" => {
+            if arguments.len() != 3 {return None;}
+            if serde_json::from_str::<Vec<EventRule>>(&arguments[2]).is_err() {return None;}
+            ClientCommand::SetRules(arguments[1].clone(), arguments[2].clone())
+        }
+        "--discrete-scroll" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetDiscreteScroll(arguments[1].clone(), enabled)
+        }
+        "--scroll-rate-limit" => {
+            if arguments.len() != 3 {return None;}
+            let milliseconds = arguments[2].parse::<u64>().ok()?;
+            ClientCommand::SetScrollRateLimit(arguments[1].clone(), milliseconds)
+        }
+        "--flush-motion-on-click" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetFlushMotionOnClick(arguments[1].clone(), enabled)
+        }
+        "--ignore-buttons" => {
+            if arguments.len() != 3 {return None;}
+            let codes = if arguments[2] == "none" {vec![]} else {arguments[2].split(',').map(|code| code.trim().parse::<u32>().ok()).collect::<Option<Vec<u32>>>()?};
+            ClientCommand::SetIgnoredButtons(arguments[1].clone(), codes)
+        }
+        "--clone-settings" => {
+            if arguments.len() != 3 {return None;}
+            ClientCommand::CloneSettings(arguments[1].clone(), arguments[2].clone())
+        }
+        "--scroll-axes" => {
+            if arguments.len() != 4 {return None;}
+            let vertical = parse_bool(&arguments[2])?;
+            let horizontal = parse_bool(&arguments[3])?;
+            ClientCommand::SetScrollAxes(arguments[1].clone(), vertical, horizontal)
+        }
+        "--max-speed" => {
+            if arguments.len() != 3 {return None;}
+            let pixels = arguments[2].parse::<u32>().ok()?;
+            ClientCommand::SetMaxSpeed(arguments[1].clone(), pixels)
+        }
+        "--scroll-as-motion" => {
+            if arguments.len() != 3 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            ClientCommand::SetScrollAsMotion(arguments[1].clone(), enabled)
+        }
+        "--click-stabilization" => {
+            if arguments.len() != 3 {return None;}
+            let milliseconds = arguments[2].parse::<u64>().ok()?;
+            ClientCommand::SetClickStabilization(arguments[1].clone(), milliseconds)
+        }
+        "--hi-res-quantum" => {
+            if arguments.len() != 3 {return None;}
+            let quantum = arguments[2].parse::<u32>().ok()?;
+            ClientCommand::SetHiResQuantum(arguments[1].clone(), quantum)
+        }
+        "--channels" => {
+            if arguments.len() != 5 {return None;}
+            let motion = parse_bool(&arguments[2])?;
+            let buttons = parse_bool(&arguments[3])?;
+            let scroll = parse_bool(&arguments[4])?;
+            ClientCommand::SetChannels(arguments[1].clone(), motion, buttons, scroll)
+        }
+        "--passthrough-button" => {
+            if arguments.len() != 3 {return None;}
+            let button = arguments[2].parse::<u32>().ok()?;
+            ClientCommand::SetPassthroughButton(arguments[1].clone(), button)
+        }
+        "--clamp-first-motion" => {
+            if arguments.len() != 4 {return None;}
+            let enabled = parse_bool(&arguments[2])?;
+            let idle_ms = arguments[3].parse::<u64>().ok()?;
+            ClientCommand::SetClampFirstMotion(arguments[1].clone(), enabled, idle_ms)
+        }
+        _ => {return None;}
+    };
+    Some(function)
+
+}
+
 /// Enum representing app errors
 #[derive(Debug, Clone)]
 pub enum AppError{
@@ -49,6 +511,7 @@ impl Error for AppError{}
     Requries root user or input group to access event files
 
     Session Server: secondary server that only runs if there is a display session. automatically disables trackpads using xinput.
+    Without a display (or with --no-x) it runs without xinput and only logs the mice
     Does not require root user
 
     Client: used to interact with the session and system server
@@ -60,45 +523,74 @@ pub async fn app_logic() -> Result<(), Box<dyn std::error::Error>> {
 
     //server
     if arguments.len() == 0 || arguments[0] == "--server" {
-        return server::server().await;
+        let server_arguments = arguments.get(1..).unwrap_or(&[]);
+        // The config file is loaded first wherever it is given, so the other options override it
+        let file_config = match server_arguments.iter().position(|arg| arg == "--config") {
+            Some(index) => {
+                let path = if let Some(path) = server_arguments.get(index + 1) {path} else {return malformed();};
+                config::load_from_path(Path::new(path))?
+            }
+            // A named instance has its own config file, which it doesn't need to have
+            None => {
+                let instance = server_arguments.iter().position(|arg| arg == "--instance").and_then(|index| server_arguments.get(index + 1));
+                match instance.filter(|instance| config::valid_instance_name(instance)).map(|instance| config::instance_config_path(instance)) {
+                    Some(path) if path.exists() => config::load_from_path(&path)?,
+                    _ => ServerConfig::default()
+                }
+            }
+        };
+        let config = if let Some(config) = parse_server_args(file_config, server_arguments.iter()) {config} else {return malformed();};
+        return server::server(config).await;
     }
 
     //session server
     if arguments[0] == "--session-server" {
-        return session::session_server().await;
+        let (mut no_x, mut instance) = (false, None);
+        let mut options = arguments.iter().skip(1);
+        while let Some(option) = options.next() {
+            match option.as_str() {
+                "--no-x" => {no_x = true;}
+                "--instance" => match options.next().filter(|name| config::valid_instance_name(name)) {
+                    Some(name) => {instance = Some(name.as_str());}
+                    None => {return malformed();}
+                },
+                _ => {return malformed();}
+            }
+        }
+        return session::session_server(no_x, instance).await;
     }
 
-    let function: ClientCommand = match arguments[0].as_str() {
-        "-n" | "--new" => {
-            if arguments.len() != 3 {return malformed();}
-            ClientCommand::New(arguments[1].clone(), arguments[2].clone())
-        }
-        "-l" | "--list" => {
-            if arguments.len() != 1 {return malformed();}
-            ClientCommand::List
-        }
-        "-s" | "--stop" => {
-            if arguments.len() != 2 {return malformed();}
-            ClientCommand::Stop(arguments[1].clone())
-        }
-        "--shutdown" => {
-            if arguments.len() != 1 {return malformed();}
-            ClientCommand::Shutdown
-        }
-        "--reset" => {
-            if arguments.len() != 1 {return malformed();}
-            ClientCommand::Reset
-        }
-        "--server-pid" => {
-            if arguments.len() != 1 {return malformed();}
-            ClientCommand::PID
+    if arguments[0] == "--help" {return print_help();}
+
+    //recovery, works without a server
+    if arguments[0] == "--unlock-all" {
+        if arguments.len() != 1 {return malformed();}
+        return Ok(client::unlock_all()?);
+    }
+
+    //client over a unix socket
+    if arguments[0] == "--socket" {
+        if arguments.len() < 3 || parse_client_command(&arguments[2..]).is_none() {return malformed();}
+        return socket::socket_client(&arguments[1], &arguments[2..]).await;
+    }
+
+    //client of a named server instance
+    let (instance, arguments) = if arguments[0] == "--instance" {
+        match arguments.get(1).filter(|name| config::valid_instance_name(name)) {
+            Some(name) => (Some(name.clone()), &arguments[2..]),
+            None => {return malformed();}
         }
-        "--help" => {return print_help();}
-        _ => {return malformed();}
-    };
+    } else {(None, &arguments[..])};
+
+    //client over a dbus address other than the system bus
+    if arguments.first().is_some_and(|argument| argument == "--dbus-address") {
+        let function = if let Some(function) = arguments.get(2..).and_then(parse_client_command) {function} else {return malformed();};
+        return client::client(function, Some(&arguments[1]), instance.as_deref()).await;
+    }
 
     //client
-    return client::client(function).await;
+    let function = if let Some(function) = parse_client_command(arguments) {function} else {return malformed();};
+    return client::client(function, None, instance.as_deref()).await;
 }
 
 /// Main function. Run server, or client commands
@@ -1,17 +1,22 @@
-use std::{collections::HashMap, sync::{Arc, Mutex}, task::{Poll, Waker}};
-use futures::Future;
-use tokio::task::JoinHandle;
+use std::{any::Any, collections::HashMap, panic::AssertUnwindSafe, rc::Rc, sync::{Arc, Mutex}, task::{Poll, Waker}, time::Duration};
+use futures::{Future, FutureExt};
+use tokio::{sync::{mpsc::UnboundedSender, Notify}, task::{JoinHandle, LocalSet}, time::Instant};
 
-use crate::{communicator::{Communicator, CommunicatorDequeueFuture, CommunicatorShutdownFuture, CommunicatorWorkFuture}, mouse::{MouseCreationError, MouseDriver, MouseDriverUpdateError, MouseInfo}};
+use crate::{communicator::{Communicator, CommunicatorDequeueFuture, CommunicatorShutdownFuture, CommunicatorWorkFuture}, mouse::{MouseCreationError, MouseCreationStage, MouseDriverBuilder, MouseDriverUpdateError, MouseInfo, ProgressCallback, TrackpadSignal}};
 
 /// Struct holding mouse information used by mouse manager
 pub struct ManagedMouse{
     pub metadata: MouseInfo,
-    pub driver: Arc<tokio::sync::Mutex<MouseDriver>>,
+    /// Task running the driver's update loop, which owns the driver
     pub task: Option<JoinHandle<()>>,
-    pub abort: Arc<Mutex<AbortData>>
+    pub abort: Arc<Mutex<AbortData>>,
+    /// Asks the driver's update loop to release its buttons and return
+    pub stop: Arc<Notify>
 }
 
+/// How long stopped mice get to release their buttons and close their devices before their tasks are aborted
+const STOP_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
 /// Struct holding abort data for a managed mouse.
 pub struct AbortData{
     /// Whether or not this mouse needs to be aborted
@@ -40,61 +45,117 @@ impl Future for ManagerAbortFuture{
 pub struct MouseManager{
     /// Map from mouse name to mouse driver
     mice: HashMap<String, ManagedMouse>,
+    /// Local task set the non send mouse drivers are spawned on.
+    /// Owned by the manager so mice can be created from any context, the tasks run while update_loop is running
+    local: Rc<LocalSet>,
     communicator: Arc<Mutex<Communicator>>,
     /// bool for whether or not a mouse needs to be aborted
     abort: Arc<Mutex<bool>>,
     /// waker used to inform the system that the abort value changed
-    abort_waker: Arc<Mutex<Option<Waker>>>
+    abort_waker: Arc<Mutex<Option<Waker>>>,
+    /// Called with the mouse name whenever a stage of a mouse creation finishes
+    progress_callback: Option<ProgressCallback>,
+    /// How long the manager may go without any mice before shutting down, none to never shut down when idle
+    idle_timeout: Option<Duration>,
+    /// Trackpads the server has to signal, like the ones of aborted mice or of mice that were locked or unlocked.
+    /// None drops them, when there is no one to signal
+    trackpad_signals: Option<UnboundedSender<TrackpadSignal>>
 }
 impl MouseManager{
     /// Returns empty new mouse manager
     pub fn new(com: Arc<Mutex<Communicator>>) -> Self{
-        MouseManager { mice: HashMap::default(), communicator: com, abort: Arc::new(Mutex::new(false)), abort_waker: Arc::new(Mutex::new(None)) }
+        MouseManager { mice: HashMap::default(), local: Rc::new(LocalSet::new()), communicator: com, abort: Arc::new(Mutex::new(false)), abort_waker: Arc::new(Mutex::new(None)), progress_callback: None, idle_timeout: None, trackpad_signals: None }
     }
-    /// creates any queued mice
-    pub fn create_queued_mice(&mut self) {
-        let mut com = self.communicator.lock().unwrap();
-        let queued: Vec<(String, String)> = com.queued_mice.drain().collect();
-        for (name, path) in queued {
-            if self.mice.contains_key(&name) {
-                com.errors.insert(name.to_owned(), MouseCreationError::NameInUse);
-            }else{
-                match MouseDriver::new(name.clone(), path){
-                    Ok(mouse) => {
-                        let info = mouse.metadata.clone();
-                        let handle = Arc::new(tokio::sync::Mutex::new(mouse));
-                        let abort = Arc::new(Mutex::new(AbortData{abort: false, err: None}));
-                        let future_handle = handle.clone();
-                        let future_abort = abort.clone();
-                        let future_uni_abort = self.abort.clone();
-                        let future_uni_abort_waker = self.abort_waker.clone();
-                        let task = tokio::task::spawn_local(async move {
-                            let mut mouse = future_handle.lock().await;
-                            let err = mouse.update_loop().await;
-                            drop(mouse);
-                            let mut abort = future_abort.lock().unwrap();
-                            abort.abort = true;
-                            abort.err = Some(err);
-                            let mut abort = future_uni_abort.lock().unwrap();
-                            *abort = true;
-                            let mut abort_waker = future_uni_abort_waker.lock().unwrap();
-                            if let Some(waker) = abort_waker.take(){
-                                waker.wake();
-                            }
-                        });
-                        self.mice.insert(name.clone(), ManagedMouse{metadata: info.clone(), driver: handle, task: Some(task), abort});
-                        com.current_mice.insert(name.clone(), info);
-                    },
-                    Err(err) => {
-                        com.errors.insert(name.clone(), err);
-                    }
+    /// Sets the function called with the mouse name whenever a stage of a mouse creation finishes
+    pub fn on_creation_progress(&mut self, callback: impl Fn(&str, MouseCreationStage) + 'static) {
+        self.progress_callback = Some(Box::new(callback));
+    }
+    /// Sets how long the manager may go without any mice before shutting down
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+    /// Sets where the trackpads the server has to signal are sent
+    pub fn set_trackpad_signals(&mut self, sender: UnboundedSender<TrackpadSignal>) {
+        self.trackpad_signals = Some(sender);
+    }
+    /// creates any queued mice. The communicator isn't locked while a mouse is built, which waits for its event nodes,
+    /// so a mouse stays queued until it is built, and one cancelled in the meantime is dropped
+    pub async fn create_queued_mice(&mut self) {
+        let queued: Vec<String> = self.communicator.lock().unwrap().queued_mice.keys().cloned().collect();
+        for name in queued {
+            let (path, options, profile, paused) = {
+                let com = self.communicator.lock().unwrap();
+                // Cancelled while an earlier mouse was built
+                let (path, options) = match com.queued_mice.get(&name) {
+                    Some(queued) => queued.clone(),
+                    None => {continue;}
                 };
-            }
-            if let Some(waker) = com.result_wakers.remove(&name) {waker.wake();}
+                // The profile is looked up now, so a profile changed while the mouse was queued still applies
+                let profile = options.profile.as_ref().map(|profile| com.profiles.get(profile).cloned().ok_or_else(|| profile.clone()));
+                (path, options, profile, com.paused.clone())
+            };
+            let result = if self.mice.contains_key(&name) {
+                Err(MouseCreationError::NameInUse)
+            }else if let Some(Err(profile)) = profile {
+                Err(MouseCreationError::UnknownProfile(profile))
+            }else{
+                let progress = |stage| if let Some(callback) = &self.progress_callback {callback(&name, stage);};
+                let mut builder = MouseDriverBuilder::new(name.clone(), path).options(options).paused(paused).progress(progress);
+                if let Some(Ok(settings)) = profile {builder = builder.settings(settings);}
+                if let Some(sender) = &self.trackpad_signals {builder = builder.trackpad_signals(sender.clone());}
+                builder.build().await
+            };
+            let mut com = self.communicator.lock().unwrap();
+            // Cancelling already recorded the error and woke the waiting futures, the built mouse is dropped with its virtual devices
+            if com.queued_mice.remove(&name).is_none() {continue;}
+            match result{
+                Ok(mut mouse) => {
+                    let info = mouse.metadata.clone();
+                    let stop = mouse.stop_handle();
+                    let (task, abort) = self.spawn_update_loop(async move {mouse.update_loop().await});
+                    self.mice.insert(name.clone(), ManagedMouse{metadata: info.clone(), task: Some(task), abort, stop});
+                    com.current_mice.insert(name.clone(), info);
+                },
+                Err(err) => {
+                    com.record_error(name.clone(), err);
+                }
+            };
+            com.result_wakers.remove(&name).into_iter().flatten().for_each(|waker| waker.wake());
+            com.presence_wakers.drain(..).for_each(|waker| waker.wake());
         }
     }
-    /// Aborts all mice that need to be
+    /// Spawns a mouse's update loop on the local set. Once the loop ends its error is stored in the returned abort data,
+    /// and the manager is woken to abort the mouse. A panic is caught and reported like an error, otherwise the mouse would never be aborted
+    fn spawn_update_loop(&self, update_loop: impl Future<Output = MouseDriverUpdateError> + 'static) -> (JoinHandle<()>, Arc<Mutex<AbortData>>) {
+        let abort = Arc::new(Mutex::new(AbortData{abort: false, err: None}));
+        let future_abort = abort.clone();
+        let future_uni_abort = self.abort.clone();
+        let future_uni_abort_waker = self.abort_waker.clone();
+        let task = self.local.spawn_local(async move {
+            // The driver is dropped while unwinding, closing its devices
+            let err = match AssertUnwindSafe(update_loop).catch_unwind().await {
+                Ok(err) => err,
+                Err(panic) => MouseDriverUpdateError::Panicked(panic_message(panic))
+            };
+            let mut abort = future_abort.lock().unwrap();
+            abort.abort = true;
+            abort.err = Some(err);
+            let mut abort = future_uni_abort.lock().unwrap();
+            *abort = true;
+            let mut abort_waker = future_uni_abort_waker.lock().unwrap();
+            if let Some(waker) = abort_waker.take(){
+                waker.wake();
+            }
+        });
+        (task, abort)
+    }
+    /// Aborts all mice that need to be. They are removed from the current mice, and their trackpads signalled to be enabled again,
+    /// like a stopped mouse's
     pub fn abort_mice(&mut self) {
+        // Every finished update loop raises the flag, including the ones of stopped mice, so it is cleared here or the abort future stays ready.
+        // The loops run on this thread, so none can finish between clearing it and checking the mice
+        *self.abort.lock().unwrap() = false;
+        let mut com = self.communicator.lock().unwrap();
         let mut aborted_mice: Vec<String> = vec![];
         for (name, mouse) in self.mice.iter_mut(){
             let mut abort = mouse.abort.lock().unwrap();
@@ -106,49 +167,78 @@ impl MouseManager{
             }
             if let Some(err) = error{
                 println!("Mouse {} Aborted with error: {:?}", *name, err);
+                com.record_abort(name.clone(), format!("{:?}", err));
+            }
+            // The stored info has the current trackpad, the manager's is stale after a rebind
+            if let Some(info) = com.current_mice.remove(name) {
+                if let Some(sender) = &self.trackpad_signals {let _ = sender.send(TrackpadSignal::Enable(info.input_id));}
             }
             aborted_mice.push(name.to_owned());
         }
         aborted_mice.into_iter().for_each(|name| {self.mice.remove(&name);});
     }
-    /// Aborts all mice
+    /// Stops all mice, like stop_mice does, and prints the errors of the ones that failed before
     pub async fn shutdown(&mut self) {
+        drain_mice(self.mice.values_mut()).await;
         for (name, mouse) in self.mice.iter_mut(){
-            let mut abort = mouse.abort.lock().unwrap();
-            let error = abort.err.take();
-            drop(abort);
-            if let Some(task) = mouse.task.take(){
-                task.abort();
-            }
-            if let Some(err) = error{
+            let error = mouse.abort.lock().unwrap().err.take();
+            if let Some(err) = error.filter(|err| !matches!(err, MouseDriverUpdateError::Stopped)){
                 println!("Mouse {} Aborted with error: {:?}", *name, err);
             }
         }
         self.mice.clear();
     }
-    /// Removes any dequeued mice from the system
+    /// Removes any dequeued mice from the system. Their update loops are asked to stop, so they release their buttons and return
+    /// between frames instead of being cancelled in the middle of reading the trackpad
     pub async fn stop_mice(&mut self) {
-        let mut com = self.communicator.lock().unwrap();
-        let queued: Vec<String> = com.dequeued_mice.drain().collect();
-        for name in queued {
-            com.current_mice.remove(&name);
-            let mut managed_mouse = if let Some(mouse) = self.mice.remove(&name) {mouse} else {continue;};
-            if let Some(task) = managed_mouse.task.take(){
-                task.abort();
-            }
-        }
+        let mut removed: Vec<ManagedMouse> = {
+            let mut com = self.communicator.lock().unwrap();
+            let queued: Vec<String> = com.dequeued_mice.drain().collect();
+            queued.into_iter().filter_map(|name| {
+                com.current_mice.remove(&name);
+                self.mice.remove(&name)
+            }).collect()
+        };
+        // The communicator isn't locked while waiting, so the servers keep answering
+        drain_mice(removed.iter_mut()).await;
     }
-    /// asynchronous update loop for the mouse manager
+    /// Prints the state of the manager and the communicator, without changing anything
+    pub fn dump_state(&self) {
+        let mut names: Vec<&String> = self.mice.keys().collect();
+        names.sort();
+        let running: Vec<String> = names.into_iter().map(|name| {
+            let task = match &self.mice[name].task {
+                Some(task) if !task.is_finished() => "running",
+                Some(_) => "finished",
+                None => "no task"
+            };
+            format!("{} ({})", name, task)
+        }).collect();
+        println!("===== State dump =====");
+        println!("Managed mice ({}): {}", running.len(), running.join(", "));
+        println!("Idle timeout: {:?}", self.idle_timeout);
+        println!("{}", self.communicator.lock().unwrap().state_dump());
+        println!("======================");
+    }
+    /// asynchronous update loop for the mouse manager. Drives the mouse driver tasks until the loop exits
     pub async fn update_loop(&mut self) {
+        let local = self.local.clone();
+        local.run_until(self.manage_mice()).await;
+    }
+    /// Handles communicator requests and signals until a shutdown is requested
+    async fn manage_mice(&mut self) {
         let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+        let mut sigusr1 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()).unwrap();
         loop{
             let queued_future = CommunicatorWorkFuture{com: self.communicator.clone()};
             let abort_future = ManagerAbortFuture{abort: self.abort.clone(), waker: self.abort_waker.clone()};      
             let shutdown_future = CommunicatorShutdownFuture{com: self.communicator.clone()};
             let dequeue_future = CommunicatorDequeueFuture{com: self.communicator.clone()};
+            // Recreated every iteration, so the timer restarts whenever something happens and stops while mice exist
+            let idle_future = idle_timer(self.idle_timeout, self.mice.is_empty());
             tokio::select! {
                 _ = queued_future => {
-                    self.create_queued_mice();
+                    self.create_queued_mice().await;
                 }
                 _ = abort_future => {
                     self.abort_mice();
@@ -160,6 +250,11 @@ impl MouseManager{
                 _ = dequeue_future => {
                     self.stop_mice().await;
                 }
+                _ = idle_future => {
+                    println!("No mice for {:?}, shutting down", self.idle_timeout.unwrap_or_default());
+                    self.shutdown().await;
+                    break;
+                }
                 _ = tokio::signal::ctrl_c() => {
                     self.shutdown().await;
                     break;
@@ -168,7 +263,189 @@ impl MouseManager{
                     self.shutdown().await;
                     break;
                 }
+                _ = sigusr1.recv() => {
+                    self.dump_state();
+                }
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Asks the mice to stop, giving them up to STOP_DRAIN_TIMEOUT together to finish their frame and release their buttons,
+/// then aborts the tasks still running
+async fn drain_mice<'a>(mice: impl Iterator<Item = &'a mut ManagedMouse>) {
+    let mice: Vec<&mut ManagedMouse> = mice.collect();
+    mice.iter().for_each(|mouse| mouse.stop.notify_one());
+    let deadline = Instant::now() + STOP_DRAIN_TIMEOUT;
+    for mouse in mice {
+        if let Some(task) = mouse.task.as_mut() {
+            if tokio::time::timeout_at(deadline, task).await.is_ok() {mouse.task = None;}
+        }
+        if let Some(task) = mouse.task.take(){
+            task.abort();
+        }
+    }
+}
+
+/// The message a panic was started with, panics without a string message get a placeholder
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().map(|message| message.to_string()).unwrap_or_else(|| "unknown panic".to_string())
+    }
+}
+
+/// Waits for the idle timeout while there are no mice, and forever otherwise
+async fn idle_timer(timeout: Option<Duration>, idle: bool) {
+    match timeout {
+        Some(timeout) if idle => tokio::time::sleep(timeout).await,
+        _ => std::future::pending().await
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds a mouse without a driver, whose update loop only waits to be stopped
+    fn add_mouse(manager: &mut MouseManager, name: &str, input_id: u32) {
+        let stop = Arc::new(Notify::new());
+        let stopped = stop.clone();
+        add_mouse_with_loop(manager, name, input_id, stop, async move {
+            stopped.notified().await;
+            MouseDriverUpdateError::Stopped
+        });
+    }
+
+    /// Adds a mouse without a driver, running update_loop in place of the driver's
+    fn add_mouse_with_loop(manager: &mut MouseManager, name: &str, input_id: u32, stop: Arc<Notify>, update_loop: impl Future<Output = MouseDriverUpdateError> + 'static) {
+        let info = MouseInfo::detached(name, input_id);
+        let (task, abort) = manager.spawn_update_loop(update_loop);
+        manager.communicator.lock().unwrap().current_mice.insert(name.to_string(), info.clone());
+        manager.mice.insert(name.to_string(), ManagedMouse{metadata: info, task: Some(task), abort, stop});
+    }
+
+    /// Whether the manager loop would handle an abort right away
+    fn abort_ready(manager: &MouseManager) -> bool {
+        ManagerAbortFuture{abort: manager.abort.clone(), waker: manager.abort_waker.clone()}.now_or_never().is_some()
+    }
+
+    #[tokio::test]
+    async fn abort_future_is_pending_again_after_stopping_a_mouse() {
+        let mut manager = MouseManager::new(Arc::new(Mutex::new(Communicator::default())));
+        let local = manager.local.clone();
+        local.run_until(async {
+            add_mouse(&mut manager, "trackpad", 3);
+            assert!(!abort_ready(&manager));
+            manager.communicator.lock().unwrap().stop_mouse("trackpad".to_string());
+            manager.stop_mice().await;
+            // The stopped loop raised the flag as it ended, handling it once has to clear it, or the manager loop would spin
+            assert!(abort_ready(&manager));
+            manager.abort_mice();
+            assert!(!abort_ready(&manager));
+        }).await;
+    }
+
+    /// A manager whose trackpad signals can be read back
+    fn manager_with_signals() -> (MouseManager, tokio::sync::mpsc::UnboundedReceiver<TrackpadSignal>) {
+        let mut manager = MouseManager::new(Arc::new(Mutex::new(Communicator::default())));
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        manager.set_trackpad_signals(sender);
+        (manager, receiver)
+    }
+
+    fn received(receiver: &mut tokio::sync::mpsc::UnboundedReceiver<TrackpadSignal>) -> Vec<TrackpadSignal> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    #[tokio::test]
+    async fn aborting_removes_failed_mice_and_enables_their_trackpads() {
+        let (mut manager, mut signals) = manager_with_signals();
+        let local = manager.local.clone();
+        local.run_until(async {
+            add_mouse(&mut manager, "running", 3);
+            add_mouse_with_loop(&mut manager, "failed", 4, Arc::new(Notify::new()), async {
+                MouseDriverUpdateError::EmitEventsError(std::io::Error::other("unplugged"))
+            });
+            add_mouse_with_loop(&mut manager, "panicked", 5, Arc::new(Notify::new()), async {
+                panic!("driver bug")
+            });
+            // Lets the failing loops run to their end
+            ManagerAbortFuture{abort: manager.abort.clone(), waker: manager.abort_waker.clone()}.await;
+            tokio::task::yield_now().await;
+            manager.abort_mice();
+            let mut aborted: Vec<String> = manager.communicator.lock().unwrap().list_aborted().into_iter().map(|(name, _, _)| name).collect();
+            aborted.sort();
+            assert_eq!(aborted, ["failed", "panicked"]);
+            assert_eq!(manager.mice.keys().collect::<Vec<_>>(), ["running"]);
+            assert_eq!(manager.communicator.lock().unwrap().current_mice.keys().collect::<Vec<_>>(), ["running"]);
+            let enabled = received(&mut signals);
+            assert_eq!(enabled.len(), 2);
+            assert!(enabled.contains(&TrackpadSignal::Enable(4)) && enabled.contains(&TrackpadSignal::Enable(5)));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn aborting_signals_the_trackpad_a_mouse_was_rebound_to() {
+        let (mut manager, mut signals) = manager_with_signals();
+        let local = manager.local.clone();
+        local.run_until(async {
+            add_mouse_with_loop(&mut manager, "trackpad", 3, Arc::new(Notify::new()), async {
+                MouseDriverUpdateError::EmitEventsError(std::io::Error::other("unplugged"))
+            });
+            manager.communicator.lock().unwrap().current_mice.get_mut("trackpad").unwrap().input_id = 7;
+            ManagerAbortFuture{abort: manager.abort.clone(), waker: manager.abort_waker.clone()}.await;
+            manager.abort_mice();
+            assert_eq!(received(&mut signals), [TrackpadSignal::Enable(7)]);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn stopping_removes_only_the_stopped_mice() {
+        let (mut manager, mut signals) = manager_with_signals();
+        let local = manager.local.clone();
+        local.run_until(async {
+            add_mouse(&mut manager, "stopped", 3);
+            add_mouse(&mut manager, "kept", 4);
+            let input_id = manager.communicator.lock().unwrap().stop_mouse("stopped".to_string());
+            // The StopMouse caller signals the returned trackpad itself
+            assert_eq!(input_id, Some(3));
+            manager.stop_mice().await;
+            manager.abort_mice();
+            assert_eq!(manager.mice.keys().collect::<Vec<_>>(), ["kept"]);
+            assert_eq!(manager.communicator.lock().unwrap().current_mice.keys().collect::<Vec<_>>(), ["kept"]);
+            // A stop isn't an abort, so it isn't recorded or signalled again
+            assert!(manager.communicator.lock().unwrap().list_aborted().is_empty());
+            assert!(received(&mut signals).is_empty());
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_every_mouse() {
+        let (mut manager, mut signals) = manager_with_signals();
+        let local = manager.local.clone();
+        local.run_until(async {
+            add_mouse(&mut manager, "trackpad", 3);
+            add_mouse(&mut manager, "other", 4);
+            // The shutdown caller signals the returned trackpads itself
+            let mut input_ids = manager.communicator.lock().unwrap().request_shutdown();
+            input_ids.sort();
+            assert_eq!(input_ids, [3, 4]);
+            manager.shutdown().await;
+            assert!(manager.mice.is_empty());
+            assert!(received(&mut signals).is_empty());
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn stopping_aborts_loops_that_dont_return() {
+        let (mut manager, _signals) = manager_with_signals();
+        let local = manager.local.clone();
+        local.run_until(async {
+            add_mouse_with_loop(&mut manager, "stuck", 3, Arc::new(Notify::new()), std::future::pending());
+            manager.communicator.lock().unwrap().stop_mouse("stuck".to_string());
+            manager.stop_mice().await;
+            assert!(manager.mice.is_empty());
+            assert!(manager.communicator.lock().unwrap().current_mice.is_empty());
+        }).await;
+    }
+}
@@ -1,7 +1,66 @@
-use std::{fs::{File, OpenOptions}, os::{fd::OwnedFd, unix::fs::OpenOptionsExt}, path::{Path, PathBuf}};
-use evdev::{uinput::{VirtualDevice, VirtualDeviceBuilder}, AttributeSet, Device, EventStream, EventType, InputEvent, InputEventKind, Key, RelativeAxisType, Synchronization};
-use input::{event::{pointer::{ButtonState, PointerScrollEvent}, PointerEvent}, Event, Libinput, LibinputInterface};
+use std::{collections::{HashMap, HashSet, VecDeque}, fs::{File, OpenOptions}, io::Write, os::{fd::{AsRawFd, OwnedFd}, unix::fs::OpenOptionsExt}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use evdev::{uinput::{VirtualDevice, VirtualDeviceBuilder}, AbsoluteAxisType, AttributeSet, BusType, InputId, Device, EventStream, EventType, InputEvent, InputEventKind, Key, RelativeAxisType, Synchronization};
+use input::{DeviceCapability, event::{gesture::{GestureEndEvent, GestureEventCoordinates, GestureEventTrait, GestureHoldEvent, GestureSwipeEvent}, pointer::{ButtonState, PointerEventTrait, PointerScrollEvent}, GestureEvent, PointerEvent}, Event, Libinput, LibinputInterface};
 use libc::{O_RDONLY, O_RDWR, O_WRONLY};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+/// How much the scroll speed (in notches per second) adds to the scroll acceleration factor
+const SCROLL_ACCEL_GAIN: f64 = 0.02;
+/// Upper limit for the scroll acceleration factor
+const SCROLL_ACCEL_MAX: f64 = 4.0;
+/// Time between the notches discrete scrolling emits from its queue when no input arrives
+const DISCRETE_SCROLL_INTERVAL: Duration = Duration::from_millis(15);
+/// Time between the frames that emit motion carried over by the speed cap when no input arrives, about a trackpad's frame rate
+const CARRIED_MOTION_INTERVAL: Duration = Duration::from_millis(8);
+/// Gain of the linear accel profile, the factor grows by this for every unit per millisecond of pointer speed
+const LINEAR_ACCEL_GAIN: f64 = 0.1;
+/// Largest factor the linear accel profile multiplies motion by
+const LINEAR_ACCEL_MAX: f64 = 5.0;
+/// Longest the first relative motion after idling can be, in libinput's units before scaling, when it is clamped
+const FIRST_MOTION_MAX: f64 = 4.0;
+
+/// Relative motion units per millimeter of absolute motion. libinput normalizes relative motion to a 1000dpi device
+const ABSOLUTE_UNITS_PER_MM: f64 = 1000.0/25.4;
+/// Resolution, in device units per millimeter, motion is normalized to when normalize_by_resolution is on.
+/// A common trackpad resolution, so tuning values keep roughly their meaning
+const REFERENCE_RESOLUTION: f64 = 12.0;
+/// Gap between absolute motion events, in microseconds, after which the next event is treated as a new contact
+const ABSOLUTE_CONTACT_GAP_USEC: u64 = 100_000;
+
+/// How many consecutive frames that can't move the pointer are skipped before libinput is dispatched anyway.
+/// Skipped events stay queued in libinput's kernel buffer, so this keeps the buffer from overflowing while a finger rests
+const IDLE_FRAME_BATCH: u32 = 8;
+
+/// How many times a frame is retried after a transient emit error (EAGAIN, EINTR), with backoff from 1ms, before the mouse is stopped
+const EMIT_RETRIES: u32 = 3;
+
+/// Button codes the mouse turns into clicks: BTN_LEFT, BTN_RIGHT, and BTN_MIDDLE
+const KNOWN_BUTTONS: [u32; 3] = [272, 273, 274];
+/// Shortest time between two logs of the same unknown button code, in microseconds
+const UNKNOWN_BUTTON_LOG_INTERVAL_USEC: u64 = 1_000_000;
+
+/// How many times the virtual device's syspath is read again when it has no event node yet
+const OUTPUT_ID_RETRIES: u32 = 20;
+/// Time between reads of the virtual device's syspath while waiting for its event node
+const OUTPUT_ID_RETRY_INTERVAL: Duration = Duration::from_millis(25);
+
+/// How many times dispatching libinput is retried after being interrupted by a signal (EINTR) before the mouse is stopped
+const DISPATCH_RETRIES: u32 = 3;
+
+/// Longest a hold can last, in microseconds, and still count as a tap
+const TAP_MAX_USEC: u64 = 300_000;
+
+/// Number of fixed point units per whole unit when fixed point accumulation is enabled
+const FIXED_POINT_SCALE: i64 = 256;
+
+/// Fraction of the configured sensitivity a mouse with a soft start begins with, ramping up to all of it
+const SOFT_START_FLOOR: f64 = 0.2;
+
+/// How often an event tap without a reader tries to open its pipe again
+const EVENT_TAP_REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+/// How far back the emit throughput of a mouse is measured
+pub const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
 
 /// Interface used by Libinput.
 pub struct Interface;
@@ -20,6 +79,135 @@ impl LibinputInterface for Interface {
     }
 }
 
+/// Copies every frame a mouse emits to a named pipe, one line per frame of space separated type:code:value triples, for visualizers.
+/// The pipe is written without blocking: frames are dropped while no reader has it open or the reader falls behind
+struct EventTap{
+    path: PathBuf,
+    /// Write end of the pipe, none while no reader has it open
+    pipe: Option<File>,
+    /// When opening the pipe was last tried
+    last_open: Option<Instant>
+}
+impl EventTap{
+    /// Creates the named pipe at path if nothing exists there yet. The pipe itself is opened once a reader shows up
+    fn create(path: &str) -> Result<Self, std::io::Error> {
+        let path = PathBuf::from(path);
+        if !path.exists() {
+            let c_path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()).map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+            if unsafe {libc::mkfifo(c_path.as_ptr(), 0o644)} != 0 {return Err(std::io::Error::last_os_error());}
+        }
+        Ok(EventTap{path, pipe: None, last_open: None})
+    }
+    /// Writes a frame to the pipe, dropping it if that would block. Opening a pipe fails while it has no reader, so that is retried at most every EVENT_TAP_REOPEN_INTERVAL
+    fn write(&mut self, events: &[InputEvent]) {
+        if self.pipe.is_none() {
+            if self.last_open.is_some_and(|last| last.elapsed() < EVENT_TAP_REOPEN_INTERVAL) {return;}
+            self.last_open = Some(Instant::now());
+            self.pipe = OpenOptions::new().append(true).custom_flags(libc::O_NONBLOCK).open(&self.path).ok();
+        }
+        let pipe = if let Some(pipe) = self.pipe.as_mut() {pipe} else {return;};
+        let mut line = events.iter().map(|event| format!("{}:{}:{}", event.event_type().0, event.code(), event.value())).collect::<Vec<String>>().join(" ");
+        line.push('\n');
+        // Writes up to PIPE_BUF bytes are all or nothing, so a full pipe drops the whole line. Any other error means the reader left
+        match pipe.write(line.as_bytes()) {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(_) => {self.pipe = None;}
+        }
+    }
+}
+
+/// Rolling count of the frames a mouse emitted over the last THROUGHPUT_WINDOW, shared with the servers through the mouse's info
+#[derive(Debug, Default)]
+pub struct Throughput{
+    /// When each recent frame was emitted and how many events it had, oldest first
+    frames: VecDeque<(Instant, usize)>
+}
+impl Throughput{
+    /// Counts a frame of events emitted now
+    fn record(&mut self, events: usize) {
+        let now = Instant::now();
+        self.frames.push_back((now, events));
+        self.prune(now);
+    }
+    /// Forgets the frames older than the window
+    fn prune(&mut self, now: Instant) {
+        while self.frames.front().is_some_and(|(time, _)| now.duration_since(*time) > THROUGHPUT_WINDOW) {
+            self.frames.pop_front();
+        }
+    }
+    /// Frames and events emitted per second, averaged over the whole window, so they read low for the first seconds of a mouse
+    pub fn rates(&mut self) -> (f64, f64) {
+        self.prune(Instant::now());
+        let events: usize = self.frames.iter().map(|(_, events)| events).sum();
+        let seconds = THROUGHPUT_WINDOW.as_secs_f64();
+        (self.frames.len() as f64/seconds, events as f64/seconds)
+    }
+}
+
+/// Whether an evdev event can move the pointer or press buttons.
+/// Timestamps, and the pressure and contact size updates a resting finger sends every frame, can't
+fn is_pointer_relevant(kind: InputEventKind) -> bool {
+    match kind {
+        InputEventKind::Synchronization(_) | InputEventKind::Misc(_) => false,
+        InputEventKind::AbsAxis(axis) => ![
+            AbsoluteAxisType::ABS_PRESSURE,
+            AbsoluteAxisType::ABS_DISTANCE,
+            AbsoluteAxisType::ABS_TOOL_WIDTH,
+            AbsoluteAxisType::ABS_MT_TOUCH_MAJOR,
+            AbsoluteAxisType::ABS_MT_TOUCH_MINOR,
+            AbsoluteAxisType::ABS_MT_WIDTH_MAJOR,
+            AbsoluteAxisType::ABS_MT_WIDTH_MINOR,
+            AbsoluteAxisType::ABS_MT_ORIENTATION,
+            AbsoluteAxisType::ABS_MT_PRESSURE,
+            AbsoluteAxisType::ABS_MT_DISTANCE
+        ].contains(&axis),
+        _ => true
+    }
+}
+
+/// A synthetic event pushed into a mouse by the InjectEvent method, bypassing libinput.
+/// libinput only hands out events it read from a real device, so this is also how the unit tests feed a MouseMovement
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SyntheticEvent{
+    /// Pointer motion, in the units libinput reports motion in
+    Motion{dx: f64, dy: f64},
+    /// Press or release of a button code
+    Button{code: u32, pressed: bool},
+    /// Scroll in notches, positive is up and right, with the scroll settings applied but without scroll acceleration
+    Scroll{vertical: f64, horizontal: f64}
+}
+/// An injected event, and the channel the (type, code, value) of the events it made the mouse emit are sent back on
+#[cfg(feature = "testing")]
+pub type Injection = (SyntheticEvent, tokio::sync::oneshot::Sender<Vec<(u16, u16, i32)>>);
+#[cfg(not(feature = "testing"))]
+type Injection = std::convert::Infallible;
+#[cfg(feature = "testing")]
+type InjectionReceiver = Arc<tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<Injection>>>;
+/// Without the testing feature nothing is ever injected, so there is nothing to receive from
+#[cfg(not(feature = "testing"))]
+#[derive(Clone)]
+struct InjectionReceiver;
+
+/// Asks a mouse driver to read the trackpad at input_path instead of its current one
+pub struct RebindRequest{
+    /// Path of the new trackpad's evdev event file
+    pub input_path: String,
+    /// Receives the mouse's info with the new trackpad, or why it couldn't be opened, in which case the old one is kept
+    pub reply: tokio::sync::oneshot::Sender<Result<MouseInfo, MouseCreationError>>
+}
+
+/// The client that asked for a mouse to be created
+#[derive(Debug, Clone)]
+pub struct MouseOwner{
+    /// Unique DBus name of the caller, e.g. :1.42, or socket for unix socket clients
+    pub client: String,
+    /// Unix user id of the caller
+    pub uid: u32
+}
+
 /// Struct containing a virtual mouse's metadata.  
 #[derive(Debug, Clone)]
 pub struct MouseInfo{
@@ -28,7 +216,446 @@ pub struct MouseInfo{
     /// evdev event number for the input device
     pub input_id: u32,
     /// evdev event number for the output device
-    pub output_id: u32
+    pub output_id: u32,
+    /// evdev event numbers of the mirror output devices, which emit the same events as the output device
+    pub mirror_output_ids: Vec<u32>,
+    /// Path of the input device's evdev event file
+    pub input_path: String,
+    /// Horizontal and vertical resolution of the input device in units per millimeter, none if it doesn't report one
+    pub resolution: Option<(i32, i32)>,
+    /// Options the virtual device was created with
+    pub options: MouseCreationOptions,
+    /// Handle to the mouse's runtime settings, read by the mouse driver every frame
+    pub settings: Arc<Mutex<MouseSettings>>,
+    /// When the mouse was created
+    pub created_at: SystemTime,
+    /// Client that created the mouse, none for mice created from the config file
+    pub owner: Option<MouseOwner>,
+    /// Sends one shot relative motions to the mouse driver, emitted as is on the virtual device
+    pub nudger: tokio::sync::mpsc::UnboundedSender<(i32, i32)>,
+    /// Frames the mouse driver emitted recently, counted by the driver
+    pub throughput: Arc<Mutex<Throughput>>,
+    /// Whether the trackpad is locked, disabled so only the virtual mouse uses it. Set by the mouse driver,
+    /// an unlocked trackpad is left enabled and the virtual mouse ignores it
+    pub locked: Arc<AtomicBool>,
+    /// Asks the mouse driver to read a different trackpad, keeping the virtual devices
+    pub rebinder: tokio::sync::mpsc::UnboundedSender<RebindRequest>,
+    /// Sends synthetic events to the mouse driver
+    #[cfg(feature = "testing")]
+    pub injector: tokio::sync::mpsc::UnboundedSender<Injection>
+}
+impl MouseInfo{
+    /// Creation time in seconds since the unix epoch
+    pub fn created_at_unix(&self) -> u64 {
+        self.created_at.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+    }
+    /// Whether the trackpad is locked
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+    /// Info of a mouse that has no driver behind it, the channels just go nowhere
+    #[cfg(test)]
+    pub fn detached(name: &str, input_id: u32) -> Self {
+        MouseInfo{
+            name: name.to_string(),
+            input_id,
+            output_id: input_id + 100,
+            mirror_output_ids: vec![],
+            input_path: format!("/dev/input/event{}", input_id),
+            resolution: None,
+            options: MouseCreationOptions::default(),
+            settings: Arc::new(Mutex::new(MouseSettings::default())),
+            created_at: SystemTime::now(),
+            owner: None,
+            nudger: tokio::sync::mpsc::unbounded_channel().0,
+            throughput: Default::default(),
+            locked: Arc::new(AtomicBool::new(true)),
+            rebinder: tokio::sync::mpsc::unbounded_channel().0,
+            #[cfg(feature = "testing")]
+            injector: tokio::sync::mpsc::unbounded_channel().0
+        }
+    }
+}
+
+/// Runtime adjustable settings of a virtual mouse. Shared between the DBus server and the mouse driver
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MouseSettings{
+    /// Whether vertical scrolling is turned into horizontal scrolling while the scroll modifier is held
+    pub shift_to_hscroll: bool,
+    /// Whether the scroll modifier is currently held.
+    /// The server does not read keyboards itself, the state is set over DBus (SetScrollModifier) by whatever tracks the modifier key.
+    /// It is state rather than configuration, so it isn't read from config files
+    #[serde(skip)]
+    pub modifier_held: bool,
+    /// Whether fast scrolling should scroll farther than slow scrolling
+    pub scroll_accel: bool,
+    /// Milliseconds without events after which leftover sub unit motion and scroll is discarded. 0 disables the reset
+    pub idle_reset_ms: u64,
+    /// Whether the first motion after the trackpad was idle for first_motion_idle_ms is shortened to FIRST_MOTION_MAX,
+    /// since devices can batch up movement while idle and deliver it as one jump
+    pub clamp_first_motion: bool,
+    /// Milliseconds without events after which the next motion counts as the first one
+    pub first_motion_idle_ms: u64,
+    /// Whether deltas are accumulated as fixed point integers (1/256 units) instead of floats, which can't drift over long runs
+    pub fixed_point: bool,
+    /// Whether a three finger tap clicks the middle button. Independent of libinput's tap to click
+    pub three_finger_middle_click: bool,
+    /// Whether the high resolution scroll axes are emitted. When off only the notched REL_WHEEL and REL_HWHEEL are, for consumers that sum both
+    pub hi_res_scroll: bool,
+    /// Factor horizontal motion is multiplied by. Together with y_scale it corrects for trackpads with a non square aspect ratio
+    pub x_scale: f64,
+    /// Factor vertical motion is multiplied by
+    pub y_scale: f64,
+    /// Button code (e.g. 274 for BTN_MIDDLE) that toggles the trackpad lock instead of clicking
+    pub lock_toggle_button: Option<u32>,
+    /// Button code that unlocks the trackpad while it is held instead of clicking, restoring the lock when it is released,
+    /// for a locked mouse used only now and then
+    pub passthrough_button: Option<u32>,
+    /// Milliseconds to wait for a SYN_REPORT before dispatching libinput anyway, for devices that don't send them reliably.
+    /// 0 waits for the SYN_REPORT forever
+    pub sync_timeout_ms: u64,
+    /// Scroll values (in notches) of a single event smaller than this are ignored, per axis, so perpendicular noise doesn't scroll
+    pub scroll_deadzone: f64,
+    /// Whether a three finger swipe drags, holding the left button while moving the pointer
+    pub three_finger_drag: bool,
+    /// Whether two quick single finger taps emit a double click
+    pub double_tap: bool,
+    /// Longest time, in milliseconds, between the end of the first tap and the begin of the second for them to be a double tap
+    pub double_tap_interval_ms: u64,
+    /// Whether button codes that aren't turned into clicks are printed, to find out what the hardware sends
+    pub unknown_button_logging: bool,
+    /// Most notches each scroll axis emits in a single frame, the excess is discarded so runaway input can't jump a document. 0 doesn't clamp
+    pub max_scroll_per_frame: u32,
+    /// Button code that, while held, multiplies pointer motion by turbo_factor instead of clicking
+    pub turbo_button: Option<u32>,
+    /// Factor pointer motion is multiplied by while the turbo button is held, above 1 for speed and below 1 for precision
+    pub turbo_factor: f64,
+    /// Whether pointer motion is scaled by the input device's resolution, so the same tuning values feel the same on different trackpads.
+    /// Devices that don't report a resolution keep their raw deltas
+    pub normalize_by_resolution: bool,
+    /// Pointer acceleration profile
+    pub accel_profile: AccelProfile,
+    /// Rules transforming the emitted events, applied in order
+    pub rules: Vec<EventRule>,
+    /// Whether every frame scrolls at most one notch per axis, queueing the rest for the following frames, for apps that need single clicks
+    pub discrete_scroll: bool,
+    /// Shortest time in milliseconds between two frames that scroll, scroll in between is summed up and emitted once the interval passed.
+    /// Unlike max_scroll_per_frame this limits how often, not how much, the mouse scrolls. 0 doesn't limit it
+    pub scroll_rate_limit_ms: u64,
+    /// Whether the sub pixel motion left in the accumulators is rounded and emitted right before a button press, so the click lands where the finger was
+    pub flush_motion_on_click: bool,
+    /// Button codes whose presses are dropped, e.g. a physical click on a trackpad used with tap to click
+    pub ignored_buttons: HashSet<u32>,
+    /// Whether the mouse scrolls vertically, disabled axes emit nothing
+    pub vscroll_enabled: bool,
+    /// Whether the mouse scrolls horizontally, for trackpads that trigger it accidentally
+    pub hscroll_enabled: bool,
+    /// Most pixels the pointer moves per frame on each axis, the rest is carried into the following frames. 0 doesn't cap it
+    pub max_speed: u32,
+    /// Whether two finger scrolling moves the pointer instead of scrolling, for users who scroll more comfortably than they drag
+    pub scroll_as_motion: bool,
+    /// Milliseconds after a button press during which pointer motion is dropped, so a finger rolling while clicking doesn't move the pointer.
+    /// Motion past the window, like dragging with the button held, moves it as usual. 0 doesn't drop any
+    pub click_stabilization_ms: u64,
+    /// Whether the mouse moves the pointer. Off gives a mouse that only clicks, e.g. for kiosks
+    pub motion_enabled: bool,
+    /// Whether the mouse presses buttons. Releases still go out, so a button held when this is turned off doesn't stay pressed
+    pub buttons_enabled: bool,
+    /// Whether the mouse scrolls at all, on both axes, independent of vscroll_enabled and hscroll_enabled
+    pub scroll_enabled: bool,
+    /// High resolution scroll units per notch. 120 is what the kernel and most compositors use, some consumers expect other values
+    pub hi_res_quantum: u32
+}
+impl Default for MouseSettings{
+    fn default() -> Self {
+        Self{
+            shift_to_hscroll: false,
+            modifier_held: false,
+            scroll_accel: false,
+            idle_reset_ms: 0,
+            clamp_first_motion: false,
+            first_motion_idle_ms: 500,
+            fixed_point: false,
+            three_finger_middle_click: false,
+            hi_res_scroll: true,
+            x_scale: 1.0,
+            y_scale: 1.0,
+            lock_toggle_button: None,
+            passthrough_button: None,
+            sync_timeout_ms: 0,
+            scroll_deadzone: 0.0,
+            three_finger_drag: false,
+            double_tap: false,
+            double_tap_interval_ms: 300,
+            unknown_button_logging: false,
+            max_scroll_per_frame: 0,
+            turbo_button: None,
+            turbo_factor: 1.0,
+            normalize_by_resolution: false,
+            accel_profile: AccelProfile::Adaptive,
+            rules: vec![],
+            discrete_scroll: false,
+            scroll_rate_limit_ms: 0,
+            flush_motion_on_click: false,
+            ignored_buttons: HashSet::new(),
+            vscroll_enabled: true,
+            hscroll_enabled: true,
+            max_speed: 0,
+            scroll_as_motion: false,
+            click_stabilization_ms: 0,
+            motion_enabled: true,
+            buttons_enabled: true,
+            scroll_enabled: true,
+            hi_res_quantum: 120
+        }
+    }
+}
+
+/// What happens to the motion and scroll events of a frame beyond the queue depth. Button and gesture events are never dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy{
+    /// Every event is kept and summed into the frame's deltas, like without a queue depth
+    Coalesce,
+    /// The oldest events beyond the depth are dropped, keeping the most recent motion
+    DropOldest,
+    /// The newest events beyond the depth are dropped, keeping the motion that arrived first
+    DropNewest
+}
+impl QueuePolicy{
+    /// Names of all policies, used in error messages
+    pub const NAMES: &'static str = "coalesce, drop-oldest, or drop-newest";
+    /// Name of the policy, as used over DBus
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueuePolicy::Coalesce => "coalesce",
+            QueuePolicy::DropOldest => "drop-oldest",
+            QueuePolicy::DropNewest => "drop-newest"
+        }
+    }
+    /// Looks up a policy by name, none for unknown names
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "coalesce" => Some(QueuePolicy::Coalesce),
+            "drop-oldest" => Some(QueuePolicy::DropOldest),
+            "drop-newest" => Some(QueuePolicy::DropNewest),
+            _ => None
+        }
+    }
+}
+
+/// Keys scrolling is turned into while the scroll modifier is held, for apps that zoom or page with the keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScrollKeyMap{
+    /// Scrolling always scrolls
+    #[default]
+    Off,
+    /// Left Ctrl is held around the scroll, which zooms in most apps
+    Ctrl,
+    /// Every vertical notch presses Page Up or Page Down instead of scrolling, horizontal scrolling still scrolls
+    Page
+}
+impl ScrollKeyMap{
+    /// Names of all key maps, used in error messages
+    pub const NAMES: &'static str = "off, ctrl, or page";
+    /// Name of the key map, as used over DBus
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScrollKeyMap::Off => "off",
+            ScrollKeyMap::Ctrl => "ctrl",
+            ScrollKeyMap::Page => "page"
+        }
+    }
+    /// Looks up a key map by name, none for unknown names
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(ScrollKeyMap::Off),
+            "ctrl" => Some(ScrollKeyMap::Ctrl),
+            "page" => Some(ScrollKeyMap::Page),
+            _ => None
+        }
+    }
+    /// Keys the virtual device has to advertise for the key map
+    fn keys(&self) -> &'static [Key] {
+        match self {
+            ScrollKeyMap::Off => &[],
+            ScrollKeyMap::Ctrl => &[Key::KEY_LEFTCTRL],
+            ScrollKeyMap::Page => &[Key::KEY_PAGEUP, Key::KEY_PAGEDOWN]
+        }
+    }
+}
+
+/// Pointer acceleration profiles, referred to by name over DBus and in config files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccelProfile{
+    /// libinput's flat profile, motion is multiplied by a constant factor
+    Flat,
+    /// libinput's adaptive profile, the default for trackpads, where the factor depends on the speed
+    Adaptive,
+    /// libinput's flat profile, with a factor growing linearly with the speed applied by the mouse
+    Linear
+}
+impl AccelProfile{
+    /// Names of all profiles, used in error messages
+    pub const NAMES: &'static str = "flat, adaptive, or linear";
+    /// Name of the profile, as used over DBus
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccelProfile::Flat => "flat",
+            AccelProfile::Adaptive => "adaptive",
+            AccelProfile::Linear => "linear"
+        }
+    }
+    /// Looks up a profile by name, none for unknown names
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "flat" => Some(AccelProfile::Flat),
+            "adaptive" => Some(AccelProfile::Adaptive),
+            "linear" => Some(AccelProfile::Linear),
+            _ => None
+        }
+    }
+    /// The profile libinput is configured with for this profile
+    fn libinput_profile(&self) -> input::AccelProfile {
+        match self {
+            AccelProfile::Adaptive => input::AccelProfile::Adaptive,
+            AccelProfile::Flat | AccelProfile::Linear => input::AccelProfile::Flat
+        }
+    }
+}
+
+/// Output axes an event rule can refer to. The scroll axes include their high resolution channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAxis{
+    X,
+    Y,
+    Wheel,
+    HWheel
+}
+impl RuleAxis{
+    /// Whether an output event belongs to the axis
+    fn matches(&self, event: &InputEvent) -> bool {
+        if event.event_type() != EventType::RELATIVE {return false;}
+        let axes: &[RelativeAxisType] = match self {
+            RuleAxis::X => &[RelativeAxisType::REL_X],
+            RuleAxis::Y => &[RelativeAxisType::REL_Y],
+            RuleAxis::Wheel => &[RelativeAxisType::REL_WHEEL, RelativeAxisType::REL_WHEEL_HI_RES],
+            RuleAxis::HWheel => &[RelativeAxisType::REL_HWHEEL, RelativeAxisType::REL_HWHEEL_HI_RES]
+        };
+        axes.iter().any(|axis| axis.0 == event.code())
+    }
+}
+
+/// A rule transforming the events a mouse emits. Rules apply to every frame right before it is emitted, in list order,
+/// each one to the output of the previous one. Serialized as e.g. {"rule": "drop_axis", "axis": "hwheel"}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum EventRule{
+    /// Swaps the left and right buttons
+    SwapButtons,
+    /// Drops every event of the axis
+    DropAxis{axis: RuleAxis},
+    /// Limits the pointer motion of a frame to max units per axis, discarding the rest. 0 doesn't clamp
+    ClampMotion{max: u32}
+}
+impl EventRule{
+    /// Applies the rule to a frame
+    fn apply(&self, events: Vec<InputEvent>) -> Vec<InputEvent> {
+        match self {
+            EventRule::SwapButtons => events.into_iter().map(|event| {
+                let swapped = match event.kind() {
+                    InputEventKind::Key(Key::BTN_LEFT) => Key::BTN_RIGHT,
+                    InputEventKind::Key(Key::BTN_RIGHT) => Key::BTN_LEFT,
+                    _ => {return event;}
+                };
+                InputEvent::new(EventType::KEY, swapped.code(), event.value())
+            }).collect(),
+            EventRule::DropAxis{axis} => events.into_iter().filter(|event| !axis.matches(event)).collect(),
+            EventRule::ClampMotion{max} => events.into_iter().map(|event| {
+                if !RuleAxis::X.matches(&event) && !RuleAxis::Y.matches(&event) {return event;}
+                InputEvent::new(EventType::RELATIVE, event.code(), clamp_relative(event.value(), *max))
+            }).collect()
+        }
+    }
+}
+
+/// Options used when creating a virtual mouse. Unlike the settings, these can't change while the mouse is running
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MouseCreationOptions{
+    /// Whether the virtual device also advertises the keyboard keys, so button to key mappings have somewhere to emit
+    pub with_keyboard: bool,
+    /// Whether the virtual device has the high resolution scroll axes. Removing them needs a new device, so this can't change at runtime,
+    /// the hi_res_scroll setting only suppresses the events
+    pub hi_res_scroll: bool,
+    /// Bus type the virtual device reports (e.g. 0x03 USB, 0x06 virtual), for software that filters devices by bus.
+    /// None keeps evdev's default, USB
+    pub bus_type: Option<u16>,
+    /// Number of extra virtual devices every event is echoed to, e.g. one for the desktop and one captured by a recorder
+    pub mirrors: u32,
+    /// Milliseconds between creating the mouse and signalling MouseCreated, which has the session server disable the trackpad.
+    /// A mouse stopped within the delay never gets its trackpad disabled. 0 signals right away
+    pub lock_delay_ms: u64,
+    /// Path of a named pipe every emitted frame is copied to, created if it doesn't exist
+    pub event_tap: Option<String>,
+    /// Most motion and scroll events processed per frame, 0 for no limit
+    pub queue_depth: u32,
+    /// What happens to the motion and scroll events beyond the queue depth
+    pub queue_policy: QueuePolicy,
+    /// Keys scrolling is turned into while the scroll modifier is held. The keys are advertised by the virtual device, so this can't change at runtime
+    pub scroll_key_map: ScrollKeyMap,
+    /// Name of the server side profile whose settings the mouse starts with, none for the default settings
+    pub profile: Option<String>,
+    /// Milliseconds after creation over which the pointer sensitivity ramps up to the configured one, so a mouse created
+    /// mid gesture doesn't jump. 0 starts at full sensitivity
+    pub soft_start_ms: u64,
+    /// Whether the mouse is stopped once the DBus client that created it disconnects, so a short lived client leaves no mouse behind.
+    /// Mice without an owner, like the ones from the config file, are never stopped this way
+    pub tie_to_caller: bool
+}
+impl Default for MouseCreationOptions{
+    fn default() -> Self {
+        Self{with_keyboard: false, hi_res_scroll: true, bus_type: None, mirrors: 0, lock_delay_ms: 0, event_tap: None, queue_depth: 0, queue_policy: QueuePolicy::Coalesce, scroll_key_map: ScrollKeyMap::Off, profile: None, soft_start_ms: 0, tie_to_caller: false}
+    }
+}
+
+/// Prefix of the virtual devices' names, followed by the mouse name
+pub const DEVICE_NAME_PREFIX: &str = "TPtoMouse ";
+
+/// Stages of the virtual mouse creation process, reported as each one finishes
+#[derive(Debug, Clone, Copy)]
+pub enum MouseCreationStage{
+    /// The input device was added to libinput and opened with evdev
+    OpenedEvdev,
+    /// The virtual output device was created
+    CreatedVirtualDevice,
+    /// The input and output event ids were found
+    ResolvedIds
+}
+impl MouseCreationStage{
+    /// Human readable name of the stage, sent with the MouseCreationProgress signal
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MouseCreationStage::OpenedEvdev => "opened evdev",
+            MouseCreationStage::CreatedVirtualDevice => "created virtual device",
+            MouseCreationStage::ResolvedIds => "resolved ids"
+        }
+    }
+}
+/// Function called with the mouse name whenever a stage of a mouse creation finishes
+pub type ProgressCallback = Box<dyn Fn(&str, MouseCreationStage)>;
+
+/// A trackpad the session server has to enable or disable, without a DBus call asking for it. Sent to the server, which signals it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackpadSignal{
+    /// The trackpad with the input id should be enabled again, signalled as MouseDeleted
+    Enable(u32),
+    /// The trackpad with the input id should be disabled, its mouse is using it again. Signalled as MouseCreated
+    Disable(u32)
 }
 
 /// Errors from the virtual mouse creation process
@@ -40,6 +667,8 @@ pub enum MouseCreationError{
     FailedToAddPathAsLibinputDevice,
     /// The path could not be opened by the evdev crate as an evdev device
     FailedToOpenEvdevDevice(std::io::Error),
+    /// Another process has an exclusive grab on the device, so the mouse would never get any events
+    DeviceBusy,
     /// After opening the device, it could not be turned into an event stream
     FailedToCreateEventStream(std::io::Error),
     /// VirtualDeviceBuilder failed to create a virtual device
@@ -52,6 +681,12 @@ pub enum MouseCreationError{
     FailedToGetOutputSyspath(std::io::Error),
     /// Could not get the output event id from the output's syspath
     FailedToGetOutputIDFromSyspath(PathBuf),
+    /// Could not create the named pipe of the event tap
+    FailedToCreateEventTap(std::io::Error),
+    /// The creation was cancelled with CancelCreate before it finished
+    Cancelled,
+    /// The creation options name a profile the server doesn't have. Contains the profile name
+    UnknownProfile(String),
     /// The program had a future awaiting a mouse that is not queued, created, or returned an error
     AsyncProgramError
 }
@@ -61,12 +696,16 @@ impl ToString for MouseCreationError{
             MouseCreationError::NameInUse => "Name is already used".to_string(),
             MouseCreationError::FailedToAddPathAsLibinputDevice => "Path was unable to be added to the libinput context as a device".to_string(),
             MouseCreationError::FailedToOpenEvdevDevice(err) => format!("Evdev device failed to open: {}", err),
+            MouseCreationError::DeviceBusy => "Device is grabbed by another process".to_string(),
             MouseCreationError::FailedToCreateEventStream(err) => format!("Event Stream could not be created: {}", err),
             MouseCreationError::FailedToCreateVirtualDevice(err) => format!("Virtual device could not be created: {}", err),
             MouseCreationError::FailedToGetInputID(err) => format!("Could not get input id: {}", err),
             MouseCreationError::FailedToGetLibinputID => format!("Could not get libinput id from the xinput command line tool"),
             MouseCreationError::FailedToGetOutputSyspath(err) => format!("Could not get output syspath: {}", err),
             MouseCreationError::FailedToGetOutputIDFromSyspath(err) => format!("Could not get output id from syspath: {:?}", err),
+            MouseCreationError::FailedToCreateEventTap(err) => format!("Could not create the event tap pipe: {}", err),
+            MouseCreationError::Cancelled => "Creation was cancelled".to_string(),
+            MouseCreationError::UnknownProfile(profile) => format!("There is no profile named {}", profile),
             MouseCreationError::AsyncProgramError => "Future created for mouse that is not queued, created, or failed".to_string(),
         }
     }
@@ -79,7 +718,11 @@ pub enum MouseDriverUpdateError{
     /// The libinput context was unable to dispatch for events
     DataSourceDispatchError(std::io::Error),
     /// The virtual device was unable to emit events
-    EmitEventsError(std::io::Error)
+    EmitEventsError(std::io::Error),
+    /// The mouse was asked to stop, and released its buttons
+    Stopped,
+    /// The update loop panicked, contains the panic message
+    Panicked(String)
 }
 
 /// Struct containing virtual mouse data.
@@ -90,161 +733,1215 @@ pub struct MouseDriver{
     test_source: EventStream,
     /// Libinput event input.
     data_source: Libinput,
+    /// The trackpad in the libinput context, used to configure it
+    device: input::Device,
+    /// Accel profile libinput was last configured with, none before the first frame
+    applied_accel_profile: Option<AccelProfile>,
     /// Virtual device output
     output: VirtualDevice,
+    /// Virtual devices that get a copy of everything emitted on the output
+    mirrors: Vec<VirtualDevice>,
     /// Mouse Position and event tracking data
-    movement: MouseMovement
+    movement: MouseMovement,
+    /// Number of consecutive frames skipped because they couldn't move the pointer
+    idle_frames: u32,
+    /// Whether the trackpad gets locked again once the passthrough button is released, none while it isn't held
+    passthrough_restore: Option<bool>,
+    /// Flag shared by all mice, while it is set every mouse ignores its trackpad like an unlocked one
+    paused: Arc<AtomicBool>,
+    /// Where the trackpads to enable or disable when locking and unlocking are sent, none to not signal them
+    trackpad_signals: Option<tokio::sync::mpsc::UnboundedSender<TrackpadSignal>>,
+    /// Notified to have the update loop release the buttons and return after the current frame
+    stop: Arc<Notify>,
+    /// Time in microseconds each unknown button code was last logged, used to rate limit the logging
+    unknown_button_log: HashMap<u32, u64>,
+    /// Synthetic events waiting to be processed, always empty without the testing feature
+    injections: InjectionReceiver,
+    /// Relative motions waiting to be emitted, sent through the nudger of the mouse's info
+    nudges: Arc<tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<(i32, i32)>>>,
+    /// Trackpads to switch to, sent through the rebinder of the mouse's info
+    rebinds: Arc<tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<RebindRequest>>>,
+    /// Pipe every emitted frame is copied to, if the mouse was created with one
+    event_tap: Option<EventTap>
 }
-impl MouseDriver{
-    /// Create a new mouse driver
-    pub fn new(name: String, input_path: String) -> Result<Self, MouseCreationError>{
-        // Get Libinput setup
-        let mut data_source = Libinput::new_from_path(Interface);
-        let device = data_source.path_add_device(&input_path).ok_or(MouseCreationError::FailedToAddPathAsLibinputDevice)?;
-        // Get the input event id
-        fn sysname_to_id(sysname: String) -> Result<u32, MouseCreationError> {
-            sysname.clone().strip_prefix("event")
-                .ok_or_else(|| MouseCreationError::FailedToGetInputID(sysname.clone()))
-                .and_then(|val| val.parse::<u32>().or_else(|_| Err(MouseCreationError::FailedToGetInputID(sysname.clone()))))
-        }
-        let input_id = sysname_to_id(device.sysname().to_string())?;
-        // Get evdev test source setup
-        let test_source = Device::open(input_path.clone())
-            .map_err(|err| {MouseCreationError::FailedToOpenEvdevDevice(err)})?
-            .into_event_stream().map_err(|err| MouseCreationError::FailedToCreateEventStream(err))?;
+
+/// Builder for mouse drivers, for creating them with creation options, starting settings, or a progress callback
+pub struct MouseDriverBuilder<'a>{
+    name: String,
+    input_path: String,
+    options: MouseCreationOptions,
+    settings: Option<MouseSettings>,
+    paused: Arc<AtomicBool>,
+    trackpad_signals: Option<tokio::sync::mpsc::UnboundedSender<TrackpadSignal>>,
+    progress: Box<dyn FnMut(MouseCreationStage) + 'a>
+}
+impl<'a> MouseDriverBuilder<'a>{
+    /// Starts building a mouse driver named name, reading the trackpad at input_path, with the default options
+    pub fn new(name: String, input_path: String) -> Self{
+        Self{name, input_path, options: MouseCreationOptions::default(), settings: None, paused: Arc::new(AtomicBool::new(false)), trackpad_signals: None, progress: Box::new(|_| {})}
+    }
+    /// Sets all creation options at once
+    pub fn options(mut self, options: MouseCreationOptions) -> Self{
+        self.options = options;
+        self
+    }
+    /// Sets whether the virtual device also advertises the keyboard keys
+    pub fn with_keyboard(mut self, with_keyboard: bool) -> Self{
+        self.options.with_keyboard = with_keyboard;
+        self
+    }
+    /// Sets whether the virtual device has the high resolution scroll axes
+    pub fn hi_res_scroll(mut self, hi_res_scroll: bool) -> Self{
+        self.options.hi_res_scroll = hi_res_scroll;
+        self
+    }
+    /// Sets the bus type the virtual device reports
+    pub fn bus_type(mut self, bus_type: u16) -> Self{
+        self.options.bus_type = Some(bus_type);
+        self
+    }
+    /// Sets the number of extra virtual devices every event is echoed to
+    pub fn mirrors(mut self, mirrors: u32) -> Self{
+        self.options.mirrors = mirrors;
+        self
+    }
+    /// Sets the settings the mouse starts with, instead of the defaults
+    pub fn settings(mut self, settings: MouseSettings) -> Self{
+        self.settings = Some(settings);
+        self
+    }
+    /// Sets the pause flag shared with the other mice, instead of one only this mouse uses
+    pub fn paused(mut self, paused: Arc<AtomicBool>) -> Self{
+        self.paused = paused;
+        self
+    }
+    /// Sets where the trackpads to enable or disable are sent when the mouse is locked or unlocked
+    pub fn trackpad_signals(mut self, sender: tokio::sync::mpsc::UnboundedSender<TrackpadSignal>) -> Self{
+        self.trackpad_signals = Some(sender);
+        self
+    }
+    /// Sets a function called after each finished stage of the creation
+    pub fn progress(mut self, progress: impl FnMut(MouseCreationStage) + 'a) -> Self{
+        self.progress = Box::new(progress);
+        self
+    }
+    /// Creates the mouse driver. Waits for the virtual devices' event nodes, so it may take up to OUTPUT_ID_RETRIES retry intervals
+    pub async fn build(self) -> Result<MouseDriver, MouseCreationError>{
+        let MouseDriverBuilder{name, input_path, options, settings, paused, trackpad_signals, mut progress} = self;
+        let OpenedInput{data_source, device, input_id, test_source, resolution} = open_input(&input_path)?;
+        progress(MouseCreationStage::OpenedEvdev);
         // Create the virtual mouse device
-        fn create_virtual_device(name: String) -> std::io::Result<VirtualDevice> {
-            VirtualDeviceBuilder::new()?.name(("TPtoMouse ".to_owned() + name.as_str()).as_str())
-                .with_relative_axes(&AttributeSet::from_iter([
-                    RelativeAxisType::REL_X,
-                    RelativeAxisType::REL_Y,
-                    RelativeAxisType::REL_WHEEL,
-                    RelativeAxisType::REL_WHEEL_HI_RES,
-                    RelativeAxisType::REL_HWHEEL,
-                    RelativeAxisType::REL_HWHEEL_HI_RES
-                ]))?
-                .with_keys(&AttributeSet::from_iter([
-                    Key::BTN_LEFT,
-                    Key::BTN_RIGHT,
-                    Key::BTN_MIDDLE
-                ]))?
+        fn create_virtual_device(device_name: String, options: &MouseCreationOptions) -> std::io::Result<VirtualDevice> {
+            let mut keys = AttributeSet::from_iter([
+                Key::BTN_LEFT,
+                Key::BTN_RIGHT,
+                Key::BTN_MIDDLE
+            ]);
+            if options.with_keyboard {
+                // Every key from KEY_ESC to KEY_MICMUTE, the range a standard keyboard reports
+                (Key::KEY_ESC.code()..=Key::KEY_MICMUTE.code()).for_each(|code| keys.insert(Key::new(code)));
+            }
+            options.scroll_key_map.keys().iter().for_each(|key| keys.insert(*key));
+            let mut axes = AttributeSet::from_iter([
+                RelativeAxisType::REL_X,
+                RelativeAxisType::REL_Y,
+                RelativeAxisType::REL_WHEEL,
+                RelativeAxisType::REL_HWHEEL
+            ]);
+            if options.hi_res_scroll {
+                axes.insert(RelativeAxisType::REL_WHEEL_HI_RES);
+                axes.insert(RelativeAxisType::REL_HWHEEL_HI_RES);
+            }
+            let mut builder = VirtualDeviceBuilder::new()?.name(device_name.as_str());
+            if let Some(bus_type) = options.bus_type {
+                // Only the bus type changes, the vendor, product, and version stay at evdev's defaults
+                builder = builder.input_id(InputId::new(BusType(bus_type), 0x1234, 0x5678, 0x111));
+            }
+            builder.with_relative_axes(&axes)?
+                .with_keys(&keys)?
                 .build()
         }
-        let mut output = create_virtual_device(name.clone()).map_err(|err| MouseCreationError::FailedToCreateVirtualDevice(err))?;
-        // Get the output event id
-        let syspath = output.get_syspath().map_err(|err| MouseCreationError::FailedToGetOutputSyspath(err))?;
+        let device_name = DEVICE_NAME_PREFIX.to_owned() + name.as_str();
+        let mut output = create_virtual_device(device_name.clone(), &options).map_err(|err| MouseCreationError::FailedToCreateVirtualDevice(err))?;
+        // Mirrors are named after the output with their number appended, so they can be told apart in xinput and evtest
+        let mut mirrors = (1..=options.mirrors)
+            .map(|mirror| create_virtual_device(format!("{} mirror {}", device_name, mirror), &options))
+            .collect::<std::io::Result<Vec<VirtualDevice>>>().map_err(|err| MouseCreationError::FailedToCreateVirtualDevice(err))?;
+        progress(MouseCreationStage::CreatedVirtualDevice);
+        // Get the output event ids
+        // Devices with several capabilities can have other children (mouseN, ...), so only eventN with a numeric N counts
         fn get_output_id(syspath: PathBuf) -> std::io::Result<u32>{
-            let id_string = syspath.clone().read_dir()?.filter_map(|entry| {
+            syspath.clone().read_dir()?.filter_map(|entry| {
                 match entry {
                     Ok(dir) => {
                         match dir.file_name().into_string() {
                             Ok(name) => {
-                                name.strip_prefix("event").map(|id| id.to_string())
+                                name.strip_prefix("event").and_then(|id| id.parse::<u32>().ok())
                             },
                             Err(_) => {None}
                         }
                     },
                     Err(_) => {None}
                 }
-            }).next().ok_or(std::io::Error::from_raw_os_error(0))?;
-            id_string.parse::<u32>().map_err(|_| std::io::Error::from_raw_os_error(0))
+            }).next().ok_or(std::io::Error::from_raw_os_error(0))
+        }
+        // Without the syspath from uinput (UI_GET_SYSNAME needs kernel 3.15), the newest virtual input device with the device's name is used.
+        // Input numbers only grow, so the newest one has the highest number
+        fn find_virtual_syspath(device_name: &str) -> Option<PathBuf> {
+            Path::new("/sys/devices/virtual/input").read_dir().ok()?.filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let number = path.file_name()?.to_str()?.strip_prefix("input")?.parse::<u32>().ok()?;
+                let name = std::fs::read_to_string(path.join("name")).ok()?;
+                (name.trim_end_matches('\n') == device_name).then_some((number, path))
+            }).max_by_key(|(number, _)| *number).map(|(_, path)| path)
         }
-        let output_id = get_output_id(syspath.clone()).map_err(|_| MouseCreationError::FailedToGetOutputIDFromSyspath(syspath))?;
+        // The event node is created asynchronously, so with a slow udev it may not exist yet right after the device is built.
+        // The retries sleep asynchronously, so the other mice keep running in the meantime
+        async fn resolve_output_id(device: &mut VirtualDevice, device_name: &str) -> Result<u32, MouseCreationError> {
+            let syspath = match device.get_syspath() {
+                Ok(syspath) => syspath,
+                Err(err) => find_virtual_syspath(device_name).ok_or(MouseCreationError::FailedToGetOutputSyspath(err))?
+            };
+            let mut attempt = 0;
+            loop {
+                match get_output_id(syspath.clone()) {
+                    Ok(output_id) => {return Ok(output_id);}
+                    Err(_) if attempt < OUTPUT_ID_RETRIES => {
+                        attempt += 1;
+                        tokio::time::sleep(OUTPUT_ID_RETRY_INTERVAL).await;
+                    }
+                    Err(_) => {return Err(MouseCreationError::FailedToGetOutputIDFromSyspath(syspath));}
+                }
+            }
+        }
+        // Each mirror is its own uinput device, so its id is resolved from its own syspath, the same way as the output's
+        let output_id = resolve_output_id(&mut output, &device_name).await?;
+        let mut mirror_output_ids = Vec::with_capacity(mirrors.len());
+        for (index, mirror) in mirrors.iter_mut().enumerate() {
+            mirror_output_ids.push(resolve_output_id(mirror, &format!("{} mirror {}", device_name, index + 1)).await?);
+        }
+        progress(MouseCreationStage::ResolvedIds);
 
-        let metadata = MouseInfo{name, input_id, output_id};
+        let settings = settings.unwrap_or(MouseSettings{hi_res_scroll: options.hi_res_scroll, ..Default::default()});
+        let scroll_key_map = options.scroll_key_map;
+        let soft_start = (options.soft_start_ms > 0).then(|| (Instant::now(), Duration::from_millis(options.soft_start_ms)));
+        let event_tap = options.event_tap.as_deref().map(EventTap::create).transpose().map_err(MouseCreationError::FailedToCreateEventTap)?;
+        #[cfg(feature = "testing")]
+        let (injector, injections) = {
+            let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+            (sender, Arc::new(tokio::sync::Mutex::new(receiver)))
+        };
+        #[cfg(not(feature = "testing"))]
+        let injections = InjectionReceiver;
+        let (nudger, nudges) = tokio::sync::mpsc::unbounded_channel();
+        let (rebinder, rebinds) = tokio::sync::mpsc::unbounded_channel();
+        let metadata = MouseInfo{
+            name, input_id, output_id, mirror_output_ids, input_path, resolution, options, settings: Arc::new(Mutex::new(settings)), created_at: SystemTime::now(), owner: None, nudger, throughput: Arc::new(Mutex::new(Throughput::default())), locked: Arc::new(AtomicBool::new(true)), rebinder,
+            #[cfg(feature = "testing")]
+            injector
+        };
 
-        Ok(Self{
+        Ok(MouseDriver{
             metadata,
             test_source,
             data_source,
+            device,
+            applied_accel_profile: None,
             output,
-            movement: MouseMovement::default()
+            mirrors,
+            movement: MouseMovement{resolution: resolution.map(|(x, y)| (x as f64, y as f64)), scroll_key_map, soft_start, ..Default::default()},
+            idle_frames: 0,
+            passthrough_restore: None,
+            paused,
+            trackpad_signals,
+            stop: Arc::new(Notify::new()),
+            unknown_button_log: HashMap::new(),
+            injections,
+            nudges: Arc::new(tokio::sync::Mutex::new(nudges)),
+            rebinds: Arc::new(tokio::sync::Mutex::new(rebinds)),
+            event_tap
         })
     }
+}
+
+/// A trackpad opened for a mouse driver, through libinput for its events and evdev for waiting on its frames
+struct OpenedInput{
+    data_source: Libinput,
+    device: input::Device,
+    input_id: u32,
+    test_source: EventStream,
+    resolution: Option<(i32, i32)>
+}
+
+/// Opens the trackpad at input_path with libinput and evdev, failing with DeviceBusy if another process grabbed it
+fn open_input(input_path: &str) -> Result<OpenedInput, MouseCreationError> {
+    // Get Libinput setup
+    let mut data_source = Libinput::new_from_path(Interface);
+    let device = data_source.path_add_device(input_path).ok_or(MouseCreationError::FailedToAddPathAsLibinputDevice)?;
+    // Get the input event id
+    fn sysname_to_id(sysname: String) -> Result<u32, MouseCreationError> {
+        sysname.clone().strip_prefix("event")
+            .ok_or_else(|| MouseCreationError::FailedToGetInputID(sysname.clone()))
+            .and_then(|val| val.parse::<u32>().or_else(|_| Err(MouseCreationError::FailedToGetInputID(sysname.clone()))))
+    }
+    let input_id = sysname_to_id(device.sysname().to_string())?;
+    // Get evdev test source setup
+    let mut evdev_device = Device::open(input_path)
+        .map_err(|err| {MouseCreationError::FailedToOpenEvdevDevice(err)})?;
+    // A device grabbed by another process still opens, but never delivers events. Grabbing it briefly finds out,
+    // EVIOCGRAB fails with EBUSY while someone else holds the grab. Other grab failures don't mean it is taken, so they are ignored
+    match evdev_device.grab() {
+        Ok(()) => {let _ = evdev_device.ungrab();}
+        Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {return Err(MouseCreationError::DeviceBusy);}
+        Err(_) => {}
+    }
+    // The resolution of ABS_X and ABS_Y, if the device has both and reports a resolution for them. 0 means unreported
+    let resolution = evdev_device.supported_absolute_axes()
+        .filter(|axes| axes.contains(AbsoluteAxisType::ABS_X) && axes.contains(AbsoluteAxisType::ABS_Y))
+        .and_then(|_| evdev_device.get_abs_state().ok())
+        .map(|state| (state[AbsoluteAxisType::ABS_X.0 as usize].resolution, state[AbsoluteAxisType::ABS_Y.0 as usize].resolution))
+        .filter(|(x, y)| *x > 0 && *y > 0);
+    let test_source = evdev_device.into_event_stream().map_err(|err| MouseCreationError::FailedToCreateEventStream(err))?;
+    Ok(OpenedInput{data_source, device, input_id, test_source, resolution})
+}
+
+/// Hardware details of an input device as libinput and evdev report them, for bug reports
+#[derive(Debug, Clone)]
+pub struct DeviceInfo{
+    /// Name libinput reports for the device
+    pub libinput_name: String,
+    /// Kernel name of the device's event file, e.g. event5
+    pub sysname: String,
+    /// USB vendor id, or the equivalent for other buses
+    pub vendor_id: u32,
+    /// USB product id, or the equivalent for other buses
+    pub product_id: u32,
+    /// Names of the libinput capabilities the device has, e.g. pointer and gesture
+    pub capabilities: Vec<String>,
+    /// Name evdev reports for the device, empty if it has none
+    pub evdev_name: String
+}
+impl DeviceInfo{
+    /// Opens the device at path with libinput and evdev, collects its details, and closes it again
+    pub fn query(path: &str) -> Result<Self, MouseCreationError> {
+        let mut libinput = Libinput::new_from_path(Interface);
+        let device = libinput.path_add_device(path).ok_or(MouseCreationError::FailedToAddPathAsLibinputDevice)?;
+        let capabilities = [
+            (DeviceCapability::Keyboard, "keyboard"),
+            (DeviceCapability::Pointer, "pointer"),
+            (DeviceCapability::Touch, "touch"),
+            (DeviceCapability::TabletTool, "tablet-tool"),
+            (DeviceCapability::TabletPad, "tablet-pad"),
+            (DeviceCapability::Gesture, "gesture"),
+            (DeviceCapability::Switch, "switch")
+        ].into_iter().filter(|(capability, _)| device.has_capability(*capability)).map(|(_, name)| name.to_string()).collect();
+        let info = DeviceInfo{
+            libinput_name: device.name().to_string(),
+            sysname: device.sysname().to_string(),
+            vendor_id: device.id_vendor(),
+            product_id: device.id_product(),
+            capabilities,
+            evdev_name: String::new()
+        };
+        libinput.path_remove_device(device);
+        let evdev_device = Device::open(path).map_err(MouseCreationError::FailedToOpenEvdevDevice)?;
+        Ok(DeviceInfo{evdev_name: evdev_device.name().unwrap_or_default().to_string(), ..info})
+    }
+}
+
+/// UI_GET_VERSION, _IOR('U', 45, unsigned int), asks uinput for its interface version. Kernels before 4.5 don't know it
+const UI_GET_VERSION: u64 = 0x8004552d;
+
+/// Versions of the kernel interfaces the server relies on, for bug reports about specific kernels
+#[derive(Debug, Clone)]
+pub struct SystemInfo{
+    /// Kernel release as uname -r prints it, empty if it couldn't be read
+    pub kernel_release: String,
+    /// Whether /dev/uinput exists, virtual devices can't be created without it
+    pub uinput_present: bool,
+    /// Version of the uinput interface, none if /dev/uinput couldn't be opened or the kernel predates UI_GET_VERSION
+    pub uinput_version: Option<u32>,
+    /// Version of the evdev protocol as major.minor.patch, read from the first input device that opens, none if none does
+    pub evdev_version: Option<String>
+}
+impl SystemInfo{
+    /// Reads the kernel release, and opens /dev/uinput and an input device briefly for their versions
+    pub fn query() -> Self {
+        let kernel_release = std::fs::read_to_string("/proc/sys/kernel/osrelease").map(|release| release.trim().to_string()).unwrap_or_default();
+        let uinput_present = Path::new("/dev/uinput").exists();
+        let uinput_version = OpenOptions::new().read(true).write(true).open("/dev/uinput").ok().and_then(|uinput| {
+            let mut version: libc::c_uint = 0;
+            (unsafe {libc::ioctl(uinput.as_raw_fd(), UI_GET_VERSION as _, &mut version)} == 0).then_some(version)
+        });
+        let evdev_version = evdev::enumerate().next()
+            .map(|(_, device)| device.driver_version())
+            .map(|(major, minor, patch)| format!("{}.{}.{}", major, minor, patch));
+        SystemInfo{kernel_release, uinput_present, uinput_version, evdev_version}
+    }
+    /// One line summary for the server's startup log
+    pub fn summary(&self) -> String {
+        let unknown = || "unknown".to_string();
+        format!("kernel {}, /dev/uinput {} (version {}), evdev version {}",
+            Some(self.kernel_release.clone()).filter(|release| !release.is_empty()).unwrap_or_else(unknown),
+            if self.uinput_present {"present"} else {"missing"},
+            self.uinput_version.map(|version| version.to_string()).unwrap_or_else(unknown),
+            self.evdev_version.clone().unwrap_or_else(unknown))
+    }
+}
+
+/// Longest time ProbeInput may watch a device
+pub const MAX_PROBE_DURATION: Duration = Duration::from_secs(60);
 
-    /// Asynchronously waits for the next syn report to happen for the trackpad input device
-    pub async fn await_sync_event(&mut self) -> Result<(), MouseDriverUpdateError>{
+/// Number of events of each evdev type a device sent while it was probed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InputCounts{
+    /// SYN_REPORTs, one per frame
+    pub frames: u32,
+    /// Key events, buttons as well as touches like BTN_TOUCH and BTN_TOOL_FINGER
+    pub key: u32,
+    /// Relative axis events, motion and scroll of mice
+    pub relative: u32,
+    /// Absolute axis events, finger positions, pressure, and contact sizes of trackpads
+    pub absolute: u32,
+    /// Everything else, like MSC_TIMESTAMP
+    pub other: u32
+}
+impl InputCounts{
+    /// Opens the device at path with evdev next to any mouse using it, and counts its events by type for duration.
+    /// Trackpad scrolling is finger motion at this level, so it counts as absolute events
+    pub async fn probe(path: &str, duration: Duration) -> Result<Self, MouseCreationError> {
+        let device = Device::open(path).map_err(MouseCreationError::FailedToOpenEvdevDevice)?;
+        let mut stream = device.into_event_stream().map_err(MouseCreationError::FailedToCreateEventStream)?;
+        let mut counts = InputCounts::default();
+        let deadline = tokio::time::Instant::now() + duration.min(MAX_PROBE_DURATION);
+        while let Ok(event) = tokio::time::timeout_at(deadline, stream.next_event()).await {
+            let event = event.map_err(MouseCreationError::FailedToOpenEvdevDevice)?;
+            match event.kind() {
+                InputEventKind::Synchronization(Synchronization::SYN_REPORT) => counts.frames += 1,
+                InputEventKind::Synchronization(_) => {}
+                InputEventKind::Key(_) => counts.key += 1,
+                InputEventKind::RelAxis(_) => counts.relative += 1,
+                InputEventKind::AbsAxis(_) => counts.absolute += 1,
+                _ => counts.other += 1
+            }
+        }
+        Ok(counts)
+    }
+}
+
+impl MouseDriver{
+    /// Create a new mouse driver with the default creation options. Use MouseDriverBuilder for anything else
+    pub async fn new(name: String, input_path: String) -> Result<Self, MouseCreationError>{
+        MouseDriverBuilder::new(name, input_path).build().await
+    }
+
+    /// Asynchronously waits for the next syn report to happen for the trackpad input device.
+    /// Returns whether the frame had any events that can move the pointer or press buttons
+    pub async fn await_sync_event(&mut self) -> Result<bool, MouseDriverUpdateError>{
+        let mut relevant = false;
         loop{
             match self.test_source.next_event().await {
                 Err(err) => {return Err(MouseDriverUpdateError::TestSourceReadError(err));},
-                Ok(event) => {if event.kind() == InputEventKind::Synchronization(Synchronization::SYN_REPORT) {return Ok(());}}
+                Ok(event) => {
+                    if event.kind() == InputEventKind::Synchronization(Synchronization::SYN_REPORT) {return Ok(relevant);}
+                    relevant |= is_pointer_relevant(event.kind());
+                }
             }
         }
     }
-    /// Poll function to update the mouse endlessly until it errors out
+    /// Waits for the next frame, or the sync timeout if one is set. Returns whether the frame is relevant like await_sync_event
+    async fn next_frame(&mut self) -> Result<bool, MouseDriverUpdateError> {
+        let sync_timeout_ms = self.movement.settings.sync_timeout_ms;
+        if sync_timeout_ms == 0 {
+            self.await_sync_event().await
+        } else {
+            // Without a SYN_REPORT in time the frame is assumed to be relevant, so libinput gets dispatched
+            tokio::time::timeout(Duration::from_millis(sync_timeout_ms), self.await_sync_event()).await.unwrap_or(Ok(true))
+        }
+    }
+    /// Handle used to ask the update loop to stop. The loop finishes its current frame, releases the buttons, and returns Stopped
+    pub fn stop_handle(&self) -> Arc<Notify> {
+        self.stop.clone()
+    }
+    /// Poll function to update the mouse endlessly until it errors out or is stopped
     pub async fn update_loop(&mut self) -> MouseDriverUpdateError {
+        let stop = self.stop.clone();
+        let injections = self.injections.clone();
+        let nudges = self.nudges.clone();
+        let rebinds = self.rebinds.clone();
         loop{
-            if let Err(err) = self.await_sync_event().await {return err;};
+            // Only waiting for a frame is interrupted, so a stop never lands in the middle of processing one
+            let held_back_delay = self.movement.held_back_delay(Instant::now());
+            let frame = tokio::select! {
+                frame = self.next_frame() => frame,
+                _ = stop.notified() => {return self.release_and_stop().await;}
+                // Notches carried over by discrete scrolling, scroll held back by the rate limit, and motion carried over by the speed cap
+                // are emitted even when no input arrives
+                _ = held_back_tick(held_back_delay) => {
+                    for events in self.movement.take_frames() {
+                        if let Err(err) = self.emit(&events).await {return err;}
+                    }
+                    continue;
+                }
+                Some(injection) = next_injection(&injections) => {
+                    if let Err(err) = self.inject(injection).await {return err;}
+                    continue;
+                }
+                // Nudges bypass the trackpad, the settings, and the lock, so they show whether the virtual device moves the cursor at all
+                Some((dx, dy)) = next_nudge(&nudges) => {
+                    let events = [
+                        InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, dx),
+                        InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, dy)
+                    ];
+                    if let Err(err) = self.emit(&events).await {return err;}
+                    continue;
+                }
+                Some(request) = next_rebind(&rebinds) => {
+                    let result = self.rebind(&request.input_path);
+                    let rebound = result.is_ok();
+                    let _ = request.reply.send(result);
+                    // Releases of buttons held on the old trackpad never arrive, so they are released here
+                    if rebound {
+                        let releases = [Key::BTN_LEFT, Key::BTN_RIGHT, Key::BTN_MIDDLE].map(|key| InputEvent::new(EventType::KEY, key.code(), 0));
+                        if let Err(err) = self.emit(&releases).await {return err;}
+                    }
+                    continue;
+                }
+            };
+            let relevant = match frame {
+                Ok(relevant) => relevant,
+                Err(err) => {return err;}
+            };
+            // A resting finger only reports pressure and contact size, which can't move the pointer, so dispatching is skipped
+            if !relevant && self.idle_frames < IDLE_FRAME_BATCH {
+                self.idle_frames += 1;
+                continue;
+            }
+            self.idle_frames = 0;
 
-            if let Err(err) = self.data_source.dispatch() {return MouseDriverUpdateError::DataSourceDispatchError(err);}
+            if let Err(err) = dispatch_with_retries(|| self.data_source.dispatch()).await {return err;}
+            // pick up any settings changed since the last frame
+            self.movement.settings = self.metadata.settings.lock().unwrap().clone();
+            // A turbo button unbound while held never sends its release, so the boost ends with the binding
+            if self.movement.settings.turbo_button.is_none() {self.movement.turbo_held = false;}
+            // Likewise a passthrough button unbound while held restores the lock right away
+            if self.movement.settings.passthrough_button.is_none() {self.end_passthrough();}
+            self.apply_accel_profile();
 
             let events: Vec<Event> = self.data_source.by_ref().collect();
+            let events = limit_queue(events, self.metadata.options.queue_depth, self.metadata.options.queue_policy);
+            if !events.is_empty() {
+                self.movement.reset_if_idle(Instant::now());
+            }
             for event in events{
+                // The lock toggle button toggles the lock instead of clicking. Only presses toggle and releases are swallowed,
+                // so one press is one toggle. Bouncing switches are already debounced by libinput
+                if let Event::Pointer(PointerEvent::Button(ev)) = &event {
+                    if Some(ev.button()) == self.movement.settings.lock_toggle_button {
+                        if ev.button_state() == ButtonState::Pressed {self.toggle_lock();}
+                        continue;
+                    }
+                    // The passthrough button unlocks the trackpad while held, and is never forwarded as a click
+                    if Some(ev.button()) == self.movement.settings.passthrough_button {
+                        if ev.button_state() == ButtonState::Pressed {self.start_passthrough();} else {self.end_passthrough();}
+                        continue;
+                    }
+                    // The turbo button boosts motion while held and is never forwarded as a click, releasing it restores the base sensitivity
+                    if Some(ev.button()) == self.movement.settings.turbo_button {
+                        self.movement.turbo_held = ev.button_state() == ButtonState::Pressed;
+                        continue;
+                    }
+                    let ignored = self.movement.settings.ignored_buttons.contains(&ev.button());
+                    if self.movement.settings.unknown_button_logging && !ignored && !KNOWN_BUTTONS.contains(&ev.button()) {
+                        self.log_unknown_button(ev.button(), ev.time_usec());
+                    }
+                }
+                // While unlocked or paused the trackpad is used directly, so everything is ignored but button releases and swipe ends,
+                // so buttons held when unlocking don't get stuck
+                let release = matches!(&event, Event::Pointer(PointerEvent::Button(ev)) if ev.button_state() == ButtonState::Released)
+                    || matches!(&event, Event::Gesture(GestureEvent::Swipe(GestureSwipeEvent::End(_))));
+                if (!self.metadata.is_locked() || self.paused.load(Ordering::Relaxed)) && !release {continue;}
                 self.movement.process_event(event);
             }
             // emit mouse events
             let events = self.movement.get_output_events();
             if events.len() > 0 {
-                if let Err(err) = self.output.emit(&events) {return MouseDriverUpdateError::EmitEventsError(err);}
+                if let Err(err) = self.emit(&events).await {return err;}
+            }
+            for events in self.movement.take_extra_frames() {
+                if let Err(err) = self.emit(&events).await {return err;}
             }
         }
     }  
+    /// Releases every button of the virtual mouse, so nothing is left held when the mouse goes away.
+    /// Releases of buttons that aren't pressed are dropped by the kernel, so all of them are released.
+    /// The trackpad isn't unlocked, whoever stops the mouse signals it to be enabled
+    async fn release_and_stop(&mut self) -> MouseDriverUpdateError {
+        let releases = [Key::BTN_LEFT, Key::BTN_RIGHT, Key::BTN_MIDDLE].map(|key| InputEvent::new(EventType::KEY, key.code(), 0));
+        if let Err(err) = self.emit(&releases).await {return err;}
+        MouseDriverUpdateError::Stopped
+    }
+    /// Switches to the trackpad at input_path, keeping the virtual devices, so whatever is bound to their event files keeps working.
+    /// The old trackpad is closed, and the movement state starts over. On failure the old trackpad is kept. Returns the updated info
+    fn rebind(&mut self, input_path: &str) -> Result<MouseInfo, MouseCreationError> {
+        let OpenedInput{data_source, device, input_id, test_source, resolution} = open_input(input_path)?;
+        self.test_source = test_source;
+        self.device = device;
+        self.data_source = data_source;
+        self.applied_accel_profile = None;
+        self.idle_frames = 0;
+        self.movement = MouseMovement{
+            resolution: resolution.map(|(x, y)| (x as f64, y as f64)),
+            scroll_key_map: self.movement.scroll_key_map,
+            soft_start: self.movement.soft_start,
+            settings: self.movement.settings.clone(),
+            ..Default::default()
+        };
+        self.metadata.input_id = input_id;
+        self.metadata.input_path = input_path.to_string();
+        self.metadata.resolution = resolution;
+        Ok(self.metadata.clone())
+    }
+    /// Configures libinput with the accel profile of the settings, if it changed since it was last applied.
+    /// Trackpads that don't support the profile keep their current one, and the failure is printed
+    fn apply_accel_profile(&mut self) {
+        let profile = self.movement.settings.accel_profile;
+        if self.applied_accel_profile == Some(profile) {return;}
+        self.applied_accel_profile = Some(profile);
+        if let Err(err) = self.device.config_accel_set_profile(profile.libinput_profile()) {
+            println!("Mouse {} could not use the {} accel profile: {:?}", self.metadata.name, profile.as_str(), err);
+        }
+    }
+    /// Prints a button code the mouse doesn't handle, at most once per UNKNOWN_BUTTON_LOG_INTERVAL_USEC for each code
+    fn log_unknown_button(&mut self, code: u32, time_usec: u64) {
+        if self.unknown_button_log.get(&code).is_some_and(|last| time_usec.saturating_sub(*last) < UNKNOWN_BUTTON_LOG_INTERVAL_USEC) {
+            return;
+        }
+        self.unknown_button_log.insert(code, time_usec);
+        println!("Mouse {} got unknown button code {}", self.metadata.name, code);
+    }
+    /// Locks the trackpad, signalling the session server to disable it, so only the virtual mouse uses it
+    pub fn lock(&mut self) {
+        self.set_locked(true);
+    }
+    /// Unlocks the trackpad, signalling the session server to enable it, so it is used directly and the virtual mouse ignores it
+    pub fn unlock(&mut self) {
+        self.set_locked(false);
+    }
+    /// Locks or unlocks the trackpad, signalling it if the lock changed. Nothing is signalled while the mice are paused,
+    /// their trackpads are enabled anyway, and ResumeAll only disables the locked ones again
+    fn set_locked(&mut self, locked: bool) {
+        if self.metadata.locked.swap(locked, Ordering::Relaxed) == locked || self.paused.load(Ordering::Relaxed) {return;}
+        let signal = if locked {TrackpadSignal::Disable(self.metadata.input_id)} else {TrackpadSignal::Enable(self.metadata.input_id)};
+        if let Some(sender) = &self.trackpad_signals {let _ = sender.send(signal);}
+    }
+    /// Locks the trackpad if it is unlocked, and unlocks it otherwise.
+    /// While the passthrough button is held the trackpad stays unlocked, and the toggle applies once the button is released
+    pub fn toggle_lock(&mut self) {
+        if let Some(locked) = self.passthrough_restore.as_mut() {
+            *locked = !*locked;
+            return;
+        }
+        if self.metadata.is_locked() {self.unlock();} else {self.lock();}
+    }
+    /// Unlocks the trackpad for as long as the passthrough button is held, remembering whether to lock it again.
+    /// Unlocking signals the trackpad to be enabled, so the user gets the physical trackpad back while holding the button.
+    /// A repeated press without a release keeps the first remembered state, so a lost release can't make the unlock stick
+    fn start_passthrough(&mut self) {
+        if self.passthrough_restore.is_none() {
+            self.passthrough_restore = Some(self.metadata.is_locked());
+        }
+        self.unlock();
+    }
+    /// Restores the lock the trackpad had before the passthrough button was pressed, signalling the trackpad to be disabled again
+    /// if it was locked. Nothing happens if it isn't held.
+    /// The release still arrives while the trackpad is enabled, xinput only enables it for X, and the mouse reads its event file
+    fn end_passthrough(&mut self) {
+        if let Some(locked) = self.passthrough_restore.take() {
+            self.set_locked(locked);
+        }
+    }
+    /// Processes a synthetic event like one from libinput, and sends back the events it made the mouse emit
+    #[cfg(feature = "testing")]
+    async fn inject(&mut self, (event, reply): Injection) -> Result<(), MouseDriverUpdateError> {
+        self.movement.process_synthetic(event);
+        let mut emitted = vec![];
+        for events in self.movement.take_frames() {
+            self.emit(&events).await?;
+            emitted.extend(events.iter().map(|event| (event.event_type().0, event.code(), event.value())));
+        }
+        // The caller may have given up waiting, which is fine
+        let _ = reply.send(emitted);
+        Ok(())
+    }
+    #[cfg(not(feature = "testing"))]
+    async fn inject(&mut self, injection: Injection) -> Result<(), MouseDriverUpdateError> {
+        match injection {}
+    }
+    /// Emits a frame on the virtual device and its mirrors
+    async fn emit(&mut self, events: &[InputEvent]) -> Result<(), MouseDriverUpdateError> {
+        emit_with_retries(&mut self.output, events).await?;
+        for mirror in self.mirrors.iter_mut() {
+            emit_with_retries(mirror, events).await?;
+        }
+        if let Some(tap) = self.event_tap.as_mut() {tap.write(events);}
+        self.metadata.throughput.lock().unwrap().record(events.len());
+        Ok(())
+    }
+}
+
+/// Applies the queue policy to the events of a frame once it has more than depth motion and scroll events.
+/// Everything else, like buttons and gestures, is always kept, and the kept events stay in order
+fn limit_queue(events: Vec<Event>, depth: u32, policy: QueuePolicy) -> Vec<Event> {
+    let droppable = |event: &Event| matches!(event, Event::Pointer(ev) if !matches!(ev, PointerEvent::Button(_)));
+    let depth = depth as usize;
+    let count = events.iter().filter(|event| droppable(event)).count();
+    if depth == 0 || count <= depth || policy == QueuePolicy::Coalesce {return events;}
+    let excess = count - depth;
+    let mut seen = 0;
+    events.into_iter().filter(|event| {
+        if !droppable(event) {return true;}
+        seen += 1;
+        match policy {
+            QueuePolicy::DropOldest => seen > excess,
+            QueuePolicy::DropNewest => seen <= depth,
+            QueuePolicy::Coalesce => true
+        }
+    }).collect()
+}
+
+/// Waits until held back scroll or motion has to be emitted, and forever while there is none
+async fn held_back_tick(delay: Option<Duration>) {
+    match delay {
+        Some(delay) => tokio::time::sleep(delay).await,
+        None => std::future::pending().await
+    }
+}
+
+/// Waits for the next synthetic event. Without the testing feature nothing is ever injected, so it waits forever
+#[cfg(feature = "testing")]
+async fn next_injection(injections: &InjectionReceiver) -> Option<Injection> {
+    injections.lock().await.recv().await
+}
+#[cfg(not(feature = "testing"))]
+async fn next_injection(_injections: &InjectionReceiver) -> Option<Injection> {
+    std::future::pending().await
+}
+
+/// Waits for the next trackpad the mouse is asked to switch to
+async fn next_rebind(rebinds: &tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<RebindRequest>>) -> Option<RebindRequest> {
+    rebinds.lock().await.recv().await
+}
+
+/// Waits for the next nudge sent to the mouse
+async fn next_nudge(nudges: &tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<(i32, i32)>>) -> Option<(i32, i32)> {
+    nudges.lock().await.recv().await
+}
+
+/// Dispatches libinput with dispatch. A dispatch that would block (EAGAIN) had nothing to read, which is fine. Dispatches interrupted
+/// by a signal (EINTR) are retried with a short backoff like emits, other errors and repeated interruptions are returned
+async fn dispatch_with_retries(mut dispatch: impl FnMut() -> std::io::Result<()>) -> Result<(), MouseDriverUpdateError> {
+    let mut backoff = Duration::from_millis(1);
+    let mut attempt = 0;
+    loop {
+        let err = match dispatch() {
+            Ok(()) => {return Ok(());}
+            Err(err) => err
+        };
+        match err.raw_os_error() {
+            Some(libc::EAGAIN) => {return Ok(());}
+            Some(libc::EINTR) if attempt < DISPATCH_RETRIES => {}
+            _ => {return Err(MouseDriverUpdateError::DataSourceDispatchError(err));}
+        }
+        attempt += 1;
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+/// Emits a frame on a virtual device. Writes that would block or were interrupted are retried with a short backoff,
+/// other errors (like ENODEV when the device is gone) and repeated failures are returned.
+/// The kernel timestamps events written to uinput when it receives them and ignores their own time, so the input's timing can't be carried through
+async fn emit_with_retries(device: &mut VirtualDevice, events: &[InputEvent]) -> Result<(), MouseDriverUpdateError> {
+    let mut backoff = Duration::from_millis(1);
+    let mut attempt = 0;
+    loop {
+        let err = match device.emit(events) {
+            Ok(()) => {return Ok(());}
+            Err(err) => err
+        };
+        let transient = matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted);
+        if !transient || attempt >= EMIT_RETRIES {return Err(MouseDriverUpdateError::EmitEventsError(err));}
+        attempt += 1;
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+/// Takes the scroll of a frame out of a scroll accumulator holding units_per_notch units per notch. In discrete mode that is at most
+/// one notch, carrying the rest into the following frames, otherwise everything up to the per frame maximum
+fn take_scroll(accumulator: &mut Accumulator, units_per_notch: u32, settings: &MouseSettings) -> Option<i32> {
+    if settings.discrete_scroll {
+        accumulator.take_limited(units_per_notch as i32, settings.fixed_point)
+    } else {
+        accumulator.take().map(|val| clamp_relative(val, settings.max_scroll_per_frame.saturating_mul(units_per_notch)))
+    }
+}
+
+/// Takes the motion of a frame out of a motion accumulator, at most the speed cap, carrying the rest into the following frames
+fn take_motion(accumulator: &mut Accumulator, settings: &MouseSettings) -> Option<i32> {
+    if settings.max_speed == 0 {
+        accumulator.take()
+    } else {
+        accumulator.take_limited(settings.max_speed.min(i32::MAX as u32) as i32, settings.fixed_point)
+    }
+}
+
+/// Clamps a relative value to at most limit in either direction, a limit of 0 doesn't clamp
+fn clamp_relative(value: i32, limit: u32) -> i32 {
+    if limit == 0 {return value;}
+    let limit = limit.min(i32::MAX as u32) as i32;
+    value.clamp(-limit, limit)
+}
+
+/// Accumulates the deltas of one relative axis, handing out whole units and carrying the sub unit remainder
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Accumulator{
+    /// Floating point accumulation, used by default
+    float: f64,
+    /// Fixed point accumulation in 1/FIXED_POINT_SCALE units, used when fixed point is enabled
+    fixed: i64
+}
+impl Accumulator{
+    /// Adds a delta, either as a float or rounded to the fixed point resolution. The fixed point value saturates instead of overflowing
+    pub fn add(&mut self, delta: f64, fixed_point: bool) {
+        if fixed_point {
+            self.fixed = self.fixed.saturating_add((delta*FIXED_POINT_SCALE as f64).round() as i64);
+        } else {
+            self.float += delta;
+        }
+    }
+    /// Takes the whole units out of the accumulator, keeping the remainder. Returns none if there isn't a whole unit.
+    /// More than an i32 can hold is clamped to the i32 range instead of wrapping around, and the rest is carried into the next take
+    pub fn take(&mut self) -> Option<i32> {
+        let whole = (self.fixed / FIXED_POINT_SCALE).saturating_add(self.float.trunc() as i64);
+        if whole == 0 {return None;}
+        let taken = whole.clamp(i32::MIN as i64, i32::MAX as i64);
+        self.fixed %= FIXED_POINT_SCALE;
+        self.float = self.float.fract() + (whole - taken) as f64;
+        Some(taken as i32)
+    }
+    /// Takes at most limit whole units out of the accumulator in either direction, keeping the rest
+    pub fn take_limited(&mut self, limit: i32, fixed_point: bool) -> Option<i32> {
+        let whole = self.take()?;
+        let taken = whole.clamp(-limit, limit);
+        // Whole units are exact in both representations, so putting the rest back doesn't lose anything
+        self.add((whole - taken) as f64, fixed_point);
+        Some(taken)
+    }
+    /// Takes everything out of the accumulator rounded to whole units, discarding the remainder. Returns none if that rounds to 0
+    pub fn flush(&mut self) -> Option<i32> {
+        let total = self.fixed as f64 / FIXED_POINT_SCALE as f64 + self.float;
+        self.clear();
+        Some(total.round() as i32).filter(|val| *val != 0)
+    }
+    /// Whether the accumulator holds at least one whole unit
+    pub fn has_whole(&self) -> bool {
+        (self.fixed / FIXED_POINT_SCALE).saturating_add(self.float.trunc() as i64) != 0
+    }
+    /// Discards the accumulated value
+    pub fn clear(&mut self) {
+        self.float = 0.0;
+        self.fixed = 0;
+    }
 }
 
 /// Struct containing Mouse tracking data
 #[derive(Default, Debug, Clone)]
 pub struct MouseMovement{
     /// Delta x of mouse pointer location since last event was sent
-    relx: f64,
+    relx: Accumulator,
     /// Delta y of mouse pointer location since last event was sent
-    rely: f64,
+    rely: Accumulator,
     /// Delta scroll of the mouse since the last event was sent
-    rel_scroll: f64,
-    /// Delta scroll of the mouse with high resolution (normal*120) since the last event was sent
-    rel_scroll_hr: f64,
+    rel_scroll: Accumulator,
+    /// Delta scroll of the mouse with high resolution (normal*hi_res_quantum) since the last event was sent
+    rel_scroll_hr: Accumulator,
     /// Delta horizontal scroll fo the mouse since the last event was sent
-    rel_hscroll: f64,
-    /// Delta horizontal scroll of the mouse with high resolution (normal*120) since the last event was sent
-    rel_hscroll_hr: f64,
+    rel_hscroll: Accumulator,
+    /// Delta horizontal scroll of the mouse with high resolution (normal*hi_res_quantum) since the last event was sent
+    rel_hscroll_hr: Accumulator,
     /// 0 if the left click has been released, 1 if pressed, none otherwise
     left_button_event: Option<i32>,
     /// 0 if the right click has been released, 1 if pressed, none otherwise
     right_button_event: Option<i32>,
     /// 0 if the middle click has been released, 1 if pressed, none otherwise
     middle_button_event: Option<i32>,
+    /// Last absolute position in millimeters and its time in microseconds, used to turn absolute motion into relative motion
+    last_absolute: Option<(f64, f64, u64)>,
+    /// Time in microseconds of the last scroll event, used for scroll acceleration
+    last_scroll_usec: Option<u64>,
+    /// When scroll was last emitted, used for the scroll rate limit
+    last_scroll_emit: Option<Instant>,
+    /// Time in microseconds of the last motion event, used for the linear accel profile
+    last_motion_usec: Option<u64>,
+    /// Time in microseconds of the last button press, used for click stabilization
+    last_press_usec: Option<u64>,
+    /// Time the last batch of events arrived, used to discard stale remainders after idling
+    last_event_time: Option<Instant>,
+    /// Whether the next relative motion is the first after idling, and gets clamped
+    clamp_next_motion: bool,
+    /// Finger count and time in microseconds of the current hold gesture's begin, used to detect taps
+    hold_start: Option<(i32, u64)>,
+    /// Time in microseconds the last single finger tap ended, while waiting for a second one to make a double tap
+    last_tap_end: Option<u64>,
+    /// Whether a three finger swipe is being turned into a drag, holding the left button
+    three_finger_dragging: bool,
+    /// Frames to emit right after the current one, for events that must not share a frame (a release after its press)
+    extra_frames: Vec<Vec<InputEvent>>,
+    /// Whether the turbo button is held, multiplying pointer motion by the turbo factor
+    pub turbo_held: bool,
+    /// Horizontal and vertical resolution of the input device in units per millimeter, used to normalize motion
+    pub resolution: Option<(f64, f64)>,
+    /// Keys scrolling is turned into while the scroll modifier is held, from the creation options
+    pub scroll_key_map: ScrollKeyMap,
+    /// When the soft start began and how long it ramps, none without one
+    pub soft_start: Option<(Instant, Duration)>,
+    /// Settings used when processing events
+    pub settings: MouseSettings
 }
 impl MouseMovement{
+    /// Factors horizontal and vertical pointer motion are multiplied by, the axis scales times the turbo factor while it is held,
+    /// normalized by the device resolution when that is on, and reduced while the soft start ramps up
+    fn motion_scale(&self) -> (f64, f64) {
+        let turbo = if self.turbo_held {self.settings.turbo_factor} else {1.0};
+        let (x_norm, y_norm) = match self.resolution {
+            Some((x, y)) if self.settings.normalize_by_resolution => (REFERENCE_RESOLUTION/x, REFERENCE_RESOLUTION/y),
+            _ => (1.0, 1.0)
+        };
+        let soft_start = self.soft_start_factor(Instant::now());
+        (self.settings.x_scale*turbo*x_norm*soft_start, self.settings.y_scale*turbo*y_norm*soft_start)
+    }
+    /// Factor pointer motion is multiplied by during the soft start, growing linearly from SOFT_START_FLOOR to 1 over its length
+    fn soft_start_factor(&self, now: Instant) -> f64 {
+        match self.soft_start {
+            Some((start, length)) if !length.is_zero() => {
+                let progress = (now.saturating_duration_since(start).as_secs_f64()/length.as_secs_f64()).min(1.0);
+                SOFT_START_FLOOR + (1.0 - SOFT_START_FLOOR)*progress
+            }
+            _ => 1.0
+        }
+    }
+    /// How long until scroll that was held back has to be emitted, either notches carried over by discrete scrolling or scroll waiting
+    /// for the rate limit. None when nothing is held back
+    pub fn scroll_delay(&self, now: Instant) -> Option<Duration> {
+        let notches_queued = self.settings.discrete_scroll && (self.rel_scroll.has_whole() || self.rel_hscroll.has_whole());
+        let rate_limited = self.settings.scroll_rate_limit_ms > 0
+            && [&self.rel_scroll, &self.rel_scroll_hr, &self.rel_hscroll, &self.rel_hscroll_hr].iter().any(|scroll| scroll.has_whole());
+        if !notches_queued && !rate_limited {return None;}
+        let notch_interval = if notches_queued {DISCRETE_SCROLL_INTERVAL} else {Duration::ZERO};
+        Some(notch_interval.max(self.scroll_rate_wait(now)))
+    }
+    /// How long until held back scroll or motion carried over by the speed cap has to be emitted, none when nothing is held back
+    pub fn held_back_delay(&self, now: Instant) -> Option<Duration> {
+        let motion_carried = self.settings.max_speed > 0 && (self.relx.has_whole() || self.rely.has_whole());
+        [self.scroll_delay(now), motion_carried.then_some(CARRIED_MOTION_INTERVAL)].into_iter().flatten().min()
+    }
+    /// How much longer the scroll rate limit holds scroll back
+    fn scroll_rate_wait(&self, now: Instant) -> Duration {
+        let interval = Duration::from_millis(self.settings.scroll_rate_limit_ms);
+        self.last_scroll_emit.map_or(Duration::ZERO, |last| interval.saturating_sub(now.saturating_duration_since(last)))
+    }
+    /// Queues a press or release of the left, right, or middle button, other button codes are ignored
+    pub fn set_button(&mut self, code: u32, pressed: bool) {
+        let value = Some(if pressed {1} else {0});
+        match code {
+            272 => {self.left_button_event = value;}
+            273 => {self.right_button_event = value;}
+            274 => {self.middle_button_event = value;}
+            _ => {}
+        };
+    }
+    /// Whether motion at the time in microseconds falls in the click stabilization window of the last button press
+    fn stabilizing_click(&self, time_usec: u64) -> bool {
+        let window = self.settings.click_stabilization_ms.saturating_mul(1000);
+        window > 0 && self.last_press_usec.is_some_and(|press| time_usec.saturating_sub(press) < window)
+    }
+    /// Adds unaccelerated motion in the units libinput reports, scaled like trackpad motion
+    #[cfg(any(test, feature = "testing"))]
+    pub fn add_motion(&mut self, dx: f64, dy: f64) {
+        let (x_scale, y_scale) = self.motion_scale();
+        self.relx.add(dx*x_scale, self.settings.fixed_point);
+        self.rely.add(dy*y_scale, self.settings.fixed_point);
+    }
+    /// Reads in a synthetic event like process_event does a libinput one
+    #[cfg(any(test, feature = "testing"))]
+    pub fn process_synthetic(&mut self, event: SyntheticEvent) {
+        match event {
+            SyntheticEvent::Motion{dx, dy} => self.add_motion(dx, dy),
+            SyntheticEvent::Button{code, pressed} => self.set_button(code, pressed),
+            SyntheticEvent::Scroll{vertical, horizontal} => self.accumulate_scroll(vertical, horizontal)
+        }
+    }
     /// Reads in an event, and updates the movement values accordingly
     pub fn process_event(&mut self, event: Event) {
         match event{
             Event::Pointer(PointerEvent::Motion(ev)) => {
-                self.relx += ev.dx();
-                self.rely += ev.dy();
+                if self.stabilizing_click(ev.time_usec()) {return;}
+                let (dx, dy) = self.clamp_first_motion(ev.dx(), ev.dy());
+                let (x_scale, y_scale) = self.motion_scale();
+                let factor = self.linear_accel_factor(ev.time_usec(), dx.hypot(dy));
+                self.relx.add(dx*x_scale*factor, self.settings.fixed_point);
+                self.rely.add(dy*y_scale*factor, self.settings.fixed_point);
+            },
+            Event::Pointer(PointerEvent::MotionAbsolute(ev)) => {
+                // Devices that only report absolute positions move by the difference to the last position.
+                // The first event of a contact has nothing to compare to, so it only records the position
+                let (x, y, time) = (ev.absolute_x(), ev.absolute_y(), ev.time_usec());
+                if let Some((last_x, last_y, last_time)) = self.last_absolute {
+                    // Motion dropped by click stabilization still moves the last position, so the pointer doesn't jump once the window ends
+                    if time.saturating_sub(last_time) < ABSOLUTE_CONTACT_GAP_USEC && !self.stabilizing_click(time) {
+                        let (x_scale, y_scale) = self.motion_scale();
+                        self.relx.add((x - last_x)*ABSOLUTE_UNITS_PER_MM*x_scale, self.settings.fixed_point);
+                        self.rely.add((y - last_y)*ABSOLUTE_UNITS_PER_MM*y_scale, self.settings.fixed_point);
+                    }
+                }
+                self.last_absolute = Some((x, y, time));
             },
             Event::Pointer(PointerEvent::Button(ev)) => {
-                match ev.button() {
-                    272 => {self.left_button_event = Some(match ev.button_state() {ButtonState::Pressed => 1, ButtonState::Released => 0});}
-                    273 => {self.right_button_event = Some(match ev.button_state() {ButtonState::Pressed => 1, ButtonState::Released => 0});}
-                    274 => {self.middle_button_event = Some(match ev.button_state() {ButtonState::Pressed => 1, ButtonState::Released => 0});}
-                    _ => {}
-                };
+                // Only presses are dropped, so a button held when it got ignored is still released
+                let pressed = ev.button_state() == ButtonState::Pressed;
+                if pressed && self.settings.ignored_buttons.contains(&ev.button()) {return;}
+                if pressed {self.last_press_usec = Some(ev.time_usec());}
+                self.set_button(ev.button(), pressed);
             },
-            Event::Pointer(PointerEvent::ScrollFinger(ev)) => {
+            // Scrolling as motion moves the pointer the way the fingers move, by libinput's scroll distance, which is in the units of pointer motion,
+            // scaled like motion. The scroll settings and the scroll accelerations don't apply to it, and nothing is scrolled
+            Event::Pointer(PointerEvent::ScrollFinger(ev)) if self.settings.scroll_as_motion => {
+                let (x_scale, y_scale) = self.motion_scale();
+                if ev.has_axis(input::event::pointer::Axis::Horizontal) {
+                    self.relx.add(ev.scroll_value(input::event::pointer::Axis::Horizontal)*x_scale, self.settings.fixed_point);
+                }
                 if ev.has_axis(input::event::pointer::Axis::Vertical) {
-                    self.rel_scroll += ev.scroll_value(input::event::pointer::Axis::Vertical)*-0.05;
-                    self.rel_scroll_hr += ev.scroll_value(input::event::pointer::Axis::Vertical)*120.0*-0.05;
+                    self.rely.add(ev.scroll_value(input::event::pointer::Axis::Vertical)*y_scale, self.settings.fixed_point);
                 }
-                if ev.has_axis(input::event::pointer::Axis::Horizontal) {
-                    self.rel_hscroll += ev.scroll_value(input::event::pointer::Axis::Horizontal)*-0.05;
-                    self.rel_hscroll_hr += ev.scroll_value(input::event::pointer::Axis::Horizontal)*120.0*-0.05;
+            },
+            Event::Pointer(PointerEvent::ScrollFinger(ev)) => {
+                let vertical = if ev.has_axis(input::event::pointer::Axis::Vertical) {
+                    ev.scroll_value(input::event::pointer::Axis::Vertical)*-0.05
+                } else {0.0};
+                let horizontal = if ev.has_axis(input::event::pointer::Axis::Horizontal) {
+                    ev.scroll_value(input::event::pointer::Axis::Horizontal)*-0.05
+                } else {0.0};
+                let factor = self.scroll_accel_factor(ev.time_usec(), vertical.hypot(horizontal));
+                self.accumulate_scroll(vertical*factor, horizontal*factor);
+            },
+            // libinput reports resting fingers as a hold gesture, with the finger count. Moving the fingers cancels the hold
+            // (and starts a swipe), so a short hold that ends without being cancelled is a tap
+            Event::Gesture(GestureEvent::Hold(GestureHoldEvent::Begin(ev))) => {
+                self.hold_start = Some((ev.finger_count(), ev.time_usec()));
+            },
+            Event::Gesture(GestureEvent::Hold(GestureHoldEvent::End(ev))) => {
+                if let Some((fingers, start)) = self.hold_start.take() {
+                    let tapped = !ev.cancelled() && ev.time_usec().saturating_sub(start) <= TAP_MAX_USEC;
+                    match fingers {
+                        1 => self.single_tap(tapped, start, ev.time_usec()),
+                        3 if tapped && self.settings.three_finger_middle_click => {
+                            self.middle_button_event = Some(1);
+                            self.extra_frames.push(vec![InputEvent::new(EventType::KEY, Key::BTN_MIDDLE.code(), 0)]);
+                        }
+                        _ => {}
+                    }
+                }
+            },
+            // A three finger swipe drags: the swipe's begin presses the left button, its motion moves the pointer, and its end
+            // (cancelled or not) releases the button. The setting is only checked at the begin, so a drag always gets its release
+            Event::Gesture(GestureEvent::Swipe(GestureSwipeEvent::Begin(ev))) if ev.finger_count() == 3 && self.settings.three_finger_drag => {
+                self.three_finger_dragging = true;
+                self.left_button_event = Some(1);
+            },
+            Event::Gesture(GestureEvent::Swipe(GestureSwipeEvent::Update(ev))) if self.three_finger_dragging => {
+                let (x_scale, y_scale) = self.motion_scale();
+                self.relx.add(ev.dx()*x_scale, self.settings.fixed_point);
+                self.rely.add(ev.dy()*y_scale, self.settings.fixed_point);
+            },
+            Event::Gesture(GestureEvent::Swipe(GestureSwipeEvent::End(_))) if self.three_finger_dragging => {
+                self.three_finger_dragging = false;
+                // A press that hasn't been emitted yet gets its release in the next frame, so the drag isn't lost
+                if self.left_button_event == Some(1) {
+                    self.extra_frames.push(vec![InputEvent::new(EventType::KEY, Key::BTN_LEFT.code(), 0)]);
+                } else {
+                    self.left_button_event = Some(0);
                 }
             },
             _ => {}
         };
     }
+    /// Handles the end of a single finger hold, emitting a double click when it is the second of two taps.
+    /// The second tap has to begin within the double tap interval after the first ended, so two slow taps stay separate,
+    /// and anything that isn't a tap in between (a cancelled or long hold) starts over
+    fn single_tap(&mut self, tapped: bool, start: u64, end: u64) {
+        if !tapped || !self.settings.double_tap {
+            self.last_tap_end = None;
+            return;
+        }
+        let interval = self.settings.double_tap_interval_ms.saturating_mul(1000);
+        match self.last_tap_end.take() {
+            Some(last_end) if start.saturating_sub(last_end) <= interval => {
+                // Two clean press and release pairs, each event in its own frame
+                self.left_button_event = Some(1);
+                self.extra_frames.push(vec![InputEvent::new(EventType::KEY, Key::BTN_LEFT.code(), 0)]);
+                self.extra_frames.push(vec![InputEvent::new(EventType::KEY, Key::BTN_LEFT.code(), 1)]);
+                self.extra_frames.push(vec![InputEvent::new(EventType::KEY, Key::BTN_LEFT.code(), 0)]);
+            }
+            _ => {self.last_tap_end = Some(end);}
+        }
+    }
+    /// Takes every frame to emit now, the one from get_output_events followed by the extra frames, leaving out empty ones
+    pub fn take_frames(&mut self) -> Vec<Vec<InputEvent>> {
+        let mut frames = vec![self.get_output_events()];
+        frames.extend(self.take_extra_frames());
+        frames.retain(|events| !events.is_empty());
+        frames
+    }
+    /// Takes the frames that should be emitted, each on its own, right after the frame from get_output_events
+    pub fn take_extra_frames(&mut self) -> Vec<Vec<InputEvent>> {
+        // The rules apply to these too, so a swapped press gets a swapped release
+        std::mem::take(&mut self.extra_frames).into_iter()
+            .map(|events| self.drop_disabled_presses(events))
+            .map(|events| self.apply_rules(events))
+            .filter(|events| !events.is_empty())
+            .collect()
+    }
+    /// Drops the button presses of taps and drags split into several frames while buttons are disabled, keeping their releases
+    fn drop_disabled_presses(&self, events: Vec<InputEvent>) -> Vec<InputEvent> {
+        if self.settings.buttons_enabled {return events;}
+        let buttons = [Key::BTN_LEFT.code(), Key::BTN_RIGHT.code(), Key::BTN_MIDDLE.code()];
+        events.into_iter().filter(|event| !(event.event_type() == EventType::KEY && buttons.contains(&event.code()) && event.value() != 0)).collect()
+    }
+    /// Applies the event rules to a frame, in order
+    fn apply_rules(&self, events: Vec<InputEvent>) -> Vec<InputEvent> {
+        self.settings.rules.iter().fold(events, |events, rule| rule.apply(events))
+    }
+    /// Shortens the first relative motion after idling to FIRST_MOTION_MAX, keeping its direction. Later motion passes unchanged
+    fn clamp_first_motion(&mut self, dx: f64, dy: f64) -> (f64, f64) {
+        if !std::mem::take(&mut self.clamp_next_motion) {return (dx, dy);}
+        let length = dx.hypot(dy);
+        if length <= FIRST_MOTION_MAX {return (dx, dy);}
+        (dx*FIRST_MOTION_MAX/length, dy*FIRST_MOTION_MAX/length)
+    }
+    /// Discards the sub unit motion and scroll remainders if no events arrived for the idle reset period, and marks the next motion
+    /// for clamping if none arrived for the first motion idle period. Should be called with the current time before processing a new batch of events
+    pub fn reset_if_idle(&mut self, now: Instant) {
+        let idle = self.last_event_time.replace(now).map(|last| now.duration_since(last));
+        if self.settings.clamp_first_motion && idle.is_some_and(|idle| idle >= Duration::from_millis(self.settings.first_motion_idle_ms)) {
+            self.clamp_next_motion = true;
+        }
+        if self.settings.idle_reset_ms == 0 {return;}
+        if idle.is_some_and(|idle| idle >= Duration::from_millis(self.settings.idle_reset_ms)) {
+            self.relx.clear();
+            self.rely.clear();
+            self.rel_scroll.clear();
+            self.rel_scroll_hr.clear();
+            self.rel_hscroll.clear();
+            self.rel_hscroll_hr.clear();
+        }
+    }
+    /// Returns the factor a scroll event should be scaled by, based on how fast scroll events are coming in.
+    /// Both axes and both resolutions are scaled by the same factor so the high resolution channels stay at hi_res_quantum per notch
+    pub fn scroll_accel_factor(&mut self, time_usec: u64, magnitude: f64) -> f64 {
+        let last = self.last_scroll_usec.replace(time_usec);
+        if !self.settings.scroll_accel {return 1.0;}
+        let elapsed = match last {
+            Some(last) if time_usec > last => (time_usec - last) as f64 / 1_000_000.0,
+            _ => {return 1.0;}
+        };
+        let speed = magnitude / elapsed;
+        (1.0 + speed*SCROLL_ACCEL_GAIN).min(SCROLL_ACCEL_MAX)
+    }
+    /// Returns the factor a motion event should be scaled by with the linear accel profile, based on the pointer speed.
+    /// libinput runs the flat profile underneath, so this is the only acceleration. Other profiles return 1
+    pub fn linear_accel_factor(&mut self, time_usec: u64, magnitude: f64) -> f64 {
+        let last = self.last_motion_usec.replace(time_usec);
+        if self.settings.accel_profile != AccelProfile::Linear {return 1.0;}
+        let elapsed = match last {
+            Some(last) if time_usec > last => (time_usec - last) as f64 / 1_000.0,
+            _ => {return 1.0;}
+        };
+        let speed = magnitude / elapsed;
+        (1.0 + speed*LINEAR_ACCEL_GAIN).min(LINEAR_ACCEL_MAX)
+    }
+    /// Adds scroll deltas (in notches) to the scroll accumulators, applying the scroll settings
+    pub fn accumulate_scroll(&mut self, mut vertical: f64, mut horizontal: f64) {
+        // The deadzone applies before the notches are split into the normal and high resolution channels,
+        // so the high resolution channels use the same threshold, scaled to hi_res_quantum per notch
+        if vertical.abs() < self.settings.scroll_deadzone {vertical = 0.0;}
+        if horizontal.abs() < self.settings.scroll_deadzone {horizontal = 0.0;}
+        // A scroll key map takes the modifier over, so the scroll isn't shifted away from the axis it maps
+        if self.settings.shift_to_hscroll && self.settings.modifier_held && self.scroll_key_map == ScrollKeyMap::Off {
+            horizontal += vertical;
+            vertical = 0.0;
+        }
+        // Disabling applies to the axis scrolled after shifting, so shift scrolling on a disabled horizontal axis does nothing
+        if !self.settings.vscroll_enabled {vertical = 0.0;}
+        if !self.settings.hscroll_enabled {horizontal = 0.0;}
+        let fixed_point = self.settings.fixed_point;
+        let quantum = self.settings.hi_res_quantum as f64;
+        self.rel_scroll.add(vertical, fixed_point);
+        self.rel_scroll_hr.add(vertical*quantum, fixed_point);
+        self.rel_hscroll.add(horizontal, fixed_point);
+        self.rel_hscroll_hr.add(horizontal*quantum, fixed_point);
+    }
+    /// Adds the scroll of a frame to it, or turns it into keys while the scroll modifier is held and a scroll key map is set.
+    /// With ctrl, Ctrl is pressed in the frame, the scroll follows in the next frame, and Ctrl is released in the one after, so the scroll lands
+    /// while Ctrl is down. With page, every vertical notch is a press and then a release of Page Up or Page Down, each in its own frame,
+    /// and the high resolution vertical scroll is dropped
+    fn map_scroll_to_keys(&mut self, scroll: Vec<InputEvent>, event_storage: &mut Vec<InputEvent>) {
+        let key_map = if self.settings.modifier_held {self.scroll_key_map} else {ScrollKeyMap::Off};
+        match key_map {
+            ScrollKeyMap::Off => event_storage.extend(scroll),
+            ScrollKeyMap::Ctrl => {
+                if scroll.is_empty() {return;}
+                event_storage.push(InputEvent::new(EventType::KEY, Key::KEY_LEFTCTRL.code(), 1));
+                self.extra_frames.push(scroll);
+                self.extra_frames.push(vec![InputEvent::new(EventType::KEY, Key::KEY_LEFTCTRL.code(), 0)]);
+            }
+            ScrollKeyMap::Page => for event in scroll {
+                if event.code() == RelativeAxisType::REL_WHEEL.0 {
+                    // Positive wheel values scroll up
+                    let key = if event.value() > 0 {Key::KEY_PAGEUP} else {Key::KEY_PAGEDOWN};
+                    for _ in 0..event.value().unsigned_abs() {
+                        self.extra_frames.push(vec![InputEvent::new(EventType::KEY, key.code(), 1)]);
+                        self.extra_frames.push(vec![InputEvent::new(EventType::KEY, key.code(), 0)]);
+                    }
+                } else if event.code() != RelativeAxisType::REL_WHEEL_HI_RES.0 {
+                    event_storage.push(event);
+                }
+            }
+        }
+    }
     /// reduce delta changes of the mouse, returning the list of input event containing the reduction
     pub fn get_output_events(&mut self) -> Vec<InputEvent>{
         let mut event_storage = Vec::with_capacity(8);
+        // Like a disabled scroll axis, a disabled channel drops what it accumulated instead of saving it for later
+        if !self.settings.motion_enabled {
+            self.relx.clear();
+            self.rely.clear();
+        }
+        if !self.settings.buttons_enabled {
+            self.left_button_event = self.left_button_event.filter(|val| *val == 0);
+            self.right_button_event = self.right_button_event.filter(|val| *val == 0);
+            self.middle_button_event = self.middle_button_event.filter(|val| *val == 0);
+        }
+        // The flushed motion goes before the buttons, so the press happens where the finger was
+        if self.settings.flush_motion_on_click && [self.left_button_event, self.right_button_event, self.middle_button_event].contains(&Some(1)) {
+            if let Some(val) = self.relx.flush(){
+                event_storage.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, val));
+            }
+            if let Some(val) = self.rely.flush(){
+                event_storage.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, val));
+            }
+        }
         if let Some(val) = self.left_button_event.take(){
             event_storage.push(InputEvent::new(EventType::KEY, Key::BTN_LEFT.code(), val));
         }
@@ -254,30 +1951,331 @@ impl MouseMovement{
         if let Some(val) = self.middle_button_event.take(){
             event_storage.push(InputEvent::new(EventType::KEY, Key::BTN_MIDDLE.code(), val));
         }
-        if self.rel_scroll.abs() >= 1.0 {
-            event_storage.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_WHEEL.0, self.rel_scroll.trunc() as i32));
-            self.rel_scroll = self.rel_scroll.fract();
+        // Scroll accumulated before an axis got disabled is dropped with it
+        if !self.settings.vscroll_enabled || !self.settings.scroll_enabled {
+            self.rel_scroll.clear();
+            self.rel_scroll_hr.clear();
         }
-        if self.rel_scroll_hr.abs() >= 1.0 {
-            event_storage.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_WHEEL_HI_RES.0, self.rel_scroll_hr.trunc() as i32));
-            self.rel_scroll_hr = self.rel_scroll_hr.fract();
+        if !self.settings.hscroll_enabled || !self.settings.scroll_enabled {
+            self.rel_hscroll.clear();
+            self.rel_hscroll_hr.clear();
         }
-        if self.rel_hscroll.abs() >= 1.0 {
-            event_storage.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_HWHEEL.0, self.rel_hscroll.trunc() as i32));
-            self.rel_hscroll = self.rel_hscroll.fract();
+        // Within the rate limit interval scroll stays in the accumulators, summing up until it is emitted
+        let now = Instant::now();
+        if self.scroll_rate_wait(now).is_zero() {
+            let mut scroll = Vec::with_capacity(4);
+            if let Some(val) = take_scroll(&mut self.rel_scroll, 1, &self.settings){
+                scroll.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_WHEEL.0, val));
+            }
+            // The high resolution channels are still taken when suppressed, so their leftovers don't come back when turned on again
+            if let Some(val) = take_scroll(&mut self.rel_scroll_hr, self.settings.hi_res_quantum, &self.settings).filter(|_| self.settings.hi_res_scroll){
+                scroll.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_WHEEL_HI_RES.0, val));
+            }
+            if let Some(val) = take_scroll(&mut self.rel_hscroll, 1, &self.settings){
+                scroll.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_HWHEEL.0, val));
+            }
+            if let Some(val) = take_scroll(&mut self.rel_hscroll_hr, self.settings.hi_res_quantum, &self.settings).filter(|_| self.settings.hi_res_scroll){
+                scroll.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_HWHEEL_HI_RES.0, val));
+            }
+            if !scroll.is_empty() {self.last_scroll_emit = Some(now);}
+            self.map_scroll_to_keys(scroll, &mut event_storage);
         }
-        if self.rel_hscroll_hr.abs() >= 1.0 {
-            event_storage.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_HWHEEL_HI_RES.0, self.rel_hscroll_hr.trunc() as i32));
-            self.rel_hscroll_hr = self.rel_hscroll_hr.fract();
+        if let Some(val) = take_motion(&mut self.relx, &self.settings){
+            event_storage.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, val));
+        }
+        if let Some(val) = take_motion(&mut self.rely, &self.settings){
+            event_storage.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, val));
+        }
+        return self.apply_rules(event_storage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds synthetic events to a movement, and returns the frames it emits afterwards as (type, code, value)
+    fn feed(movement: &mut MouseMovement, events: impl IntoIterator<Item = SyntheticEvent>) -> Vec<Vec<(u16, u16, i32)>> {
+        events.into_iter().for_each(|event| movement.process_synthetic(event));
+        movement.take_frames().into_iter()
+            .map(|events| events.iter().map(|event| (event.event_type().0, event.code(), event.value())).collect())
+            .collect()
+    }
+
+    const REL_X: (u16, u16) = (EventType::RELATIVE.0, RelativeAxisType::REL_X.0);
+    const REL_Y: (u16, u16) = (EventType::RELATIVE.0, RelativeAxisType::REL_Y.0);
+    const REL_WHEEL: (u16, u16) = (EventType::RELATIVE.0, RelativeAxisType::REL_WHEEL.0);
+    const REL_WHEEL_HI_RES: (u16, u16) = (EventType::RELATIVE.0, RelativeAxisType::REL_WHEEL_HI_RES.0);
+    const BTN_LEFT: (u16, u16) = (EventType::KEY.0, Key::BTN_LEFT.code());
+
+    #[test]
+    fn synthetic_events_drive_the_movement() {
+        let mut movement = MouseMovement::default();
+        let frames = feed(&mut movement, [
+            SyntheticEvent::Button{code: 272, pressed: true},
+            SyntheticEvent::Motion{dx: 3.0, dy: -2.0},
+            SyntheticEvent::Scroll{vertical: 1.0, horizontal: 0.0}
+        ]);
+        assert_eq!(frames, vec![vec![
+            (BTN_LEFT.0, BTN_LEFT.1, 1),
+            (REL_WHEEL.0, REL_WHEEL.1, 1),
+            (REL_WHEEL_HI_RES.0, REL_WHEEL_HI_RES.1, 120),
+            (REL_X.0, REL_X.1, 3),
+            (REL_Y.0, REL_Y.1, -2)
+        ]]);
+        // Nothing is left to emit
+        assert!(feed(&mut movement, []).is_empty());
+    }
+
+    #[tokio::test]
+    async fn dispatch_retries_interrupted_dispatches() {
+        let mut results = vec![Ok(()), Err(std::io::Error::from_raw_os_error(libc::EINTR)), Err(std::io::Error::from_raw_os_error(libc::EINTR))];
+        let result = dispatch_with_retries(|| results.pop().unwrap()).await;
+        assert!(result.is_ok());
+        assert!(results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dispatch_gives_up_after_repeated_interruptions() {
+        let mut calls = 0;
+        let result = dispatch_with_retries(|| {calls += 1; Err(std::io::Error::from_raw_os_error(libc::EINTR))}).await;
+        assert!(matches!(result, Err(MouseDriverUpdateError::DataSourceDispatchError(_))));
+        assert_eq!(calls, DISPATCH_RETRIES + 1);
+    }
+
+    #[tokio::test]
+    async fn dispatch_treats_would_block_as_nothing_to_read() {
+        let mut calls = 0;
+        let result = dispatch_with_retries(|| {calls += 1; Err(std::io::Error::from_raw_os_error(libc::EAGAIN))}).await;
+        assert!(result.is_ok());
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn dispatch_fails_on_other_errors() {
+        let result = dispatch_with_retries(|| Err(std::io::Error::from_raw_os_error(libc::ENODEV))).await;
+        assert!(matches!(result, Err(MouseDriverUpdateError::DataSourceDispatchError(_))));
+    }
+
+    #[test]
+    fn motion_carry_survives_a_button_frame() {
+        let mut movement = MouseMovement::default();
+        let frames = feed(&mut movement, [SyntheticEvent::Motion{dx: 0.6, dy: 0.0}, SyntheticEvent::Button{code: 272, pressed: true}]);
+        assert_eq!(frames, vec![vec![(BTN_LEFT.0, BTN_LEFT.1, 1)]]);
+        let frames = feed(&mut movement, [SyntheticEvent::Motion{dx: 0.6, dy: 0.0}]);
+        assert_eq!(frames, vec![vec![(REL_X.0, REL_X.1, 1)]]);
+        assert!((movement.relx.float - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn button_frames_keep_each_press_and_release() {
+        let mut movement = MouseMovement::default();
+        let frames = feed(&mut movement, [SyntheticEvent::Button{code: 273, pressed: true}, SyntheticEvent::Button{code: 274, pressed: true}]);
+        assert_eq!(frames, vec![vec![(EventType::KEY.0, Key::BTN_RIGHT.code(), 1), (EventType::KEY.0, Key::BTN_MIDDLE.code(), 1)]]);
+        // Unknown codes are ignored
+        assert!(feed(&mut movement, [SyntheticEvent::Button{code: 275, pressed: true}]).is_empty());
+        let frames = feed(&mut movement, [SyntheticEvent::Button{code: 273, pressed: false}]);
+        assert_eq!(frames, vec![vec![(EventType::KEY.0, Key::BTN_RIGHT.code(), 0)]]);
+    }
+
+    #[test]
+    fn motion_sums_until_a_whole_unit() {
+        let mut movement = MouseMovement::default();
+        assert!(feed(&mut movement, [SyntheticEvent::Motion{dx: 0.4, dy: -0.4}]).is_empty());
+        assert!(feed(&mut movement, [SyntheticEvent::Motion{dx: 0.4, dy: -0.4}]).is_empty());
+        let frames = feed(&mut movement, [SyntheticEvent::Motion{dx: 0.4, dy: -0.4}]);
+        assert_eq!(frames, vec![vec![(REL_X.0, REL_X.1, 1), (REL_Y.0, REL_Y.1, -1)]]);
+    }
+
+    #[test]
+    fn fixed_point_keeps_the_remainder() {
+        let mut accumulator = Accumulator::default();
+        accumulator.add(1.75, true);
+        assert_eq!(accumulator.take(), Some(1));
+        assert_eq!(accumulator.fixed, FIXED_POINT_SCALE*3/4);
+        accumulator.add(-2.5, true);
+        assert_eq!(accumulator.take(), Some(-1));
+        assert_eq!(accumulator.fixed, -FIXED_POINT_SCALE*3/4);
+        assert_eq!(accumulator.take(), None);
+    }
+
+    #[test]
+    fn fixed_point_sums_exactly() {
+        // Every tenth is rounded to 26/256 once, after that nothing is lost however often it is added and taken
+        let mut movement = MouseMovement::default();
+        movement.settings.fixed_point = true;
+        let mut total = 0;
+        for _ in 0..1_000_000 {
+            movement.process_synthetic(SyntheticEvent::Motion{dx: 0.1, dy: 0.0});
+            total += movement.take_frames().concat().iter().map(|event| event.value()).sum::<i32>();
         }
-        if self.relx.abs() >= 1.0 {
-            event_storage.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, self.relx.trunc() as i32));
-            self.relx = self.relx.fract();
+        assert_eq!(total, 101_562);
+        assert_eq!(movement.relx.fixed, 128);
+    }
+
+    #[test]
+    fn fixed_point_saturates() {
+        let mut accumulator = Accumulator::default();
+        accumulator.add(f64::MAX, true);
+        accumulator.add(f64::MAX, true);
+        assert_eq!(accumulator.fixed, i64::MAX);
+    }
+
+    #[test]
+    fn idle_reset_discards_stale_remainders() {
+        let mut movement = MouseMovement::default();
+        movement.settings.idle_reset_ms = 100;
+        let start = Instant::now();
+        movement.reset_if_idle(start);
+        feed(&mut movement, [SyntheticEvent::Motion{dx: 0.7, dy: 0.0}, SyntheticEvent::Scroll{vertical: 0.5, horizontal: 0.0}]);
+        // A short pause keeps the remainders
+        movement.reset_if_idle(start + Duration::from_millis(50));
+        assert!(movement.relx.float > 0.0 && movement.rel_scroll.float > 0.0);
+        // Idling for the reset period discards them, so the next motion starts from scratch
+        movement.reset_if_idle(start + Duration::from_millis(150));
+        assert_eq!(movement.relx.float, 0.0);
+        assert_eq!(movement.rel_scroll.float, 0.0);
+        assert_eq!(movement.rel_scroll_hr.float, 0.0);
+        assert!(feed(&mut movement, [SyntheticEvent::Motion{dx: 0.7, dy: 0.0}]).is_empty());
+    }
+
+    #[test]
+    fn idle_reset_off_keeps_remainders() {
+        let mut movement = MouseMovement::default();
+        let start = Instant::now();
+        movement.reset_if_idle(start);
+        feed(&mut movement, [SyntheticEvent::Motion{dx: 0.7, dy: 0.0}]);
+        movement.reset_if_idle(start + Duration::from_secs(3600));
+        assert_eq!(feed(&mut movement, [SyntheticEvent::Motion{dx: 0.7, dy: 0.0}]), vec![vec![(REL_X.0, REL_X.1, 1)]]);
+    }
+
+    #[test]
+    fn resting_fingers_are_not_pointer_relevant() {
+        let kind = |event_type: EventType, code: u16| InputEvent::new(event_type, code, 1).kind();
+        // What a resting finger sends every frame
+        for axis in [AbsoluteAxisType::ABS_MT_PRESSURE, AbsoluteAxisType::ABS_MT_TOUCH_MAJOR, AbsoluteAxisType::ABS_PRESSURE, AbsoluteAxisType::ABS_TOOL_WIDTH] {
+            assert!(!is_pointer_relevant(kind(EventType::ABSOLUTE, axis.0)), "{:?} is relevant", axis);
         }
-        if self.rely.abs() >= 1.0 {
-            event_storage.push(InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, self.rely.trunc() as i32));
-            self.rely = self.rely.fract();
+        assert!(!is_pointer_relevant(kind(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0)));
+        assert!(!is_pointer_relevant(kind(EventType::MISC, 5)));
+        // Moving and clicking
+        assert!(is_pointer_relevant(kind(EventType::ABSOLUTE, AbsoluteAxisType::ABS_MT_POSITION_X.0)));
+        assert!(is_pointer_relevant(kind(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0)));
+        assert!(is_pointer_relevant(kind(EventType::KEY, Key::BTN_LEFT.code())));
+        assert!(is_pointer_relevant(kind(EventType::KEY, Key::BTN_TOOL_FINGER.code())));
+    }
+
+    #[test]
+    fn first_motion_after_idling_is_clamped() {
+        let mut movement = MouseMovement::default();
+        movement.settings.clamp_first_motion = true;
+        movement.settings.first_motion_idle_ms = 500;
+        let start = Instant::now();
+        movement.reset_if_idle(start);
+        movement.reset_if_idle(start + Duration::from_millis(600));
+        // Shortened to FIRST_MOTION_MAX, keeping the direction
+        let (dx, dy) = movement.clamp_first_motion(30.0, -40.0);
+        assert!((dx - 2.4).abs() < 1e-9 && (dy + 3.2).abs() < 1e-9);
+        assert_eq!(feed(&mut movement, [SyntheticEvent::Motion{dx, dy}]), vec![vec![(REL_X.0, REL_X.1, 2), (REL_Y.0, REL_Y.1, -3)]]);
+        // Only the first motion is clamped
+        assert_eq!(movement.clamp_first_motion(30.0, -40.0), (30.0, -40.0));
+    }
+
+    #[test]
+    fn first_motion_clamp_needs_the_idle_period() {
+        let mut movement = MouseMovement::default();
+        movement.settings.clamp_first_motion = true;
+        let start = Instant::now();
+        movement.reset_if_idle(start);
+        movement.reset_if_idle(start + Duration::from_millis(100));
+        assert_eq!(movement.clamp_first_motion(30.0, -40.0), (30.0, -40.0));
+        // Small motion passes unchanged even after idling
+        movement.reset_if_idle(start + Duration::from_millis(700));
+        assert_eq!(movement.clamp_first_motion(1.0, 1.0), (1.0, 1.0));
+        // and with the setting off nothing is clamped
+        movement.settings.clamp_first_motion = false;
+        movement.reset_if_idle(start + Duration::from_secs(10));
+        assert_eq!(movement.clamp_first_motion(30.0, -40.0), (30.0, -40.0));
+    }
+
+    #[test]
+    fn hi_res_quantum_sets_the_units_per_notch() {
+        let mut movement = MouseMovement::default();
+        movement.settings.hi_res_quantum = 60;
+        let frames = feed(&mut movement, [SyntheticEvent::Scroll{vertical: 1.5, horizontal: 0.0}]);
+        assert_eq!(frames, vec![vec![(REL_WHEEL.0, REL_WHEEL.1, 1), (REL_WHEEL_HI_RES.0, REL_WHEEL_HI_RES.1, 90)]]);
+        // The half notch stays in the notched channel, the high resolution channel already emitted it
+        assert!((movement.rel_scroll.float - 0.5).abs() < 1e-9);
+        assert_eq!(movement.rel_scroll_hr.float, 0.0);
+        let frames = feed(&mut movement, [SyntheticEvent::Scroll{vertical: 0.5, horizontal: 0.0}]);
+        assert_eq!(frames, vec![vec![(REL_WHEEL.0, REL_WHEEL.1, 1), (REL_WHEEL_HI_RES.0, REL_WHEEL_HI_RES.1, 30)]]);
+    }
+
+    #[test]
+    fn discrete_scroll_takes_one_quantum_per_frame() {
+        let settings = MouseSettings{discrete_scroll: true, hi_res_quantum: 60, ..Default::default()};
+        let mut accumulator = Accumulator::default();
+        accumulator.add(150.0, false);
+        assert_eq!(take_scroll(&mut accumulator, settings.hi_res_quantum, &settings), Some(60));
+        assert_eq!(take_scroll(&mut accumulator, settings.hi_res_quantum, &settings), Some(60));
+        assert_eq!(take_scroll(&mut accumulator, settings.hi_res_quantum, &settings), Some(30));
+        assert_eq!(take_scroll(&mut accumulator, settings.hi_res_quantum, &settings), None);
+    }
+
+    #[test]
+    fn huge_deltas_are_clamped_and_carried() {
+        for fixed_point in [false, true] {
+            let mut accumulator = Accumulator::default();
+            accumulator.add(i32::MAX as f64 * 2.0 + 10.0, fixed_point);
+            assert_eq!(accumulator.take(), Some(i32::MAX));
+            assert_eq!(accumulator.take(), Some(i32::MAX));
+            assert_eq!(accumulator.take(), Some(10));
+            assert_eq!(accumulator.take(), None);
+            accumulator.add(-1e10, fixed_point);
+            assert_eq!(accumulator.take(), Some(i32::MIN));
         }
-        return event_storage;
+    }
+
+    #[test]
+    fn huge_motion_never_wraps_around() {
+        let mut movement = MouseMovement::default();
+        let frames = feed(&mut movement, [SyntheticEvent::Motion{dx: 1e15, dy: -1e15}]);
+        assert_eq!(frames, vec![vec![(REL_X.0, REL_X.1, i32::MAX), (REL_Y.0, REL_Y.1, i32::MIN)]]);
+    }
+
+    #[test]
+    fn flush_motion_on_click_moves_before_the_press() {
+        let mut movement = MouseMovement::default();
+        movement.settings.flush_motion_on_click = true;
+        let frames = feed(&mut movement, [SyntheticEvent::Motion{dx: 0.6, dy: -0.3}, SyntheticEvent::Button{code: 272, pressed: true}]);
+        // x rounds up to a pixel, y rounds to nothing, and both remainders are gone
+        assert_eq!(frames, vec![vec![(REL_X.0, REL_X.1, 1), (BTN_LEFT.0, BTN_LEFT.1, 1)]]);
+        assert_eq!(movement.relx.float, 0.0);
+        assert_eq!(movement.rely.float, 0.0);
+        // Releases don't flush
+        let frames = feed(&mut movement, [SyntheticEvent::Motion{dx: 0.6, dy: 0.0}, SyntheticEvent::Button{code: 272, pressed: false}]);
+        assert_eq!(frames, vec![vec![(BTN_LEFT.0, BTN_LEFT.1, 0)]]);
+    }
+
+    #[test]
+    fn clicks_keep_sub_pixel_motion_without_flushing() {
+        let mut movement = MouseMovement::default();
+        let frames = feed(&mut movement, [SyntheticEvent::Motion{dx: 0.6, dy: 0.0}, SyntheticEvent::Button{code: 272, pressed: true}]);
+        assert_eq!(frames, vec![vec![(BTN_LEFT.0, BTN_LEFT.1, 1)]]);
+        assert!((movement.relx.float - 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn discrete_scroll_emits_one_notch_per_frame() {
+        let mut movement = MouseMovement::default();
+        movement.settings.discrete_scroll = true;
+        let notch = vec![vec![(REL_WHEEL.0, REL_WHEEL.1, -1), (REL_WHEEL_HI_RES.0, REL_WHEEL_HI_RES.1, -120)]];
+        assert_eq!(feed(&mut movement, [SyntheticEvent::Scroll{vertical: -3.0, horizontal: 0.0}]), notch);
+        // The other two notches are queued, and the driver is asked to come back for them
+        assert_eq!(movement.scroll_delay(Instant::now()), Some(DISCRETE_SCROLL_INTERVAL));
+        assert_eq!(feed(&mut movement, []), notch);
+        assert_eq!(feed(&mut movement, []), notch);
+        assert_eq!(movement.scroll_delay(Instant::now()), None);
+        assert!(feed(&mut movement, []).is_empty());
     }
 }
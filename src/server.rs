@@ -1,21 +1,24 @@
-use std::{error::Error, fmt::Display, process, sync::{Arc, Mutex}};
-use dbus::{message::MatchRule, MethodErr, channel::MatchingReceiver};
-use dbus_crossroads::{Crossroads, IfaceBuilder};
+use std::{error::Error, fmt::Display, process, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+use dbus::{arg::{prop_cast, PropMap, Variant}, message::MatchRule, nonblock::{stdintf::org_freedesktop_dbus::RequestNameReply, Proxy, SyncConnection}, strings::BusName, MethodErr, Message, channel::{MatchingReceiver, Sender}};
+use dbus_crossroads::{Context, Crossroads, IfaceBuilder};
 use dbus_tokio::connection;
-use tokio::task;
-use crate::{communicator::{Communicator, CommunicatorResultFuture}, manager::MouseManager};
+use crate::{config::{self, MouseConfig, ServerConfig}, communicator::{CancelOutcome, Communicator, EventIdKind, CommunicatorPresenceFuture, CommunicatorResultFuture, rebind_mouse}, manager::MouseManager, socket, mouse::{AccelProfile, DeviceInfo, EventRule, SystemInfo, InputCounts, MouseCreationOptions, MouseInfo, MouseOwner, MouseSettings, QueuePolicy, ScrollKeyMap, TrackpadSignal, DEVICE_NAME_PREFIX}};
+#[cfg(feature = "testing")]
+use crate::mouse::SyntheticEvent;
 
 /// Error representing ways the server can fail
 #[derive(Debug)]
 pub enum ServerError{
     DBusConnectionFailed(dbus::Error),
-    ServerRequestNameFailed(dbus::Error)
+    ServerRequestNameFailed(dbus::Error),
+    ServerNameTaken
 }
 impl Display for ServerError{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let string = match self {
             ServerError::DBusConnectionFailed(err) => format!("Could not create system dbus connection. DBus error: {}", err),
-            ServerError::ServerRequestNameFailed(err) => format!("Could not aqcuire the dbus name, the server may already be running, or dbus permissions are not configured correctly. DBus Error: {:?}", err)
+            ServerError::ServerRequestNameFailed(err) => format!("Could not aqcuire the dbus name, the server may already be running, or dbus permissions are not configured correctly. DBus Error: {:?}", err),
+            ServerError::ServerNameTaken => format!("Could not aqcuire the dbus name, it is owned by another process, the server may already be running")
         };
         f.write_str(string.as_str())?;
         Ok(())
@@ -23,12 +26,381 @@ impl Display for ServerError{
 }
 impl Error for ServerError{}
 
+/// Introspection annotation used to document the interface, its methods, and its signals
+const DOC_STRING: &str = "org.gtk.GDBus.DocString";
+
+/// Methods supported by this server, returned by GetCapabilities so clients can avoid calling methods the server lacks
+const CAPABILITIES: &[&str] = &[
+    "CreateNewMouse",
+    "CreateNewMouseWithOptions",
+    "StopMouse",
+    "StopMouseById",
+    "CancelCreate",
+    "ListMice",
+    "ListMiceWithErrors",
+    "ListOwners",
+    "GetMouse",
+    "GetMirrorOutputs",
+    "ResolveMapping",
+    "GetThroughput",
+    "GetDeviceInfo",
+    "ProbeInput",
+    "Nudge",
+    "Rebind",
+    "ListAborted",
+    "WaitForMouse",
+    "GetProcessID",
+    "GetServerInfo",
+    "GetSystemInfo",
+    "GetCapabilities",
+    "GetServerConfig",
+    "ExportConfig",
+    "SaveProfile",
+    "DeleteProfile",
+    "ListProfiles",
+    "Shutdown",
+    "Reset",
+    "PauseAll",
+    "ResumeAll",
+    "SetShiftToHScroll",
+    "SetScrollModifier",
+    "SetScrollAccel",
+    "SetIdleReset",
+    "SetFixedPoint",
+    "SetThreeFingerMiddleClick",
+    "SetHiResScroll",
+    "SetAxisScale",
+    "SetLockToggleButton",
+    "SetSyncTimeout",
+    "SetScrollDeadzone",
+    "SetThreeFingerDrag",
+    "SetDoubleTap",
+    "SetUnknownButtonLogging",
+    "SetMaxScrollPerFrame",
+    "SetTurboButton",
+    "SetNormalizeByResolution",
+    "GetAccelProfile",
+    "SetAccelProfile",
+    "SetRules",
+    "SetDiscreteScroll",
+    "SetScrollRateLimit",
+    "SetFlushMotionOnClick",
+    "SetIgnoredButtons",
+    "CloneSettings",
+    "SetScrollAxes",
+    "SetMaxSpeed",
+    "SetScrollAsMotion",
+    "SetClickStabilization",
+    "SetHiResQuantum",
+    "SetChannels",
+    "SetPassthroughButton",
+    "SetClampFirstMotion"
+];
+
+impl ServerConfig{
+    /// The active configuration as a dict, as returned by GetServerConfig
+    pub fn to_prop_map(&self) -> PropMap {
+        let mut map = PropMap::new();
+        map.insert("bus-name".to_string(), Variant(Box::new(self.bus_name())));
+        if let Some(instance) = &self.instance {
+            map.insert("instance".to_string(), Variant(Box::new(instance.clone())));
+        }
+        map.insert("device-prefix".to_string(), Variant(Box::new(DEVICE_NAME_PREFIX.to_string())));
+        map.insert("allowed-uids".to_string(), Variant(Box::new(self.allowed_uids.clone())));
+        map.insert("acquire-retries".to_string(), Variant(Box::new(self.acquire_retries)));
+        map.insert("idle-timeout-secs".to_string(), Variant(Box::new(self.idle_timeout_secs)));
+        if let Some(socket) = &self.socket {
+            map.insert("socket".to_string(), Variant(Box::new(socket.to_string_lossy().to_string())));
+        }
+        map
+    }
+}
+
+impl DeviceInfo{
+    /// The device details as a dict, as returned by GetDeviceInfo
+    pub fn to_prop_map(&self) -> PropMap {
+        let mut map = PropMap::new();
+        map.insert("libinput-name".to_string(), Variant(Box::new(self.libinput_name.clone())));
+        map.insert("sysname".to_string(), Variant(Box::new(self.sysname.clone())));
+        map.insert("vendor-id".to_string(), Variant(Box::new(self.vendor_id)));
+        map.insert("product-id".to_string(), Variant(Box::new(self.product_id)));
+        map.insert("capabilities".to_string(), Variant(Box::new(self.capabilities.clone())));
+        map.insert("evdev-name".to_string(), Variant(Box::new(self.evdev_name.clone())));
+        map
+    }
+}
+
+impl SystemInfo{
+    /// The kernel interface versions as a dict, as returned by GetSystemInfo. Versions that couldn't be read are left out
+    pub fn to_prop_map(&self) -> PropMap {
+        let mut map = PropMap::new();
+        map.insert("kernel-release".to_string(), Variant(Box::new(self.kernel_release.clone())));
+        map.insert("uinput-present".to_string(), Variant(Box::new(self.uinput_present)));
+        if let Some(version) = self.uinput_version {
+            map.insert("uinput-version".to_string(), Variant(Box::new(version)));
+        }
+        if let Some(version) = &self.evdev_version {
+            map.insert("evdev-version".to_string(), Variant(Box::new(version.clone())));
+        }
+        map
+    }
+}
+
+impl TrackpadSignal{
+    /// The signal telling the session server to enable or disable the trackpad
+    pub fn to_message(&self) -> Message {
+        match self {
+            TrackpadSignal::Enable(input_id) => Message::signal(&"/".into(), &"com.cowsociety.virtual_mouse".into(), &"MouseDeleted".into())
+                .append1(*input_id),
+            TrackpadSignal::Disable(input_id) => Message::signal(&"/".into(), &"com.cowsociety.virtual_mouse".into(), &"MouseCreated".into())
+                .append1(*input_id)
+        }
+    }
+}
+
+/// Requests the server's dbus name, retrying with exponential backoff.
+/// During a service restart the old process may still be releasing the name, so the first attempts can fail
+async fn acquire_name(conn: &SyncConnection, bus_name: &str, retries: u32) -> Result<(), ServerError> {
+    let mut backoff = std::time::Duration::from_millis(100);
+    let mut attempt = 0;
+    loop {
+        let err = match conn.request_name(bus_name, false, false, true).await {
+            Ok(RequestNameReply::PrimaryOwner) | Ok(RequestNameReply::AlreadyOwner) => {return Ok(());}
+            Ok(_) => ServerError::ServerNameTaken,
+            Err(err) => ServerError::ServerRequestNameFailed(err)
+        };
+        if attempt >= retries {return Err(err);}
+        attempt += 1;
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff*2).min(std::time::Duration::from_secs(5));
+    }
+}
+
+/// Asks the bus for the unix user id of a message's sender
+async fn caller_uid(conn: Arc<SyncConnection>, sender: &BusName<'static>) -> Result<u32, dbus::Error> {
+    let proxy = Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", std::time::Duration::from_secs(2), conn);
+    let (uid,): (u32,) = proxy.method_call("org.freedesktop.DBus", "GetConnectionUnixUser", (sender.to_string(),)).await?;
+    Ok(uid)
+}
+
+/// Asks the bus whether a name, like a client's unique name, is still connected
+async fn name_has_owner(conn: Arc<SyncConnection>, name: &str) -> Result<bool, dbus::Error> {
+    let proxy = Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", std::time::Duration::from_secs(2), conn);
+    let (has_owner,): (bool,) = proxy.method_call("org.freedesktop.DBus", "NameHasOwner", (name,)).await?;
+    Ok(has_owner)
+}
+
+/// Asks the bus for the unix user id of a message's sender, and fails the method call if it isn't in the allowlist. Returns the user id
+async fn check_caller(conn: Arc<SyncConnection>, sender: Option<BusName<'static>>, allowed_uids: &[u32]) -> Result<u32, MethodErr> {
+    let sender = sender.ok_or_else(|| MethodErr::from(("org.freedesktop.DBus.Error.AccessDenied", "Message has no sender")))?;
+    let uid = caller_uid(conn, &sender).await
+        .map_err(|err| MethodErr::failed(&format!("Could not get the caller's user id: {}", err)))?;
+    if !allowed_uids.contains(&uid) {
+        return Err(MethodErr::from(("org.freedesktop.DBus.Error.AccessDenied", format!("User {} is not allowed to do this", uid))));
+    }
+    Ok(uid)
+}
+
+/// Longest argument, in characters, written to the audit log. Longer ones are cut off, so a huge argument can't flood the log
+const MAX_LOGGED_ARG_LEN: usize = 64;
+
+/// Formats the arguments of a method call for the audit log, cutting off long arguments.
+/// Strings are debug formatted, which quotes them and escapes control characters, so an argument can't forge log lines
+fn sanitize_args(msg: &Message) -> String {
+    msg.iter_init().map(|arg| {
+        let arg = format!("{:?}", arg);
+        if arg.chars().count() > MAX_LOGGED_ARG_LEN {
+            arg.chars().take(MAX_LOGGED_ARG_LEN).collect::<String>() + "..."
+        } else {arg}
+    }).collect::<Vec<String>>().join(", ")
+}
+
+/// Writes a method call of the server's interface to the audit log, with its arguments and the caller's unix user id.
+/// The uid lookup is a method call itself, so the entry is written from a spawned task, after the call may already be handled
+fn log_method_call(conn: Arc<SyncConnection>, msg: &Message) {
+    if msg.interface().as_deref() != Some("com.cowsociety.virtual_mouse") {return;}
+    let method = msg.member().map(|member| member.to_string()).unwrap_or_default();
+    let args = sanitize_args(msg);
+    let sender = msg.sender().map(|sender| sender.into_static());
+    tokio::spawn(async move {
+        let uid = match &sender {
+            Some(sender) => caller_uid(conn, sender).await.map(|uid| uid.to_string()).unwrap_or_else(|_| "unknown".to_string()),
+            None => "unknown".to_string()
+        };
+        println!("Audit: uid {} called {}({})", uid, method, args);
+    });
+}
+
+/// Describes a message for the log as interface.member from sender, with unknown for anything the message doesn't have
+fn describe_message(msg: &Message) -> String {
+    let interface = msg.interface().map(|interface| interface.to_string()).unwrap_or_else(|| "unknown".to_string());
+    let member = msg.member().map(|member| member.to_string()).unwrap_or_else(|| "unknown".to_string());
+    let sender = msg.sender().map(|sender| sender.to_string()).unwrap_or_else(|| "unknown".to_string());
+    format!("{}.{} from {}", interface, member, sender)
+}
+
+/// Reads the creation options of CreateNewMouseWithOptions, failing on unknown options or wrong types
+fn parse_creation_options(options: &PropMap) -> Result<MouseCreationOptions, MethodErr> {
+    let mut creation_options = MouseCreationOptions::default();
+    for key in options.keys() {
+        match key.as_str() {
+            "with-keyboard" => {
+                creation_options.with_keyboard = *prop_cast::<bool>(options, key).ok_or_else(|| MethodErr::invalid_arg(key))?;
+            }
+            "hi-res-scroll" => {
+                creation_options.hi_res_scroll = *prop_cast::<bool>(options, key).ok_or_else(|| MethodErr::invalid_arg(key))?;
+            }
+            "bus-type" => {
+                // Any unsigned integer type is accepted, as long as the value fits the 16 bit bus type
+                let bus_type = options[key].0.as_u64().and_then(|bus_type| u16::try_from(bus_type).ok());
+                creation_options.bus_type = Some(bus_type.ok_or_else(|| MethodErr::invalid_arg(key))?);
+            }
+            "mirrors" => {
+                let mirrors = options[key].0.as_u64().and_then(|mirrors| u32::try_from(mirrors).ok());
+                creation_options.mirrors = mirrors.ok_or_else(|| MethodErr::invalid_arg(key))?;
+            }
+            "queue-depth" => {
+                let depth = options[key].0.as_u64().and_then(|depth| u32::try_from(depth).ok());
+                creation_options.queue_depth = depth.ok_or_else(|| MethodErr::invalid_arg(key))?;
+            }
+            "queue-policy" => {
+                let policy = options[key].0.as_str().and_then(QueuePolicy::from_name);
+                creation_options.queue_policy = policy.ok_or_else(|| MethodErr::invalid_arg(&format!("{} must be {}", key, QueuePolicy::NAMES)))?;
+            }
+            "scroll-key-map" => {
+                let key_map = options[key].0.as_str().and_then(ScrollKeyMap::from_name);
+                creation_options.scroll_key_map = key_map.ok_or_else(|| MethodErr::invalid_arg(&format!("{} must be {}", key, ScrollKeyMap::NAMES)))?;
+            }
+            "profile" => {
+                let profile = options[key].0.as_str().ok_or_else(|| MethodErr::invalid_arg(key))?;
+                creation_options.profile = Some(profile.to_string());
+            }
+            "tie-to-caller" => {
+                creation_options.tie_to_caller = *prop_cast::<bool>(options, key).ok_or_else(|| MethodErr::invalid_arg(key))?;
+            }
+            "event-tap" => {
+                let path = options[key].0.as_str().ok_or_else(|| MethodErr::invalid_arg(key))?;
+                creation_options.event_tap = Some(path.to_string());
+            }
+            "lock-delay-ms" => {
+                creation_options.lock_delay_ms = options[key].0.as_u64().ok_or_else(|| MethodErr::invalid_arg(key))?;
+            }
+            "soft-start-ms" => {
+                creation_options.soft_start_ms = options[key].0.as_u64().ok_or_else(|| MethodErr::invalid_arg(key))?;
+            }
+            _ => {return Err(MethodErr::invalid_arg(key));}
+        }
+    }
+    Ok(creation_options)
+}
+
+/// Queues a mouse for creation and waits for the result, recording its owner and signalling MouseCreated on success
+async fn create_mouse(ctx: &mut Context, conn: Arc<SyncConnection>, data: Arc<Mutex<Communicator>>, name: String, path: String, options: MouseCreationOptions, owner: MouseOwner) -> Result<(String, u32, u32), MethodErr> {
+    let future = CommunicatorResultFuture{name: name.clone(), handle: data.clone()};
+    data.lock().unwrap().queue_mouse(name, path, options);
+    let info = future.await.map_err(|err| MethodErr::failed(&err.to_string()))?;
+    let info = data.lock().unwrap().set_owner(info, owner);
+    // A tied caller that disconnected while the mouse was being created was missed by the NameOwnerChanged match
+    if let Some(owner) = info.owner.as_ref().filter(|owner| info.options.tie_to_caller && !owner.client.is_empty()) {
+        if let Ok(false) = name_has_owner(conn.clone(), &owner.client).await {
+            if let Some(input_id) = data.lock().unwrap().stop_mouse(info.name.clone()) {
+                let signal = ctx.make_signal("MouseDeleted", (input_id,));
+                ctx.push_msg(signal);
+            }
+            return Err(MethodErr::failed(&format!("Mouse {} was stopped, its caller disconnected", info.name)));
+        }
+    }
+    if info.options.lock_delay_ms > 0 {
+        signal_created(conn, data, info.clone());
+    } else if !data.lock().unwrap().is_paused() {
+        // A mouse created while paused keeps its trackpad enabled, ResumeAll signals it with the others
+        let signal = ctx.make_signal("MouseCreated", (info.input_id,));
+        ctx.push_msg(signal);
+    }
+    Ok((info.name, info.input_id, info.output_id))
+}
+
+/// Signals MouseCreated for a mouse once its lock delay passed. Nothing is signalled if the mouse was stopped or unlocked in the meantime,
+/// so its trackpad is never disabled, or if the mice are paused, since ResumeAll signals it then
+fn signal_created(conn: Arc<SyncConnection>, data: Arc<Mutex<Communicator>>, info: MouseInfo) {
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(info.options.lock_delay_ms)).await;
+        let com = data.lock().unwrap();
+        if com.is_paused() || !com.is_running(&info) || !info.is_locked() {return;}
+        drop(com);
+        let signal = Message::signal(&"/".into(), &"com.cowsociety.virtual_mouse".into(), &"MouseCreated".into())
+            .append1(info.input_id);
+        let _ = conn.send(signal);
+    });
+}
+
+/// Queues the mice from the config for creation, and applies their settings once each one exists. created is called with every created mouse
+fn create_configured_mice(data: &Arc<Mutex<Communicator>>, mice: &[MouseConfig], created: impl Fn(MouseInfo) + Clone + Send + 'static) {
+    for mouse in mice.iter().cloned() {
+        let future = CommunicatorResultFuture{name: mouse.name.clone(), handle: data.clone()};
+        data.lock().unwrap().queue_mouse(mouse.name.clone(), mouse.path.clone(), mouse.options.clone());
+        let created = created.clone();
+        tokio::spawn(async move {
+            match future.await {
+                Ok(info) => {
+                    if let Some(settings) = mouse.settings {*info.settings.lock().unwrap() = settings;}
+                    created(info);
+                }
+                Err(err) => {println!("Failed to create mouse {} from the config: {}", mouse.name, err.to_string());}
+            }
+        });
+    }
+}
+
+/// Reads the settings of a profile from JSON, checking them like the settings of a config file
+pub fn parse_profile(name: &str, settings: &str) -> Result<MouseSettings, String> {
+    let settings: MouseSettings = serde_json::from_str(settings).map_err(|err| format!("Invalid settings: {}", err))?;
+    config::validate_settings(&settings, &format!("profile {}", name)).map_err(|err| err.to_string())?;
+    Ok(settings)
+}
+
+/// Runs a closure on the settings of the named mouse, failing the method call if the mouse does not exist
+fn with_settings<R>(data: &Arc<Mutex<Communicator>>, name: &str, f: impl FnOnce(&mut MouseSettings) -> R) -> Result<R, MethodErr> {
+    data.lock().unwrap().with_settings(name, f).ok_or_else(|| MethodErr::failed(&format!("No mouse named {}", name)))
+}
+
+/// Changes the settings of the named mouse, failing the method call if the mouse does not exist or the changed settings are invalid
+fn with_checked_settings(data: &Arc<Mutex<Communicator>>, name: &str, f: impl FnOnce(&mut MouseSettings)) -> Result<(), MethodErr> {
+    data.lock().unwrap().with_checked_settings(name, f)
+        .ok_or_else(|| MethodErr::failed(&format!("No mouse named {}", name)))?
+        .map_err(|err| MethodErr::invalid_arg(&err))
+}
+
+/// Whether the server runs as a systemd unit, which sets INVOCATION_ID in the environment of every process it starts
+pub fn under_systemd() -> bool {
+    std::env::var_os("INVOCATION_ID").is_some()
+}
 
 /// Server code
-pub async fn server() -> Result<(), Box<dyn Error>> {
+pub async fn server(config: ServerConfig) -> Result<(), Box<dyn Error>> {
     // Create mouse structures
+    // Logged first, so a server that fails on an unusual kernel still shows which one
+    println!("System: {}", SystemInfo::query().summary());
     let communicator = Arc::new(Mutex::new(Communicator::default()));
+    communicator.lock().unwrap().started_at = Some(SystemTime::now());
+    if let Some(path) = &config.profiles_file {
+        let mut com = communicator.lock().unwrap();
+        // A missing file is created once the first profile is saved
+        if path.exists() {com.profiles = config::load_profiles(path)?;}
+        com.profiles_file = Some(path.clone());
+    }
     let mut manager = MouseManager::new(communicator.clone());
+    if config.idle_timeout_secs > 0 {manager.set_idle_timeout(Some(Duration::from_secs(config.idle_timeout_secs)));}
+
+    // Serve a unix socket instead of DBus, for environments without a DBus daemon
+    if let Some(path) = config.socket.clone() {
+        create_configured_mice(&communicator, &config.mice, |_| {});
+        let socket_handle = socket::serve(&path, communicator.clone(), config)?;
+        manager.update_loop().await;
+        socket_handle.abort();
+        let _ = std::fs::remove_file(path);
+        return Ok(());
+    }
 
     // Setup DBus connection
     let (resource, conn) = connection::new_system_sync()
@@ -37,9 +409,30 @@ pub async fn server() -> Result<(), Box<dyn Error>> {
         resource.await
     });
 
-    // Grab dbus name, fails if already taken or not configured
-    conn.request_name("com.cowsociety.virtual_mouse", false, false, false).await
-        .map_err(|err| ServerError::ServerRequestNameFailed(err))?;
+    // Grab dbus name, fails if already taken or not configured. Named instances each own their own name
+    acquire_name(&conn, &config.bus_name(), config.acquire_retries).await?;
+
+    // Report mouse creation stages as they finish, the creation itself runs on the manager's task
+    let progress_conn = conn.clone();
+    manager.on_creation_progress(move |name, stage| {
+        let signal = Message::signal(&"/".into(), &"com.cowsociety.virtual_mouse".into(), &"MouseCreationProgress".into())
+            .append2(name, stage.as_str());
+        let _ = progress_conn.send(signal);
+    });
+
+    // Signal the trackpads of mice the manager removed without a method call, like aborted ones
+    let (trackpad_sender, mut trackpad_receiver) = tokio::sync::mpsc::unbounded_channel();
+    manager.set_trackpad_signals(trackpad_sender);
+    let trackpad_conn = conn.clone();
+    let trackpad_handle = tokio::spawn(async move {
+        while let Some(signal) = trackpad_receiver.recv().await {
+            let _ = trackpad_conn.send(signal.to_message());
+        }
+    });
+
+    // Create the mice from the config, signalling MouseCreated like CreateNewMouse does
+    let (created_conn, created_data) = (conn.clone(), communicator.clone());
+    create_configured_mice(&communicator, &config.mice, move |info| signal_created(created_conn.clone(), created_data.clone(), info));
 
     // Setup Crossroads for managing objects and interfaces
     let mut cr = Crossroads::new();
@@ -48,91 +441,563 @@ pub async fn server() -> Result<(), Box<dyn Error>> {
     
     // General Server commands
     let process_interface = cr.register("com.cowsociety.virtual_mouse", |b: &mut IfaceBuilder<Arc<Mutex<Communicator>>>| {
-        b.signal::<(u32,), _>("MouseCreated", ("input_id",));
-        b.signal::<(u32,), _>("MouseDeleted", ("input_id",));
+        b.annotate(DOC_STRING, "Creates, configures, and stops virtual mice that convert trackpad input into relative mouse events");
+        b.signal::<(u32,), _>("MouseCreated", ("input_id",))
+            .annotate(DOC_STRING, "A mouse was created from the input device with event id input_id, which should now be disabled");
+        b.signal::<(u32,), _>("MouseDeleted", ("input_id",))
+            .annotate(DOC_STRING, "A mouse was stopped, the input device with event id input_id should be enabled again");
+        b.signal::<(String, String), _>("MouseCreationProgress", ("name", "stage"))
+            .annotate(DOC_STRING, "A stage of creating the mouse called name finished: opened evdev, created virtual device, or resolved ids");
 
-        b.method_with_cr_async("CreateNewMouse", ("name", "input-path",), ("name", "input-event-id", "output-event-id"), |mut ctx, cr, (name, path,): (String, String,)| {
-            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap();
-            let future = CommunicatorResultFuture{name: name.clone(), handle: data.clone()};
-            let mut guard = data.lock().unwrap();
-            guard.queued_mice.insert(name.clone(), path.clone());
-            if let Some(waker) = guard.work_waker.take() {waker.wake();}
-            drop(guard);
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("CreateNewMouse", ("name", "input-path",), ("name", "input-event-id", "output-event-id"), move |mut ctx, cr, (name, path,): (String, String,)| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, create_conn, allowed_uids) = (check_conn.clone(), check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
             // Create a new mouse object
             async move {
-                match future.await{
-                    Ok(data) => {
-                        let signal = ctx.make_signal("MouseCreated", (data.input_id,));
-                        ctx.push_msg(signal);
-                        return ctx.reply(Ok((data.name, data.input_id, data.output_id)));
-                    },
-                    Err(err) => {
-                        return ctx.reply(Err(MethodErr::failed(&err.to_string())));
-                    }
+                let client = sender.as_ref().map(|sender| sender.to_string()).unwrap_or_default();
+                let uid = match check_caller(conn, sender, &allowed_uids).await {
+                    Ok(uid) => uid,
+                    Err(err) => {return ctx.reply(Err(err));}
+                };
+                let result = create_mouse(&mut ctx, create_conn, data, name, path, MouseCreationOptions::default(), MouseOwner{client, uid}).await;
+                ctx.reply(result)
+            }
+        }).annotate(DOC_STRING, "Creates a virtual mouse named name from the evdev device at input-path, returning the name and the input and output event ids");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("CreateNewMouseWithOptions", ("name", "input-path", "options",), ("name", "input-event-id", "output-event-id"), move |mut ctx, cr, (name, path, options,): (String, String, PropMap,)| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, create_conn, allowed_uids) = (check_conn.clone(), check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            let options = parse_creation_options(&options);
+            // Create a new mouse object
+            async move {
+                let client = sender.as_ref().map(|sender| sender.to_string()).unwrap_or_default();
+                let uid = match check_caller(conn, sender, &allowed_uids).await {
+                    Ok(uid) => uid,
+                    Err(err) => {return ctx.reply(Err(err));}
+                };
+                let result = match options {
+                    Ok(options) => create_mouse(&mut ctx, create_conn, data, name, path, options, MouseOwner{client, uid}).await,
+                    Err(err) => Err(err)
+                };
+                ctx.reply(result)
+            }
+        }).annotate(DOC_STRING, "Like CreateNewMouse, with creation options: with-keyboard (b) also advertises keyboard keys on the virtual device, hi-res-scroll (b) gives it high resolution scroll axes (default true), bus-type (q) sets the bus type it reports (default USB), mirrors (u) adds that many extra virtual devices every event is echoed to (default 0), lock-delay-ms (t) waits that long before signalling MouseCreated, skipping the signal if the mouse is stopped first (default 0), event-tap (s) copies every emitted frame to the named pipe at that path as lines of type:code:value triples, queue-depth (u) is the most motion and scroll events processed per frame (default 0, no limit), queue-policy (s) handles the ones beyond it: coalesce (default), drop-oldest, or drop-newest, buttons are never dropped, scroll-key-map (s) turns scrolling into keys while the scroll modifier is held: off (default), ctrl, or page, profile (s) starts the mouse with the settings of the named profile, failing if there is none, soft-start-ms (t) ramps the pointer sensitivity up from a fifth to the configured one over that long after creation (default 0, no ramp), tie-to-caller (b) stops the mouse once the calling connection disconnects (default false)");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("StopMouse", ("name",), (), move |mut ctx, cr, (name,): (String,)| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
                 }
+                let input_id = data.lock().unwrap().stop_mouse(name);
+                if let Some(input_id) = input_id {
+                    let signal = ctx.make_signal("MouseDeleted", (input_id,));
+                    ctx.push_msg(signal);
+                }
+                ctx.reply(Ok(()))
             }
-        });
-        b.method("StopMouse", ("name",), (), |ctx, data,  (name,): (String,)| {
-            let mut guard = data.lock().unwrap();
-            guard.dequeued_mice.insert(name.to_owned());
-            if let Some(info) = guard.current_mice.get(&name) {
-                let signal = ctx.make_signal("MouseDeleted", (info.input_id,));
-                ctx.push_msg(signal);
+        }).annotate(DOC_STRING, "Stops the virtual mouse named name");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("StopMouseById", ("kind", "id",), ("name",), move |mut ctx, cr, (kind, id,): (String, u32,)| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                let kind = match EventIdKind::from_name(&kind) {
+                    Some(kind) => kind,
+                    None => {return ctx.reply(Err(MethodErr::invalid_arg(&format!("Unknown event id kind {}, expected input or output", kind))));}
+                };
+                let mut com = data.lock().unwrap();
+                let name = match com.name_by_id(kind, id) {
+                    Some(name) => name,
+                    None => {return ctx.reply(Err(MethodErr::failed(&format!("NotFound: no mouse with {} event id {}", kind.as_str(), id))));}
+                };
+                let input_id = com.stop_mouse(name.clone());
+                drop(com);
+                if let Some(input_id) = input_id {
+                    let signal = ctx.make_signal("MouseDeleted", (input_id,));
+                    ctx.push_msg(signal);
+                }
+                ctx.reply(Ok((name,)))
             }
-            if let Some(waker) = guard.dequeue_waker.take() {waker.wake();}
-            Ok(())
-        });
-        b.method("ListMice", (), ("mice-list",), |_, data, ()| {
-            let guard = data.lock().unwrap();
-            let mut mice = vec![];
-            for (_, info) in guard.current_mice.iter(){
-                mice.push((info.name.clone(), info.input_id, info.output_id));
+        }).annotate(DOC_STRING, "Stops the virtual mouse with the event id, kind is input for the trackpad's id or output for the virtual device's (or a mirror's). Returns the mouse's name");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("CancelCreate", ("name",), ("cancelled",), move |mut ctx, cr, (name,): (String,)| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                let outcome = data.lock().unwrap().cancel_create(name);
+                if let CancelOutcome::Stopped(input_id) = outcome {
+                    let signal = ctx.make_signal("MouseDeleted", (input_id,));
+                    ctx.push_msg(signal);
+                }
+                ctx.reply(Ok((!matches!(outcome, CancelOutcome::NotFound),)))
             }
+        }).annotate(DOC_STRING, "Cancels creating the mouse named name: a queued one isn't created and its pending requests fail, one created in the last 30 seconds is stopped. Returns whether there was anything to cancel");
+        b.method("ListMice", (), ("mice-list",), |_, data, ()| {
             // Return list of Mice objects
-            Ok((mice,))
-        });
+            Ok((data.lock().unwrap().list_mice(),))
+        }).annotate(DOC_STRING, "Lists all active mice as (name, input event id, output event id, creation time as a unix timestamp), sorted by name");
+        b.method("ListMiceWithErrors", (), ("mice-list", "failure-list"), |_, data, ()| {
+            let com = data.lock().unwrap();
+            Ok((com.list_mice(), com.list_failures()))
+        }).annotate(DOC_STRING, "Lists all active mice like ListMice, and the creations that failed in the last 10 minutes, oldest first, as (name, error, failure time as a unix timestamp)");
+        b.method("ListOwners", (), ("owner-list",), |_, data, ()| {
+            Ok((data.lock().unwrap().list_owners(),))
+        }).annotate(DOC_STRING, "Lists the mice created by a client as (name, client, uid), sorted by name. The client is the caller's unique bus name, or socket for unix socket clients. Mice from the config file have no owner and aren't listed");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("GetDeviceInfo", ("input-path",), ("info",), move |mut ctx, _, (path,): (String,)| {
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            // The device is opened as the server's user, so it is limited to the users that can create mice
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                let info = DeviceInfo::query(&path).map_err(|err| MethodErr::failed(&err.to_string()));
+                ctx.reply(info.map(|info| (info.to_prop_map(),)))
+            }
+        }).annotate(DOC_STRING, "Opens the evdev device at input-path briefly and returns its details for bug reports: libinput-name, sysname, vendor-id, product-id, capabilities, and evdev-name");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("ProbeInput", ("input-path", "duration-secs",), ("frames", "key", "relative", "absolute", "other"), move |mut ctx, _, (path, duration,): (String, u32,)| {
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                let counts = InputCounts::probe(&path, Duration::from_secs(duration as u64)).await.map_err(|err| MethodErr::failed(&err.to_string()));
+                ctx.reply(counts.map(|counts| (counts.frames, counts.key, counts.relative, counts.absolute, counts.other)))
+            }
+        }).annotate(DOC_STRING, "Reads the evdev device at input-path for duration-secs (at most 60), alongside any mouse using it, and returns how many frames and key, relative, absolute, and other events it sent. Tells a device that sends nothing apart from a mouse that doesn't convert its input");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("Nudge", ("name", "dx", "dy",), (), move |mut ctx, cr, (name, dx, dy,): (String, i32, i32,)| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                let nudger = match data.lock().unwrap().current_mice.get(&name) {
+                    Some(info) => info.nudger.clone(),
+                    None => {return ctx.reply(Err(MethodErr::failed(&format!("No mouse named {}", name))));}
+                };
+                if nudger.send((dx, dy)).is_err() {
+                    return ctx.reply(Err(MethodErr::failed(&format!("Mouse {} is no longer running", name))));
+                }
+                ctx.reply(Ok(()))
+            }
+        }).annotate(DOC_STRING, "Emits one relative motion of dx, dy on the virtual device of the mouse named name, bypassing the trackpad, its settings, and the lock. Shows whether the virtual mouse moves the cursor at all");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("Rebind", ("name", "input-path",), ("input-event-id",), move |mut ctx, cr, (name, path,): (String, String,)| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                let (old_input_id, info) = match rebind_mouse(&data, &name, path).await {
+                    Ok(rebound) => rebound,
+                    Err(err) => {return ctx.reply(Err(MethodErr::failed(&err)));}
+                };
+                // The old trackpad is enabled again, and the new one disabled, unless the mice are paused or the trackpad is unlocked
+                // and both are enabled anyway
+                let signal = ctx.make_signal("MouseDeleted", (old_input_id,));
+                ctx.push_msg(signal);
+                if !data.lock().unwrap().is_paused() && info.is_locked() {
+                    let signal = ctx.make_signal("MouseCreated", (info.input_id,));
+                    ctx.push_msg(signal);
+                }
+                ctx.reply(Ok((info.input_id,)))
+            }
+        }).annotate(DOC_STRING, "Has the mouse named name read the trackpad at input-path instead, keeping its virtual devices and their event ids, so whatever uses them survives swapping the trackpad. Returns the new trackpad's event id. Signals MouseDeleted for the old trackpad and MouseCreated for the new one. On failure the old trackpad is kept");
+        b.method("ListAborted", (), ("aborted-list",), |_, data, ()| {
+            Ok((data.lock().unwrap().list_aborted(),))
+        }).annotate(DOC_STRING, "Lists the recently aborted mice, oldest first, as (name, error, abort time as a unix timestamp)");
+        b.method("GetMouse", ("name",), ("name", "input-event-id", "output-event-id", "created-at"), |_, data, (name,): (String,)| {
+            let com = data.lock().unwrap();
+            let info = com.current_mice.get(&name).ok_or_else(|| MethodErr::failed(&format!("No mouse named {}", name)))?;
+            Ok((info.name.clone(), info.input_id, info.output_id, info.created_at_unix()))
+        }).annotate(DOC_STRING, "Returns the event ids and creation time (as a unix timestamp) of the mouse named name");
+        b.method("ResolveMapping", ("input-event-id",), ("name", "output-event-id"), |_, data, (input_id,): (u32,)| {
+            data.lock().unwrap().resolve_mapping(input_id).ok_or_else(|| MethodErr::failed(&format!("No mouse reads input event {}", input_id)))
+        }).annotate(DOC_STRING, "Returns the name and output event id of the mouse reading the trackpad with event id input-event-id, failing if no mouse reads it");
+        b.method("GetMirrorOutputs", ("name",), ("mirror-output-event-ids",), |_, data, (name,): (String,)| {
+            let com = data.lock().unwrap();
+            let info = com.current_mice.get(&name).ok_or_else(|| MethodErr::failed(&format!("No mouse named {}", name)))?;
+            Ok((info.mirror_output_ids.clone(),))
+        }).annotate(DOC_STRING, "Returns the event ids of the mirror output devices of the mouse named name, in mirror order");
+        b.method("GetThroughput", ("name",), ("emits-per-sec", "events-per-sec"), |_, data, (name,): (String,)| {
+            let com = data.lock().unwrap();
+            let info = com.current_mice.get(&name).ok_or_else(|| MethodErr::failed(&format!("No mouse named {}", name)))?;
+            let rates = info.throughput.lock().unwrap().rates();
+            Ok(rates)
+        }).annotate(DOC_STRING, "Returns how many frames and events (not counting the sync events ending the frames) the mouse named name emitted per second, averaged over the last 5 seconds");
+        b.method_with_cr_async("WaitForMouse", ("name", "timeout-seconds",), ("name", "input-event-id", "output-event-id"), |mut ctx, cr, (name, timeout,): (String, u32,)| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap();
+            let future = CommunicatorPresenceFuture{name: name.clone(), com: data.clone()};
+            // Wait for the mouse to exist
+            async move {
+                match tokio::time::timeout(std::time::Duration::from_secs(timeout as u64), future).await {
+                    Ok(info) => ctx.reply(Ok((info.name, info.input_id, info.output_id))),
+                    Err(_) => ctx.reply(Err(MethodErr::failed(&format!("Timed out waiting for mouse {}", name))))
+                }
+            }
+        }).annotate(DOC_STRING, "Waits up to timeout-seconds for a mouse named name to exist, returning its name and event ids");
         b.method("GetProcessID", (), ("pid",), |_, _, ()| {
             // Return the server's process id
             Ok((process::id(),))
-        });
-        b.method("Shutdown", (), (), |ctx, data, ()| {
-            let mut guard = data.lock().unwrap();
-            for info in guard.current_mice.values() {
-                let signal = ctx.make_signal("MouseDeleted", (info.input_id,));
-                ctx.push_msg(signal);
+        }).annotate(DOC_STRING, "Returns the process id of the server");
+        b.method("GetServerInfo", (), ("pid", "systemd", "started-at", "mice"), |_, data, ()| {
+            let com = data.lock().unwrap();
+            Ok((process::id(), under_systemd(), com.started_at_unix(), com.current_mice.len() as u32))
+        }).annotate(DOC_STRING, "Returns the process id of the server, whether systemd runs it, when it started as a unix timestamp, and how many mice are active");
+        b.method("GetSystemInfo", (), ("info",), |_, _, ()| {
+            Ok((SystemInfo::query().to_prop_map(),))
+        }).annotate(DOC_STRING, "Returns the versions of the kernel interfaces the server uses, for bug reports: kernel-release, uinput-present, and uinput-version and evdev-version when they could be read");
+        b.method("GetCapabilities", (), ("capabilities",), |_, _, ()| {
+            Ok((CAPABILITIES.iter().map(|method| method.to_string()).collect::<Vec<String>>(),))
+        }).annotate(DOC_STRING, "Returns the names of the methods this server supports");
+        let server_config = config.clone();
+        b.method("GetServerConfig", (), ("config",), move |_, _, ()| {
+            Ok((server_config.to_prop_map(),))
+        }).annotate(DOC_STRING, "Returns the active server wide configuration: bus-name, device-prefix, allowed-uids, acquire-retries, idle-timeout-secs, and socket when one is used");
+        b.method("ExportConfig", ("toml",), ("config",), |_, data, (toml,): (bool,)| {
+            let mice = data.lock().unwrap().mouse_configs();
+            let config = config::export_mice(mice, toml).map_err(|err| MethodErr::failed(&err.to_string()))?;
+            Ok((config,))
+        }).annotate(DOC_STRING, "Returns the current mice and their settings as a config file (TOML if toml is true, JSON otherwise) that can be loaded with --server --config");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("Shutdown", (), (), move |mut ctx, cr, ()| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                let input_ids = data.lock().unwrap().request_shutdown();
+                for input_id in input_ids {
+                    let signal = ctx.make_signal("MouseDeleted", (input_id,));
+                    ctx.push_msg(signal);
+                }
+                ctx.reply(Ok(()))
             }
-            guard.shutdown.0 = true;
-            if let Some(waker) = guard.shutdown.1.take() {waker.wake();}
-            Ok(())
-        });
-        b.method("Reset", (), (), |ctx, data, ()| {
-            let mut guard = data.lock().unwrap();
-            let names: Vec<String> = guard.current_mice.keys().cloned().collect();
-            guard.dequeued_mice.extend(names);
-            for info in guard.current_mice.values() {
-                let signal = ctx.make_signal("MouseDeleted", (info.input_id,));
-                ctx.push_msg(signal);
+        }).annotate(DOC_STRING, "Stops all mice and exits the server");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("Reset", (), (), move |mut ctx, cr, ()| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                let input_ids = data.lock().unwrap().stop_all_mice();
+                for input_id in input_ids {
+                    let signal = ctx.make_signal("MouseDeleted", (input_id,));
+                    ctx.push_msg(signal);
+                }
+                ctx.reply(Ok(()))
             }
-            if let Some(waker) = guard.dequeue_waker.take() {waker.wake();}
-            Ok(())
-        });
+        }).annotate(DOC_STRING, "Stops all mice without exiting the server");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("PauseAll", (), (), move |mut ctx, cr, ()| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                // MouseDeleted has the session server enable the physical trackpads again while the mice are paused
+                let input_ids = data.lock().unwrap().set_paused(true);
+                for input_id in input_ids {
+                    let signal = ctx.make_signal("MouseDeleted", (input_id,));
+                    ctx.push_msg(signal);
+                }
+                ctx.reply(Ok(()))
+            }
+        }).annotate(DOC_STRING, "Pauses all mice, they ignore their trackpads, which are enabled again, until ResumeAll");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("ResumeAll", (), (), move |mut ctx, cr, ()| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                let input_ids = data.lock().unwrap().set_paused(false);
+                for input_id in input_ids {
+                    let signal = ctx.make_signal("MouseCreated", (input_id,));
+                    ctx.push_msg(signal);
+                }
+                ctx.reply(Ok(()))
+            }
+        }).annotate(DOC_STRING, "Resumes all mice paused by PauseAll, disabling their trackpads again unless they are unlocked");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("SaveProfile", ("name", "settings",), (), move |mut ctx, cr, (name, settings,): (String, String,)| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                if name.is_empty() {
+                    return ctx.reply(Err(MethodErr::invalid_arg("Profile names can't be empty")));
+                }
+                let settings = match parse_profile(&name, &settings) {
+                    Ok(settings) => settings,
+                    Err(err) => {return ctx.reply(Err(MethodErr::invalid_arg(&err)));}
+                };
+                let saved = data.lock().unwrap().save_profile(name, settings);
+                ctx.reply(saved.map_err(|err| MethodErr::failed(&err)))
+            }
+        }).annotate(DOC_STRING, "Stores the settings, a JSON object of the settings fields like the settings of a config file's mice (missing fields keep their defaults), as the profile named name, replacing any profile with that name. Mice created with the profile creation option start with them");
+        let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+        b.method_with_cr_async("DeleteProfile", ("name",), ("deleted",), move |mut ctx, cr, (name,): (String,)| {
+            let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+            let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+            let sender = ctx.message().sender().map(|sender| sender.into_static());
+            async move {
+                if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                    return ctx.reply(Err(err));
+                }
+                let deleted = data.lock().unwrap().delete_profile(&name);
+                ctx.reply(deleted.map(|deleted| (deleted,)).map_err(|err| MethodErr::failed(&err)))
+            }
+        }).annotate(DOC_STRING, "Deletes the profile named name, returns whether it existed. Mice created with it keep their settings");
+        b.method("ListProfiles", (), ("names",), |_, data, ()| {
+            Ok((data.lock().unwrap().list_profiles(),))
+        }).annotate(DOC_STRING, "Lists the names of the profiles, sorted");
+        b.method("SetShiftToHScroll", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.shift_to_hscroll = enabled)
+        }).annotate(DOC_STRING, "Sets whether the mouse turns vertical scroll into horizontal scroll while the scroll modifier is held");
+        b.method("SetScrollModifier", ("name", "held",), (), |_, data, (name, held,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.modifier_held = held)
+        }).annotate(DOC_STRING, "Sets whether the scroll modifier of the mouse is held");
+        b.method("SetScrollAccel", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.scroll_accel = enabled)
+        }).annotate(DOC_STRING, "Sets whether fast scrolling of the mouse scrolls farther than slow scrolling");
+        b.method("SetIdleReset", ("name", "milliseconds",), (), |_, data, (name, ms,): (String, u64,)| {
+            with_settings(data, &name, |settings| settings.idle_reset_ms = ms)
+        }).annotate(DOC_STRING, "Sets after how many idle milliseconds the mouse discards leftover sub unit motion, 0 disables it");
+        b.method("SetFixedPoint", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.fixed_point = enabled)
+        }).annotate(DOC_STRING, "Sets whether the mouse accumulates motion as fixed point integers instead of floats");
+        b.method("SetThreeFingerMiddleClick", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.three_finger_middle_click = enabled)
+        }).annotate(DOC_STRING, "Sets whether a quick three finger tap on the trackpad clicks the middle button");
+        b.method("SetHiResScroll", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            data.lock().unwrap().set_hi_res_scroll(&name, enabled)
+                .ok_or_else(|| MethodErr::failed(&format!("No mouse named {}", name)))?
+                .map_err(|err| MethodErr::failed(&err))
+        }).annotate(DOC_STRING, "Sets whether the mouse emits high resolution scroll events. Only suppresses them, the axes are removed with the hi-res-scroll creation option, and mice created without them can't turn them on");
+        b.method("SetAxisScale", ("name", "x", "y",), (), |_, data, (name, x, y,): (String, f64, f64,)| {
+            with_checked_settings(data, &name, |settings| {settings.x_scale = x; settings.y_scale = y;})
+        }).annotate(DOC_STRING, "Sets the factors horizontal (x) and vertical (y) motion of the mouse are multiplied by, both 1.0 by default. Both have to be positive");
+        b.method("SetLockToggleButton", ("name", "button",), (), |_, data, (name, button,): (String, u32,)| {
+            with_settings(data, &name, |settings| settings.lock_toggle_button = Some(button).filter(|button| *button != 0))
+        }).annotate(DOC_STRING, "Sets the button code (e.g. 274 for the middle button) that toggles the trackpad lock instead of clicking, 0 disables it. Unlocking signals MouseDeleted, so the trackpad is enabled and used directly while the virtual mouse ignores it, and locking signals MouseCreated");
+        b.method("SetSyncTimeout", ("name", "timeout-ms",), (), |_, data, (name, ms,): (String, u64,)| {
+            with_settings(data, &name, |settings| settings.sync_timeout_ms = ms)
+        }).annotate(DOC_STRING, "Sets after how many milliseconds without a SYN_REPORT the mouse dispatches libinput anyway, 0 waits forever");
+        b.method("SetScrollDeadzone", ("name", "deadzone",), (), |_, data, (name, deadzone,): (String, f64,)| {
+            with_checked_settings(data, &name, |settings| settings.scroll_deadzone = deadzone)
+        }).annotate(DOC_STRING, "Sets below how many notches the scroll value of a single event is ignored, per axis, 0 disables it. Can't be negative");
+        b.method("SetThreeFingerDrag", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.three_finger_drag = enabled)
+        }).annotate(DOC_STRING, "Sets whether a three finger swipe on the trackpad drags, holding the left button while moving the pointer");
+        b.method("SetDoubleTap", ("name", "enabled", "interval-ms",), (), |_, data, (name, enabled, interval_ms,): (String, bool, u64,)| {
+            with_settings(data, &name, |settings| {settings.double_tap = enabled; settings.double_tap_interval_ms = interval_ms;})
+        }).annotate(DOC_STRING, "Sets whether two quick single finger taps emit a double click, and the longest time in milliseconds between the taps");
+        b.method("SetUnknownButtonLogging", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.unknown_button_logging = enabled)
+        }).annotate(DOC_STRING, "Sets whether the server prints button codes of the trackpad that aren't turned into clicks");
+        b.method("SetMaxScrollPerFrame", ("name", "notches",), (), |_, data, (name, notches,): (String, u32,)| {
+            with_settings(data, &name, |settings| settings.max_scroll_per_frame = notches)
+        }).annotate(DOC_STRING, "Sets the most notches a mouse scrolls per frame on each axis, 0 doesn't clamp");
+        b.method("SetTurboButton", ("name", "button", "factor",), (), |_, data, (name, button, factor,): (String, u32, f64,)| {
+            with_settings(data, &name, |settings| {settings.turbo_button = Some(button).filter(|button| *button != 0); settings.turbo_factor = factor;})
+        }).annotate(DOC_STRING, "Sets the button code that multiplies pointer motion by factor while held instead of clicking, 0 disables it");
+        b.method("SetNormalizeByResolution", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.normalize_by_resolution = enabled)
+        }).annotate(DOC_STRING, "Sets whether the mouse scales pointer motion by the trackpad's resolution, trackpads that don't report one are unaffected");
+        b.method("GetAccelProfile", ("name",), ("profile",), |_, data, (name,): (String,)| {
+            with_settings(data, &name, |settings| (settings.accel_profile.as_str().to_string(),))
+        }).annotate(DOC_STRING, "Returns the name of the accel profile of the mouse: flat, adaptive, or linear");
+        b.method("SetAccelProfile", ("name", "profile",), (), |_, data, (name, profile,): (String, String,)| {
+            let profile = AccelProfile::from_name(&profile)
+                .ok_or_else(|| MethodErr::invalid_arg(&format!("Unknown accel profile {}, expected {}", profile, AccelProfile::NAMES)))?;
+            with_settings(data, &name, |settings| settings.accel_profile = profile)
+        }).annotate(DOC_STRING, "Sets the accel profile of the mouse by name: flat, adaptive, or linear");
+        b.method("SetRules", ("name", "rules",), (), |_, data, (name, rules,): (String, String,)| {
+            let rules: Vec<EventRule> = serde_json::from_str(&rules)
+                .map_err(|err| MethodErr::invalid_arg(&format!("Invalid rules: {}", err)))?;
+            with_settings(data, &name, |settings| settings.rules = rules)
+        }).annotate(DOC_STRING, "Replaces the event rules of the mouse with rules, a JSON list like [{\"rule\": \"swap_buttons\"}, {\"rule\": \"drop_axis\", \"axis\": \"hwheel\"}, {\"rule\": \"clamp_motion\", \"max\": 50}], applied in order");
+        b.method("SetDiscreteScroll", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.discrete_scroll = enabled)
+        }).annotate(DOC_STRING, "Sets whether the mouse scrolls at most one notch per frame on each axis, emitting the rest over the following frames");
+        // Hidden from GetCapabilities, it only exists in builds with the testing feature
+        #[cfg(feature = "testing")]
+        {
+            let (check_conn, allowed_uids) = (conn.clone(), config.allowed_uids.clone());
+            b.method_with_cr_async("InjectEvent", ("name", "event",), ("emitted",), move |mut ctx, cr, (name, event,): (String, String,)| {
+                let data = cr.data_mut::<Arc<Mutex<Communicator>>>(&"/".into()).unwrap().clone();
+                let (conn, allowed_uids) = (check_conn.clone(), allowed_uids.clone());
+                let sender = ctx.message().sender().map(|sender| sender.into_static());
+                async move {
+                    if let Err(err) = check_caller(conn, sender, &allowed_uids).await {
+                        return ctx.reply(Err(err));
+                    }
+                    let event: SyntheticEvent = match serde_json::from_str(&event) {
+                        Ok(event) => event,
+                        Err(err) => {return ctx.reply(Err(MethodErr::invalid_arg(&format!("Invalid event: {}", err))));}
+                    };
+                    let injector = match data.lock().unwrap().current_mice.get(&name) {
+                        Some(info) => info.injector.clone(),
+                        None => {return ctx.reply(Err(MethodErr::failed(&format!("No mouse named {}", name))));}
+                    };
+                    let (reply, emitted) = tokio::sync::oneshot::channel();
+                    if injector.send((event, reply)).is_err() {
+                        return ctx.reply(Err(MethodErr::failed(&format!("Mouse {} is no longer running", name))));
+                    }
+                    match emitted.await {
+                        Ok(emitted) => ctx.reply(Ok((emitted,))),
+                        Err(_) => ctx.reply(Err(MethodErr::failed(&format!("Mouse {} stopped before handling the event", name))))
+                    }
+                }
+            }).annotate(DOC_STRING, "Testing builds only. Pushes event, a JSON object like {\"event\": \"motion\", \"dx\": 5.0, \"dy\": 0.0} or {\"event\": \"button\", \"code\": 272, \"pressed\": true} or {\"event\": \"scroll\", \"vertical\": 1.0, \"horizontal\": 0.0}, into the mouse named name, bypassing libinput. Returns the emitted events as (type, code, value)");
+        }
+        b.method("SetScrollRateLimit", ("name", "milliseconds",), (), |_, data, (name, milliseconds,): (String, u64,)| {
+            with_settings(data, &name, |settings| settings.scroll_rate_limit_ms = milliseconds)
+        }).annotate(DOC_STRING, "Sets the shortest time in milliseconds between two frames that scroll, scroll in between is summed up and emitted once it passed. 0 doesn't limit it");
+        b.method("SetFlushMotionOnClick", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.flush_motion_on_click = enabled)
+        }).annotate(DOC_STRING, "Sets whether the sub pixel motion left over is rounded and emitted right before a button press, so the click lands where the finger was");
+        b.method("SetIgnoredButtons", ("name", "codes",), (), |_, data, (name, codes,): (String, Vec<u32>,)| {
+            with_settings(data, &name, |settings| settings.ignored_buttons = codes.into_iter().collect())
+        }).annotate(DOC_STRING, "Replaces the button codes whose presses the mouse drops, e.g. [272] for the physical click of a trackpad used with tap to click. An empty list drops none");
+        b.method("CloneSettings", ("from", "to",), (), |_, data, (from, to,): (String, String,)| {
+            data.lock().unwrap().clone_settings(&from, &to).map_err(|missing| MethodErr::failed(&format!("No mouse named {}", missing)))
+        }).annotate(DOC_STRING, "Copies all settings of the mouse named from onto the mouse named to, replacing its own");
+        b.method("SetScrollAxes", ("name", "vertical", "horizontal",), (), |_, data, (name, vertical, horizontal,): (String, bool, bool,)| {
+            with_settings(data, &name, |settings| {settings.vscroll_enabled = vertical; settings.hscroll_enabled = horizontal;})
+        }).annotate(DOC_STRING, "Sets which scroll axes the mouse scrolls on, a disabled axis emits nothing");
+        b.method("SetMaxSpeed", ("name", "pixels",), (), |_, data, (name, pixels,): (String, u32,)| {
+            with_settings(data, &name, |settings| settings.max_speed = pixels)
+        }).annotate(DOC_STRING, "Sets the most pixels the pointer moves per frame on each axis, the rest is carried into the following frames so no distance is lost. 0 doesn't cap it");
+        b.method("SetScrollAsMotion", ("name", "enabled",), (), |_, data, (name, enabled,): (String, bool,)| {
+            with_settings(data, &name, |settings| settings.scroll_as_motion = enabled)
+        }).annotate(DOC_STRING, "Sets whether two finger scrolling moves the pointer instead of scrolling, scaled by the sensitivity like one finger motion");
+        b.method("SetClickStabilization", ("name", "milliseconds",), (), |_, data, (name, milliseconds,): (String, u64,)| {
+            with_settings(data, &name, |settings| settings.click_stabilization_ms = milliseconds)
+        }).annotate(DOC_STRING, "Sets for how many milliseconds after a button press pointer motion is dropped, 0 to not drop any");
+        b.method("SetHiResQuantum", ("name", "quantum",), (), |_, data, (name, quantum,): (String, u32,)| {
+            with_settings(data, &name, |settings| settings.hi_res_quantum = quantum.max(1))
+        }).annotate(DOC_STRING, "Sets how many high resolution scroll units make one notch, 120 by default. 0 is taken as 1");
+        b.method("SetChannels", ("name", "motion", "buttons", "scroll",), (), |_, data, (name, motion, buttons, scroll,): (String, bool, bool, bool,)| {
+            with_settings(data, &name, |settings| {settings.motion_enabled = motion; settings.buttons_enabled = buttons; settings.scroll_enabled = scroll;})
+        }).annotate(DOC_STRING, "Sets whether the mouse moves the pointer, presses buttons, and scrolls, each independently. Button releases always go out, so nothing stays pressed");
+        b.method("SetPassthroughButton", ("name", "button",), (), |_, data, (name, button,): (String, u32,)| {
+            with_settings(data, &name, |settings| settings.passthrough_button = Some(button).filter(|button| *button != 0))
+        }).annotate(DOC_STRING, "Sets the button code that unlocks the trackpad while held instead of clicking, restoring the lock on release, 0 disables it. Like toggling the lock, unlocking signals MouseDeleted and locking again signals MouseCreated, so the physical trackpad is used while the button is held");
+        b.method("SetClampFirstMotion", ("name", "enabled", "idle-ms",), (), |_, data, (name, enabled, idle_ms,): (String, bool, u64,)| {
+            with_settings(data, &name, |settings| {settings.clamp_first_motion = enabled; settings.first_motion_idle_ms = idle_ms;})
+        }).annotate(DOC_STRING, "Sets whether the first motion after the trackpad was idle for idle-ms is clamped, so movement the device batched up while idle doesn't make the cursor jump");
     });
     cr.insert("/", &[process_interface], communicator.clone());
 
-    // Add Crossroads to connection
+    // Stop the mice tied to a client once its connection goes away, the bus then signals its unique name losing its owner
+    let (tied_conn, tied_data) = (conn.clone(), communicator.clone());
+    let tied_match = conn.add_match(MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")).await?.cb(move |_, (name, _, new_owner): (String, String, String)| {
+        if name.starts_with(':') && new_owner.is_empty() {
+            for input_id in tied_data.lock().unwrap().stop_tied_mice(&name) {
+                let signal = Message::signal(&"/".into(), &"com.cowsociety.virtual_mouse".into(), &"MouseDeleted".into())
+                    .append1(input_id);
+                let _ = tied_conn.send(signal);
+            }
+        }
+        true
+    });
+
+    // Add Crossroads to connection, logging every call before it is handled
+    let audit_conn = conn.clone();
     conn.start_receive(MatchRule::new_method_call(), Box::new(move |msg, conn| {
-        cr.handle_message(msg, conn).unwrap();
+        log_method_call(audit_conn.clone(), &msg);
+        // Crossroads only fails to handle a message it can't reply to, which shouldn't stop the server from handling the next one
+        let description = describe_message(&msg);
+        if cr.handle_message(msg, conn).is_err() {
+            println!("Failed to handle {}", description);
+        }
         true
     }));
 
     //update mice endlessly
-    let local = task::LocalSet::new();
-    local.run_until(async move {
-        manager.update_loop().await;
-    }).await;
+    manager.update_loop().await;
 
     // Disconnect DBus
+    let _ = conn.remove_match(tied_match.token()).await;
+    trackpad_handle.abort();
     dbus_handle.abort();
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The message as if the bus had delivered it from sender. Only the bus sets senders, so the field is appended to the marshalled
+    /// header, which works for messages without a body
+    fn from_sender(mut msg: Message, sender: &str) -> Message {
+        msg.set_serial(1);
+        let mut wire = vec![];
+        msg.marshal(|bytes| {wire.extend_from_slice(bytes); Ok::<(), ()>(())}).unwrap();
+        let align = |len: usize| len.div_ceil(8) * 8;
+        // The header field array's length is at byte 12, and the array starts at 16
+        let fields_end = 16 + u32::from_le_bytes(wire[12..16].try_into().unwrap()) as usize;
+        wire.truncate(fields_end);
+        wire.resize(align(fields_end), 0);
+        // Field code 7 is the sender, a string
+        wire.extend_from_slice(&[7, 1, b's', 0]);
+        wire.extend_from_slice(&(sender.len() as u32).to_le_bytes());
+        wire.extend_from_slice(sender.as_bytes());
+        wire.push(0);
+        let fields_len = (wire.len() - 16) as u32;
+        wire[12..16].copy_from_slice(&fields_len.to_le_bytes());
+        wire.resize(align(wire.len()), 0);
+        Message::demarshal(&wire).unwrap()
+    }
+
+    #[test]
+    fn describes_method_calls() {
+        let msg = Message::new_method_call("com.cowsociety.virtual_mouse", "/", "com.cowsociety.virtual_mouse", "StopMouse").unwrap();
+        assert_eq!(describe_message(&msg), "com.cowsociety.virtual_mouse.StopMouse from unknown");
+        assert_eq!(describe_message(&from_sender(msg, ":1.42")), "com.cowsociety.virtual_mouse.StopMouse from :1.42");
+    }
+
+    #[test]
+    fn describes_messages_without_interface_or_member() {
+        let mut call = Message::new_method_call("com.cowsociety.virtual_mouse", "/", "com.cowsociety.virtual_mouse", "StopMouse").unwrap();
+        call.set_serial(1);
+        let msg = call.method_return();
+        assert_eq!(describe_message(&msg), "unknown.unknown from unknown");
+    }
+}
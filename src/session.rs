@@ -2,11 +2,15 @@
     Should be run automatically as a user systemd service.
     Listens for mouse created and deleted broadcasts from the system server
     Runs xinput to turn on and off the mice when they are deleted/created
+    On startup it also disables the trackpads of the mice that already exist, so a restart doesn't leave them enabled
+    Without X (a TTY only boot, or started with --no-x) it skips xinput and only logs the mice, leaving their trackpads as they are
+    Started with --instance it follows that server instance instead of the default server
 */
 
-use std::{error::Error, fmt::Display};
-use dbus::message::MatchRule;
+use std::{error::Error, fmt::Display, sync::Arc};
+use dbus::{message::MatchRule, nonblock::{Proxy, SyncConnection}};
 use dbus_tokio::connection;
+use crate::config;
 
 /// Error representing ways the server can fail
 #[derive(Debug)]
@@ -28,8 +32,19 @@ impl Display for SessionServerError{
 }
 impl Error for SessionServerError{}
 
-/// Server code
-pub async fn session_server() -> Result<(), Box<dyn Error>> {
+/// Whether there is an X display for xinput to talk to
+fn x_available() -> bool {
+    std::env::var_os("DISPLAY").is_some_and(|display| !display.is_empty())
+}
+
+/// Server code. With no_x, or when no X display is found, xinput is never called.
+/// Follows the named server instance if one is given, and the default server otherwise
+pub async fn session_server(no_x: bool, instance: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let bus_name = config::bus_name(instance);
+    let use_x = !no_x && x_available();
+    if !no_x && !use_x {
+        println!("Warning: $DISPLAY is not set, running without X. Trackpads won't be disabled, pass --no-x to silence this");
+    }
     // Setup DBus connection
     // We use the system bus because that is where the broadcasts are. since we are only listening, we should be fine
     let (resource, conn) = connection::new_system_sync()
@@ -37,24 +52,45 @@ pub async fn session_server() -> Result<(), Box<dyn Error>> {
     let dbus_handle = tokio::spawn(async {
         resource.await
     });
-    // Setup callbacks to handle mouse creation and deletion events
-    let sig1 = conn.add_match(MatchRule::new_signal("com.cowsociety.virtual_mouse", "MouseCreated")).await?.cb(|_, (id,): (u32,)| {
-        if let Err(err) = toggle_mouse(id, false) {
+    // Setup callbacks to handle mouse creation and deletion events, only of the followed server so other instances' mice are left alone
+    let sig1 = conn.add_match(MatchRule::new_signal("com.cowsociety.virtual_mouse", "MouseCreated").with_sender(bus_name.clone())).await?.cb(move |_, (id,): (u32,)| {
+        if let Err(err) = toggle_mouse_if(use_x, id, false) {
             println!("Error: {:?}", err);
         }
         true
     });
-    let sig2 = conn.add_match(MatchRule::new_signal("com.cowsociety.virtual_mouse", "MouseDeleted")).await?.cb(|_, (id,): (u32,)| {
-        if let Err(err) = toggle_mouse(id, true) {
+    let sig2 = conn.add_match(MatchRule::new_signal("com.cowsociety.virtual_mouse", "MouseDeleted").with_sender(bus_name.clone())).await?.cb(move |_, (id,): (u32,)| {
+        if let Err(err) = toggle_mouse_if(use_x, id, true) {
             println!("Error: {:?}", err);
         }
         true
     });
+    // Mice created before the session server started never got their signal to it. The matches are added first, so none are missed in between
+    if let Err(err) = disable_existing_mice(conn.clone(), bus_name, use_x).await {
+        println!("Could not list the existing mice, the system server is probably not running. DBus error: {}", err);
+    }
     // Run forever
     dbus_handle.await?;
     conn.remove_match(sig1.token()).await?; conn.remove_match(sig2.token()).await?;
     Ok(())
 }
+/// Disables the input devices of the mice the system server owning bus_name already has
+async fn disable_existing_mice(conn: Arc<SyncConnection>, bus_name: String, use_x: bool) -> Result<(), dbus::Error> {
+    let proxy = Proxy::new(bus_name, "/", std::time::Duration::from_secs(2), conn);
+    let (mice,): (Vec<(String, u32, u32, u64)>,) = proxy.method_call("com.cowsociety.virtual_mouse", "ListMice", ()).await?;
+    for (_, input_id, _, _) in mice {
+        if let Err(err) = toggle_mouse_if(use_x, input_id, false) {
+            println!("Error: {:?}", err);
+        }
+    }
+    Ok(())
+}
+/// Toggles the mouse with xinput when use_x is set, and only logs what would have been done otherwise
+fn toggle_mouse_if(use_x: bool, input_id: u32, enable: bool) -> Result<(), SessionServerError> {
+    if use_x {return toggle_mouse(input_id, enable);}
+    println!("Without X, leaving the trackpad of input event {} {}", input_id, if enable {"as it is instead of enabling it"} else {"enabled"});
+    Ok(())
+}
 // Helper function to take an input id and use xinput to disable/enable the corresponding mouse
 pub fn toggle_mouse(input_id: u32, enable: bool) -> Result<(), SessionServerError> {
     let event_string = "event".to_owned() + &input_id.to_string();
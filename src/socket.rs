@@ -0,0 +1,318 @@
+/* Unix Socket Control
+    Alternative to DBus for minimal environments that don't run a DBus daemon.
+    Clients send one command per line, as a JSON array of the same arguments the command line client takes (e.g. ["--stop","name"]).
+    The server answers each command with one line of JSON, either {"result": ...} or {"error": "..."}
+*/
+
+use std::{error::Error, fmt::Display, path::Path, process, sync::{Arc, Mutex}, time::Duration};
+use serde_json::{json, Value};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{UnixListener, UnixStream}, task::JoinHandle};
+use crate::{client::ClientCommand, communicator::{CancelOutcome, Communicator, CommunicatorPresenceFuture, CommunicatorResultFuture, rebind_mouse}, mouse::{AccelProfile, DeviceInfo, EventRule, SystemInfo, InputCounts, MouseInfo, MouseOwner, MouseSettings, DEVICE_NAME_PREFIX}, config::{self, ServerConfig}};
+
+/// Error representing ways the socket server and client can fail
+#[derive(Debug)]
+pub enum SocketError{
+    BindFailed(std::io::Error),
+    ConnectFailed(std::io::Error),
+    IOError(std::io::Error),
+    NoResponse,
+    CommandFailed(String)
+}
+impl Display for SocketError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let string = match self {
+            SocketError::BindFailed(err) => format!("Could not listen on the socket. IO Error: {}", err),
+            SocketError::ConnectFailed(err) => format!("Could not connect to the socket, the server may not be running. IO Error: {}", err),
+            SocketError::IOError(err) => format!("Failed to talk over the socket. IO Error: {}", err),
+            SocketError::NoResponse => "The server closed the socket without responding".to_string(),
+            SocketError::CommandFailed(err) => format!("The command failed: {}", err)
+        };
+        f.write_str(string.as_str())?;
+        Ok(())
+    }
+}
+impl Error for SocketError{}
+
+/// Starts listening for commands on a unix socket, handling each connection on its own task. Returns the handle of the listening task
+pub fn serve(path: &Path, com: Arc<Mutex<Communicator>>, config: ServerConfig) -> Result<JoinHandle<()>, SocketError> {
+    // A socket file left behind by a previous server would make binding fail
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path).map_err(SocketError::BindFailed)?;
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {tokio::spawn(handle_connection(stream, com.clone(), config.clone()));}
+                Err(err) => {println!("Failed to accept socket connection: {}", err);}
+            }
+        }
+    }))
+}
+
+/// Answers commands from one connection until it closes
+async fn handle_connection(stream: UnixStream, com: Arc<Mutex<Communicator>>, config: ServerConfig) {
+    // The peer's credentials take the place of the DBus sender for the allowlist
+    let uid = stream.peer_cred().ok().map(|cred| cred.uid());
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let command = serde_json::from_str::<Vec<String>>(&line).ok().and_then(|arguments| crate::parse_client_command(&arguments));
+        let response = match command {
+            Some(command) => match execute(command, &com, uid, &config).await {
+                Ok(result) => json!({"result": result}),
+                Err(err) => json!({"error": err})
+            },
+            None => json!({"error": "Command was Malformed"})
+        };
+        if write.write_all((response.to_string() + "\n").as_bytes()).await.is_err() {break;}
+    }
+}
+
+/// Runs a command against the communicator, the same way the DBus methods do
+async fn execute(command: ClientCommand, com: &Arc<Mutex<Communicator>>, uid: Option<u32>, config: &ServerConfig) -> Result<Value, String> {
+    let restricted = matches!(command, ClientCommand::New(..) | ClientCommand::NewAll(..) | ClientCommand::Stop(..) | ClientCommand::StopById(..) | ClientCommand::CancelCreate(..) | ClientCommand::DeviceInfo(..) | ClientCommand::ProbeInput(..) | ClientCommand::Nudge(..) | ClientCommand::Rebind(..) | ClientCommand::SaveProfile(..) | ClientCommand::DeleteProfile(..) | ClientCommand::Shutdown | ClientCommand::Reset | ClientCommand::PauseAll | ClientCommand::ResumeAll);
+    if restricted && !uid.is_some_and(|uid| config.allowed_uids.contains(&uid)) {
+        return Err("Access denied, the user is not allowed to do this".to_string());
+    }
+    match command {
+        ClientCommand::New(name, path, options) => {
+            let future = CommunicatorResultFuture{name: name.clone(), handle: com.clone()};
+            com.lock().unwrap().queue_mouse(name, path, options);
+            let info = future.await.map_err(|err| err.to_string())?;
+            let info = set_socket_owner(com, info, uid);
+            Ok(mouse_json(&info))
+        }
+        ClientCommand::NewAll(prefix, pattern, options) => {
+            let mut created = vec![];
+            for (index, path) in crate::client::expand_device_glob(&pattern).map_err(|err| err.to_string())?.into_iter().enumerate() {
+                let name = format!("{}-{}", prefix, index);
+                let future = CommunicatorResultFuture{name: name.clone(), handle: com.clone()};
+                com.lock().unwrap().queue_mouse(name, path, options.clone());
+                let info = future.await.map_err(|err| err.to_string())?;
+                let info = set_socket_owner(com, info, uid);
+                created.push(mouse_json(&info));
+            }
+            Ok(Value::Array(created))
+        }
+        ClientCommand::List => {
+            let mice = com.lock().unwrap().list_mice();
+            Ok(mice.into_iter().map(|(name, input_id, output_id, created_at)| json!({"name": name, "input_id": input_id, "output_id": output_id, "created_at": created_at})).collect())
+        }
+        ClientCommand::ListWithErrors => {
+            let com = com.lock().unwrap();
+            let mice: Value = com.list_mice().into_iter().map(|(name, input_id, output_id, created_at)| json!({"name": name, "input_id": input_id, "output_id": output_id, "created_at": created_at})).collect();
+            let failures: Value = com.list_failures().into_iter().map(|(name, error, failed_at)| json!({"name": name, "error": error, "failed_at": failed_at})).collect();
+            Ok(json!({"mice": mice, "failures": failures}))
+        }
+        ClientCommand::ListOwners => {
+            let owners = com.lock().unwrap().list_owners();
+            Ok(owners.into_iter().map(|(name, client, uid)| json!({"name": name, "client": client, "uid": uid})).collect())
+        }
+        // The socket client prints the config, it isn't written to a file
+        ClientCommand::ExportConfig(toml, _) => {
+            let mice = com.lock().unwrap().mouse_configs();
+            Ok(json!(config::export_mice(mice, toml).map_err(|err| err.to_string())?))
+        }
+        ClientCommand::Aborted => {
+            let aborted = com.lock().unwrap().list_aborted();
+            Ok(aborted.into_iter().map(|(name, error, aborted_at)| json!({"name": name, "error": error, "aborted_at": aborted_at})).collect())
+        }
+        ClientCommand::Get(name) => {
+            let com = com.lock().unwrap();
+            let info = com.current_mice.get(&name).ok_or_else(|| format!("No mouse named {}", name))?;
+            Ok(mouse_json(info))
+        }
+        ClientCommand::Resolve(input_id) => {
+            let (name, output_id) = com.lock().unwrap().resolve_mapping(input_id).ok_or_else(|| format!("No mouse reads input event {}", input_id))?;
+            Ok(json!({"name": name, "output_id": output_id}))
+        }
+        ClientCommand::GetMirrors(name) => {
+            let com = com.lock().unwrap();
+            let info = com.current_mice.get(&name).ok_or_else(|| format!("No mouse named {}", name))?;
+            Ok(json!(info.mirror_output_ids))
+        }
+        ClientCommand::GetThroughput(name) => {
+            let com = com.lock().unwrap();
+            let info = com.current_mice.get(&name).ok_or_else(|| format!("No mouse named {}", name))?;
+            let (emits, events) = info.throughput.lock().unwrap().rates();
+            Ok(json!({"emits-per-sec": emits, "events-per-sec": events}))
+        }
+        ClientCommand::Stop(name) => {
+            com.lock().unwrap().stop_mouse(name);
+            Ok(Value::Null)
+        }
+        ClientCommand::StopById(kind, id) => {
+            let mut com = com.lock().unwrap();
+            let name = com.name_by_id(kind, id).ok_or_else(|| format!("NotFound: no mouse with {} event id {}", kind.as_str(), id))?;
+            com.stop_mouse(name.clone());
+            Ok(json!(name))
+        }
+        ClientCommand::CancelCreate(name) => {
+            let outcome = com.lock().unwrap().cancel_create(name);
+            Ok(json!(!matches!(outcome, CancelOutcome::NotFound)))
+        }
+        ClientCommand::Shutdown => {
+            com.lock().unwrap().request_shutdown();
+            Ok(Value::Null)
+        }
+        ClientCommand::Reset => {
+            com.lock().unwrap().stop_all_mice();
+            Ok(Value::Null)
+        }
+        ClientCommand::SaveProfile(name, settings) => {
+            if name.is_empty() {return Err("Profile names can't be empty".to_string());}
+            let settings = crate::server::parse_profile(&name, &settings)?;
+            com.lock().unwrap().save_profile(name, settings)?;
+            Ok(Value::Null)
+        }
+        ClientCommand::DeleteProfile(name) => Ok(json!(com.lock().unwrap().delete_profile(&name)?)),
+        ClientCommand::ListProfiles => Ok(json!(com.lock().unwrap().list_profiles())),
+        ClientCommand::PauseAll => {
+            com.lock().unwrap().set_paused(true);
+            Ok(Value::Null)
+        }
+        ClientCommand::ResumeAll => {
+            com.lock().unwrap().set_paused(false);
+            Ok(Value::Null)
+        }
+        ClientCommand::PID => Ok(json!(process::id())),
+        ClientCommand::ServerInfo => {
+            let com = com.lock().unwrap();
+            Ok(json!({"pid": process::id(), "systemd": crate::server::under_systemd(), "started_at": com.started_at_unix(), "mice": com.current_mice.len()}))
+        }
+        ClientCommand::ServerConfig => Ok(json!({
+            "device-prefix": DEVICE_NAME_PREFIX,
+            "allowed-uids": config.allowed_uids,
+            "acquire-retries": config.acquire_retries,
+            "idle-timeout-secs": config.idle_timeout_secs,
+            "instance": config.instance,
+            "socket": config.socket
+        })),
+        ClientCommand::SystemInfo => {
+            let info = SystemInfo::query();
+            Ok(json!({
+                "kernel-release": info.kernel_release,
+                "uinput-present": info.uinput_present,
+                "uinput-version": info.uinput_version,
+                "evdev-version": info.evdev_version
+            }))
+        }
+        ClientCommand::DeviceInfo(path) => {
+            let info = DeviceInfo::query(&path).map_err(|err| err.to_string())?;
+            Ok(json!({
+                "libinput-name": info.libinput_name,
+                "sysname": info.sysname,
+                "vendor-id": info.vendor_id,
+                "product-id": info.product_id,
+                "capabilities": info.capabilities,
+                "evdev-name": info.evdev_name
+            }))
+        }
+        ClientCommand::ProbeInput(path, duration) => {
+            let counts = InputCounts::probe(&path, Duration::from_secs(duration as u64)).await.map_err(|err| err.to_string())?;
+            Ok(json!({"frames": counts.frames, "key": counts.key, "relative": counts.relative, "absolute": counts.absolute, "other": counts.other}))
+        }
+        ClientCommand::Nudge(name, dx, dy) => {
+            let nudger = com.lock().unwrap().current_mice.get(&name).ok_or_else(|| format!("No mouse named {}", name))?.nudger.clone();
+            nudger.send((dx, dy)).map_err(|_| format!("Mouse {} is no longer running", name))?;
+            Ok(Value::Null)
+        }
+        ClientCommand::Rebind(name, path) => {
+            let (_, info) = rebind_mouse(com, &name, path).await?;
+            Ok(mouse_json(&info))
+        }
+        ClientCommand::WaitFor(name, timeout) => {
+            let future = CommunicatorPresenceFuture{name: name.clone(), com: com.clone()};
+            let info = tokio::time::timeout(Duration::from_secs(timeout as u64), future).await
+                .map_err(|_| format!("Timed out waiting for mouse {}", name))?;
+            Ok(mouse_json(&info))
+        }
+        ClientCommand::SetShiftToHScroll(name, enabled) => set(com, &name, |settings| settings.shift_to_hscroll = enabled),
+        ClientCommand::SetScrollModifier(name, held) => set(com, &name, |settings| settings.modifier_held = held),
+        ClientCommand::SetScrollAccel(name, enabled) => set(com, &name, |settings| settings.scroll_accel = enabled),
+        ClientCommand::SetIdleReset(name, ms) => set(com, &name, |settings| settings.idle_reset_ms = ms),
+        ClientCommand::SetFixedPoint(name, enabled) => set(com, &name, |settings| settings.fixed_point = enabled),
+        ClientCommand::SetThreeFingerMiddleClick(name, enabled) => set(com, &name, |settings| settings.three_finger_middle_click = enabled),
+        ClientCommand::SetHiResScroll(name, enabled) => {
+            com.lock().unwrap().set_hi_res_scroll(&name, enabled).ok_or_else(|| format!("No mouse named {}", name))?.map(|_| Value::Null)
+        }
+        ClientCommand::SetAxisScale(name, x, y) => set_checked(com, &name, |settings| {settings.x_scale = x; settings.y_scale = y;}),
+        ClientCommand::SetLockToggleButton(name, button) => set(com, &name, |settings| settings.lock_toggle_button = Some(button).filter(|button| *button != 0)),
+        ClientCommand::SetSyncTimeout(name, ms) => set(com, &name, |settings| settings.sync_timeout_ms = ms),
+        ClientCommand::SetScrollDeadzone(name, deadzone) => set_checked(com, &name, |settings| settings.scroll_deadzone = deadzone),
+        ClientCommand::SetThreeFingerDrag(name, enabled) => set(com, &name, |settings| settings.three_finger_drag = enabled),
+        ClientCommand::SetDoubleTap(name, enabled, interval_ms) => set(com, &name, |settings| {settings.double_tap = enabled; settings.double_tap_interval_ms = interval_ms;}),
+        ClientCommand::SetUnknownButtonLogging(name, enabled) => set(com, &name, |settings| settings.unknown_button_logging = enabled),
+        ClientCommand::SetMaxScrollPerFrame(name, notches) => set(com, &name, |settings| settings.max_scroll_per_frame = notches),
+        ClientCommand::SetTurboButton(name, button, factor) => set(com, &name, |settings| {settings.turbo_button = Some(button).filter(|button| *button != 0); settings.turbo_factor = factor;}),
+        ClientCommand::SetNormalizeByResolution(name, enabled) => set(com, &name, |settings| settings.normalize_by_resolution = enabled),
+        ClientCommand::GetAccelProfile(name) => {
+            com.lock().unwrap().with_settings(&name, |settings| json!(settings.accel_profile.as_str())).ok_or_else(|| format!("No mouse named {}", name))
+        }
+        ClientCommand::SetAccelProfile(name, profile) => {
+            let profile = AccelProfile::from_name(&profile).ok_or_else(|| format!("Unknown accel profile {}, expected {}", profile, AccelProfile::NAMES))?;
+            set(com, &name, |settings| settings.accel_profile = profile)
+        }
+        ClientCommand::SetRules(name, rules) => {
+            let rules: Vec<EventRule> = serde_json::from_str(&rules).map_err(|err| format!("Invalid rules: {}", err))?;
+            set(com, &name, |settings| settings.rules = rules)
+        },
+        ClientCommand::SetDiscreteScroll(name, enabled) => set(com, &name, |settings| settings.discrete_scroll = enabled),
+        ClientCommand::SetScrollRateLimit(name, milliseconds) => set(com, &name, |settings| settings.scroll_rate_limit_ms = milliseconds),
+        ClientCommand::SetFlushMotionOnClick(name, enabled) => set(com, &name, |settings| settings.flush_motion_on_click = enabled),
+        ClientCommand::SetIgnoredButtons(name, codes) => set(com, &name, |settings| settings.ignored_buttons = codes.into_iter().collect()),
+        ClientCommand::CloneSettings(from, to) => {
+            com.lock().unwrap().clone_settings(&from, &to).map(|_| Value::Null).map_err(|missing| format!("No mouse named {}", missing))
+        },
+        ClientCommand::SetScrollAxes(name, vertical, horizontal) => set(com, &name, |settings| {settings.vscroll_enabled = vertical; settings.hscroll_enabled = horizontal;}),
+        ClientCommand::SetMaxSpeed(name, pixels) => set(com, &name, |settings| settings.max_speed = pixels),
+        ClientCommand::SetScrollAsMotion(name, enabled) => set(com, &name, |settings| settings.scroll_as_motion = enabled),
+        ClientCommand::SetClickStabilization(name, milliseconds) => set(com, &name, |settings| settings.click_stabilization_ms = milliseconds),
+        ClientCommand::SetHiResQuantum(name, quantum) => set(com, &name, |settings| settings.hi_res_quantum = quantum.max(1)),
+        ClientCommand::SetChannels(name, motion, buttons, scroll) => set(com, &name, |settings| {settings.motion_enabled = motion; settings.buttons_enabled = buttons; settings.scroll_enabled = scroll;}),
+        ClientCommand::SetPassthroughButton(name, button) => set(com, &name, |settings| settings.passthrough_button = Some(button).filter(|button| *button != 0)),
+        ClientCommand::SetClampFirstMotion(name, enabled, idle_ms) => set(com, &name, |settings| {settings.clamp_first_motion = enabled; settings.first_motion_idle_ms = idle_ms;})
+    }
+}
+
+/// Changes the settings of the named mouse
+fn set(com: &Arc<Mutex<Communicator>>, name: &str, f: impl FnOnce(&mut MouseSettings)) -> Result<Value, String> {
+    com.lock().unwrap().with_settings(name, f).map(|_| Value::Null).ok_or_else(|| format!("No mouse named {}", name))
+}
+
+/// Changes the settings of the named mouse, failing if the changed settings are invalid
+fn set_checked(com: &Arc<Mutex<Communicator>>, name: &str, f: impl FnOnce(&mut MouseSettings)) -> Result<Value, String> {
+    com.lock().unwrap().with_checked_settings(name, f).ok_or_else(|| format!("No mouse named {}", name))?.map(|_| Value::Null)
+}
+
+/// Records a socket client as the owner of a mouse it created. Creating mice is restricted, so the client's uid is always known
+fn set_socket_owner(com: &Arc<Mutex<Communicator>>, info: MouseInfo, uid: Option<u32>) -> MouseInfo {
+    match uid {
+        Some(uid) => com.lock().unwrap().set_owner(info, MouseOwner{client: "socket".to_string(), uid}),
+        None => info
+    }
+}
+
+/// JSON representation of a mouse
+fn mouse_json(info: &MouseInfo) -> Value {
+    json!({"name": info.name, "input_id": info.input_id, "output_id": info.output_id, "mirror_output_ids": info.mirror_output_ids, "resolution": info.resolution, "created_at": info.created_at_unix()})
+}
+
+/// Sends a command to a server listening on a unix socket, and prints the result
+pub async fn socket_client(path: &str, arguments: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let stream = UnixStream::connect(path).await.map_err(SocketError::ConnectFailed)?;
+    let (read, mut write) = stream.into_split();
+    write.write_all((serde_json::to_string(arguments)? + "\n").as_bytes()).await.map_err(SocketError::IOError)?;
+    let response = BufReader::new(read).lines().next_line().await
+        .map_err(SocketError::IOError)?
+        .ok_or(SocketError::NoResponse)?;
+    let response: Value = serde_json::from_str(&response)?;
+    if let Some(err) = response.get("error") {
+        return Err(SocketError::CommandFailed(err.as_str().unwrap_or_default().to_string()).into());
+    }
+    // Strings (like exported configs) are printed as they are, not as JSON strings
+    match &response["result"] {
+        Value::String(result) => println!("{}", result),
+        result => println!("{}", result)
+    }
+    Ok(())
+}